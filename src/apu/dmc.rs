@@ -0,0 +1,233 @@
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// $4010-$4013. Plays 1 bit delta encoded samples fetched straight from CPU
+// memory, or holds whatever 7 bit level $4011 last wrote.
+pub struct Dmc{
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    rate: u16, // in CPU cycles
+    timer: u16,
+
+    // Memory reader
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+
+    // Output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    level: u8
+}
+
+impl Dmc{
+    pub fn new() -> Self{
+        let rate = Region::Ntsc.dmc_rates()[0];
+        return Dmc{
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            rate,
+            timer: rate - 1,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            level: 0
+        };
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter){
+        w.bool(self.irq_enabled);
+        w.bool(self.irq);
+        w.bool(self.looping);
+        w.u16(self.rate);
+        w.u16(self.timer);
+        w.u16(self.sample_address);
+        w.u16(self.sample_length);
+        w.u16(self.current_address);
+        w.u16(self.bytes_remaining);
+        w.option_u8(self.buffer);
+        w.u8(self.shift);
+        w.u8(self.bits_remaining);
+        w.bool(self.silence);
+        w.u8(self.level);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.irq_enabled = r.bool()?;
+        self.irq = r.bool()?;
+        self.looping = r.bool()?;
+        self.rate = r.u16()?;
+        self.timer = r.u16()?;
+        self.sample_address = r.u16()?;
+        self.sample_length = r.u16()?;
+        self.current_address = r.u16()?;
+        self.bytes_remaining = r.u16()?;
+        self.buffer = r.option_u8()?;
+        self.shift = r.u8()?;
+        self.bits_remaining = r.u8()?;
+        self.silence = r.bool()?;
+        self.level = r.u8()? & 0x7F;
+        return Ok(());
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8, region: Region){
+        match addr & 0x0003{
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled{
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.rate = region.dmc_rates()[(data & 0x0F) as usize];
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) | 0x0001
+        }
+    }
+
+    fn restart(&mut self){
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // $4015 bit 4. Also acknowledges the DMC IRQ.
+    pub fn set_enabled(&mut self, enabled: bool){
+        self.irq = false;
+        if !enabled{
+            self.bytes_remaining = 0;
+        }else if self.bytes_remaining == 0{
+            self.restart();
+        }
+    }
+
+    pub fn active(&self) -> bool{
+        return self.bytes_remaining > 0;
+    }
+
+    pub fn irq(&self) -> bool{
+        return self.irq;
+    }
+
+    pub fn request(&self) -> Option<u16>{
+        if self.buffer.is_none() && self.bytes_remaining > 0{
+            return Some(self.current_address);
+        }
+        return None;
+    }
+
+    pub fn fill(&mut self, data: u8){
+        self.buffer = Some(data);
+        // The address wraps round to $8000, not $0000
+        self.current_address = if self.current_address == 0xFFFF{
+            0x8000
+        }else{
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0{
+            if self.looping{
+                self.restart();
+            }else if self.irq_enabled{
+                self.irq = true;
+            }
+        }
+    }
+
+    // One CPU cycle
+    pub fn clock(&mut self){
+        if self.timer > 0{
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence{
+            if self.shift & 0x01 != 0{
+                if self.level <= 125{
+                    self.level += 2;
+                }
+            }else if self.level >= 2{
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0{
+            self.bits_remaining = 8;
+            match self.buffer.take(){
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                }
+                None => self.silence = true
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8{
+        return self.level;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn reader_fetches_sample_and_raises_irq(){
+        let mut dmc = Dmc::new();
+        dmc.write(0x4010, 0x80, Region::Ntsc);
+        dmc.write(0x4012, 0x01, Region::Ntsc);
+        dmc.write(0x4013, 0x00, Region::Ntsc);
+        assert_eq!(dmc.request(), None);
+
+        dmc.set_enabled(true);
+        assert_eq!(dmc.request(), Some(0xC040));
+        dmc.fill(0xFF);
+        assert_eq!(dmc.request(), None);
+        assert!(!dmc.active());
+        assert!(dmc.irq());
+    }
+
+    #[test]
+    fn output_follows_sample_bits(){
+        let mut dmc = Dmc::new();
+        dmc.write(0x4010, 0x0F, Region::Ntsc);
+        dmc.write(0x4011, 0x40, Region::Ntsc);
+        dmc.write(0x4013, 0x00, Region::Ntsc);
+        dmc.set_enabled(true);
+        dmc.fill(0xFF);
+
+        // Drain the empty shift register it started with, play the byte,
+        // then hold the level once the buffer runs dry
+        for _ in 0..2000{
+            dmc.clock();
+        }
+        assert_eq!(dmc.output(), 0x40 + 8 * 2);
+    }
+
+    #[test]
+    fn address_wraps_to_8000(){
+        let mut dmc = Dmc::new();
+        dmc.write(0x4012, 0xFF, Region::Ntsc);
+        dmc.write(0x4013, 0x04, Region::Ntsc);
+        dmc.set_enabled(true);
+        for _ in 0..0x40{
+            dmc.fill(0x00);
+            dmc.buffer = None;
+        }
+        assert_eq!(dmc.request(), Some(0x8000));
+    }
+}
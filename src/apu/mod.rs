@@ -0,0 +1,473 @@
+#![allow(dead_code)]
+
+use crate::region::Region;
+use crate::savestate::{StateError, StateFile, StateReader, StateWriter};
+
+pub mod pulse;
+pub mod triangle;
+pub mod noise;
+pub mod dmc;
+
+use pulse::{Pulse, SweepNegate};
+use triangle::Triangle;
+use noise::Noise;
+use dmc::Dmc;
+
+// Indexed by the top five bits of the length register writes
+static LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30
+];
+
+/******
+ * Units shared between channels
+ ******/
+
+// Volume envelope of the pulse and noise channels. Either a constant volume
+// or a sawtooth decaying from 15, clocked by the frame counter.
+#[derive(Default)]
+pub struct Envelope{
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8, // constant volume, or the divider period
+    divider: u8,
+    decay: u8
+}
+
+impl Envelope{
+    pub fn save_state(&self, w: &mut StateWriter){
+        w.bool(self.start);
+        w.bool(self.looping);
+        w.bool(self.constant);
+        w.u8(self.volume);
+        w.u8(self.divider);
+        w.u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.start = r.bool()?;
+        self.looping = r.bool()?;
+        self.constant = r.bool()?;
+        self.volume = r.u8()? & 0x0F;
+        self.divider = r.u8()?;
+        self.decay = r.u8()? & 0x0F;
+        return Ok(());
+    }
+
+    // Low six bits of $4000/$4004/$400C
+    pub fn write(&mut self, data: u8){
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    pub fn restart(&mut self){
+        self.start = true;
+    }
+
+    pub fn quarter_frame(&mut self){
+        if self.start{
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        }else if self.divider == 0{
+            self.divider = self.volume;
+            if self.decay > 0{
+                self.decay -= 1;
+            }else if self.looping{
+                self.decay = 15;
+            }
+        }else{
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8{
+        if self.constant{
+            return self.volume;
+        }
+        return self.decay;
+    }
+}
+
+// Silences a channel after a programmed number of half frames
+#[derive(Default)]
+pub struct LengthCounter{
+    enabled: bool,
+    halt: bool,
+    counter: u8
+}
+
+impl LengthCounter{
+    pub fn save_state(&self, w: &mut StateWriter){
+        w.bool(self.enabled);
+        w.bool(self.halt);
+        w.u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.enabled = r.bool()?;
+        self.halt = r.bool()?;
+        self.counter = r.u8()?;
+        return Ok(());
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool){
+        self.enabled = enabled;
+        if !enabled{
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool){
+        self.halt = halt;
+    }
+
+    // Top five bits of the channel's last register
+    pub fn load(&mut self, data: u8){
+        if self.enabled{
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    pub fn half_frame(&mut self){
+        if !self.halt && self.counter > 0{
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool{
+        return self.counter > 0;
+    }
+}
+
+/******
+ * The APU
+ ******/
+
+pub struct Apu{
+    region: Region,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    // Frame counter, counted in CPU cycles since it was last reset
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    // A $4017 write takes effect 3 or 4 CPU cycles later
+    frame_write: Option<(u8, u8)>, // (value, cycles to go)
+
+    // Pulse and noise run off the APU clock, half the CPU clock
+    odd_cycle: bool
+}
+
+impl Apu{
+    pub fn new() -> Self{
+        return Apu{
+            region: Region::Ntsc,
+            pulse1: Pulse::new(SweepNegate::OnesComplement),
+            pulse2: Pulse::new(SweepNegate::TwosComplement),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_write: None,
+            odd_cycle: false
+        };
+    }
+
+    pub fn set_region(&mut self, region: Region){
+        self.region = region;
+    }
+
+    // The reset button silences everything but leaves the frame counter mode
+    pub fn reset(&mut self){
+        self.cpu_write(0x4015, 0x00);
+        self.frame_irq = false;
+        self.frame_cycle = 0;
+        self.frame_write = None;
+    }
+
+    // Channels and frame counter. The region belongs to the console.
+    pub fn save_state(&self, state: &mut StateWriter){
+        state.section(*b"APU ", 1, |w| {
+            self.pulse1.save_state(w);
+            self.pulse2.save_state(w);
+            self.triangle.save_state(w);
+            self.noise.save_state(w);
+            self.dmc.save_state(w);
+            w.bool(self.five_step);
+            w.bool(self.irq_inhibit);
+            w.bool(self.frame_irq);
+            w.u32(self.frame_cycle);
+            w.option_u8(self.frame_write.map(|(value, _)| value));
+            w.u8(self.frame_write.map_or(0, |(_, delay)| delay));
+            w.bool(self.odd_cycle);
+        });
+    }
+
+    pub fn load_state(&mut self, state: &StateFile) -> Result<(), StateError>{
+        let mut r = state.section(*b"APU ")?;
+        self.pulse1.load_state(&mut r)?;
+        self.pulse2.load_state(&mut r)?;
+        self.triangle.load_state(&mut r)?;
+        self.noise.load_state(&mut r)?;
+        self.dmc.load_state(&mut r)?;
+        self.five_step = r.bool()?;
+        self.irq_inhibit = r.bool()?;
+        self.frame_irq = r.bool()?;
+        self.frame_cycle = r.u32()?;
+        let value = r.option_u8()?;
+        let delay = r.u8()?;
+        self.frame_write = value.map(|value| (value, delay));
+        self.odd_cycle = r.bool()?;
+        return Ok(());
+    }
+
+    // Level of the APU's IRQ output, frame counter or DMC
+    pub fn irq(&self) -> bool{
+        return self.frame_irq || self.dmc.irq();
+    }
+
+    pub fn frame_irq(&self) -> bool{
+        return self.frame_irq;
+    }
+
+    pub fn dmc_irq(&self) -> bool{
+        return self.dmc.irq();
+    }
+
+    // Current output level of each channel: pulse 1, pulse 2, triangle and
+    // noise are 0-15, DMC is 0-127
+    pub fn channel_outputs(&self) -> [u8; 5]{
+        return [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output()
+        ];
+    }
+
+    // $4015 reads. Bit 5 is open bus and left to the caller.
+    pub fn read_status(&mut self) -> u8{
+        let data = self.peek_status();
+        self.frame_irq = false;
+        return data;
+    }
+
+    // $4015 without acknowledging the frame IRQ, for debuggers
+    pub fn peek_status(&self) -> u8{
+        let mut data = 0;
+        if self.pulse1.length.active(){ data |= 0x01; }
+        if self.pulse2.length.active(){ data |= 0x02; }
+        if self.triangle.length.active(){ data |= 0x04; }
+        if self.noise.length.active(){ data |= 0x08; }
+        if self.dmc.active(){ data |= 0x10; }
+        if self.frame_irq{ data |= 0x40; }
+        if self.dmc.irq(){ data |= 0x80; }
+        return data;
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8){
+        match addr{
+            0x4000..=0x4003 => self.pulse1.write(addr, data),
+            0x4004..=0x4007 => self.pulse2.write(addr, data),
+            0x4008..=0x400B => self.triangle.write(addr, data),
+            0x400C..=0x400F => self.noise.write(addr, data, self.region),
+            0x4010..=0x4013 => self.dmc.write(addr, data, self.region),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            }
+            0x4017 => {
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit{
+                    self.frame_irq = false;
+                }
+                // Written on an APU cycle it lands 3 cycles later, between them 4
+                let delay = if self.odd_cycle{ 4 }else{ 3 };
+                self.frame_write = Some((data, delay));
+            }
+            _ => {}
+        }
+    }
+
+    fn quarter_frame(&mut self){
+        self.pulse1.envelope.quarter_frame();
+        self.pulse2.envelope.quarter_frame();
+        self.triangle.quarter_frame();
+        self.noise.envelope.quarter_frame();
+    }
+
+    fn half_frame(&mut self){
+        self.pulse1.half_frame();
+        self.pulse2.half_frame();
+        self.triangle.length.half_frame();
+        self.noise.length.half_frame();
+    }
+
+    fn clock_frame_counter(&mut self){
+        if let Some((data, delay)) = self.frame_write{
+            if delay == 1{
+                self.frame_write = None;
+                self.five_step = data & 0x80 != 0;
+                self.frame_cycle = 0;
+                // Entering 5-step mode clocks everything straight away
+                if self.five_step{
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                return;
+            }
+            self.frame_write = Some((data, delay - 1));
+        }
+
+        self.frame_cycle += 1;
+        let steps = self.region.frame_counter_steps();
+        let cycle = self.frame_cycle;
+        if cycle == steps[0] || cycle == steps[2]{
+            self.quarter_frame();
+        }else if cycle == steps[1]{
+            self.quarter_frame();
+            self.half_frame();
+        }else if !self.five_step{
+            // The IRQ flag is raised on three cycles around the last step
+            if cycle + 1 >= steps[3] && cycle <= steps[3] + 1 && !self.irq_inhibit{
+                self.frame_irq = true;
+            }
+            if cycle == steps[3]{
+                self.quarter_frame();
+                self.half_frame();
+            }else if cycle == steps[3] + 1{
+                self.frame_cycle = 0;
+            }
+        }else if cycle == steps[4]{
+            self.quarter_frame();
+            self.half_frame();
+        }else if cycle == steps[4] + 1{
+            self.frame_cycle = 0;
+        }
+    }
+
+    // One CPU cycle
+    pub fn clock(&mut self){
+        self.clock_frame_counter();
+
+        self.triangle.clock();
+        self.noise.clock();
+        self.dmc.clock();
+        if self.odd_cycle{
+            self.pulse1.clock();
+            self.pulse2.clock();
+        }
+        self.odd_cycle = !self.odd_cycle;
+    }
+
+    // Address the DMC wants read into its sample buffer, if any. The bus does
+    // the read, stalling the CPU, and hands the byte back through dmc_fill.
+    pub fn dmc_request(&self) -> Option<u16>{
+        return self.dmc.request();
+    }
+
+    pub fn dmc_fill(&mut self, data: u8){
+        self.dmc.fill(data);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32){
+        for _ in 0..cycles{
+            apu.clock();
+        }
+    }
+
+    #[test]
+    fn length_counter_loads_only_when_enabled(){
+        let mut apu = Apu::new();
+        apu.cpu_write(0x4003, 0x08);
+        assert_eq!(apu.read_status() & 0x01, 0);
+        apu.cpu_write(0x4015, 0x01);
+        apu.cpu_write(0x4003, 0x08);
+        assert_eq!(apu.read_status() & 0x01, 0x01);
+        apu.cpu_write(0x4015, 0x00);
+        assert_eq!(apu.read_status() & 0x01, 0);
+    }
+
+    #[test]
+    fn length_counter_counts_half_frames(){
+        let mut apu = Apu::new();
+        apu.cpu_write(0x4015, 0x08);
+        // Index 3 loads a length of 2
+        apu.cpu_write(0x400F, 0x18);
+        run(&mut apu, 14914);
+        assert_ne!(apu.read_status() & 0x08, 0);
+        run(&mut apu, 29830 - 14914);
+        assert_eq!(apu.read_status() & 0x08, 0);
+    }
+
+    #[test]
+    fn four_step_mode_raises_frame_irq(){
+        let mut apu = Apu::new();
+        run(&mut apu, 29827);
+        assert!(!apu.irq());
+        run(&mut apu, 1);
+        assert!(apu.irq());
+        // Reading $4015 acknowledges it
+        assert_ne!(apu.read_status() & 0x40, 0);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn inhibit_and_five_step_mode_have_no_irq(){
+        let mut apu = Apu::new();
+        apu.cpu_write(0x4017, 0x40);
+        run(&mut apu, 30000);
+        assert!(!apu.irq());
+        apu.cpu_write(0x4017, 0x80);
+        run(&mut apu, 80000);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn pal_frame_counter_is_slower(){
+        let mut apu = Apu::new();
+        apu.set_region(Region::Pal);
+        run(&mut apu, 29830);
+        assert!(!apu.irq());
+        run(&mut apu, 33252 - 29830);
+        assert!(apu.irq());
+    }
+
+    #[test]
+    fn envelope_decays_and_loops(){
+        let mut envelope = Envelope::default();
+        envelope.write(0x20);
+        envelope.restart();
+        envelope.quarter_frame();
+        assert_eq!(envelope.output(), 15);
+        for _ in 0..15{
+            envelope.quarter_frame();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.quarter_frame();
+        assert_eq!(envelope.output(), 15);
+
+        envelope.write(0x17);
+        assert_eq!(envelope.output(), 7);
+    }
+}
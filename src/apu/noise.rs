@@ -0,0 +1,112 @@
+use crate::apu::{Envelope, LengthCounter};
+use crate::region::Region;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// $400C-$400F. A 15 bit LFSR clocked at one of 16 rates.
+pub struct Noise{
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+    // Mode 1 taps bit 6 instead of bit 1 for a short, metallic sequence
+    short_mode: bool,
+    period: u16, // in CPU cycles
+    timer: u16,
+    shift: u16
+}
+
+impl Noise{
+    pub fn new() -> Self{
+        return Noise{
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            short_mode: false,
+            period: Region::Ntsc.noise_periods()[0],
+            timer: 0,
+            shift: 1
+        };
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter){
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.bool(self.short_mode);
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u16(self.shift);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        self.short_mode = r.bool()?;
+        self.period = r.u16()?;
+        self.timer = r.u16()?;
+        self.shift = r.u16()?;
+        return Ok(());
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8, region: Region){
+        match addr & 0x0003{
+            0 => {
+                self.length.set_halt(data & 0x20 != 0);
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.period = region.noise_periods()[(data & 0x0F) as usize];
+            }
+            _ => {
+                self.length.load(data);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    fn step_shift_register(&mut self){
+        let tap = if self.short_mode{ 6 }else{ 1 };
+        let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+        self.shift = (self.shift >> 1) | (feedback << 14);
+    }
+
+    // One CPU cycle
+    pub fn clock(&mut self){
+        if self.timer == 0{
+            self.timer = self.period - 1;
+            self.step_shift_register();
+        }else{
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8{
+        if !self.length.active() || self.shift & 0x01 != 0{
+            return 0;
+        }
+        return self.envelope.output();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn sequence_length(noise: &mut Noise) -> u32{
+        let start = noise.shift;
+        let mut steps = 0;
+        loop{
+            noise.step_shift_register();
+            steps += 1;
+            if noise.shift == start{
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    fn lfsr_sequence_lengths(){
+        let mut noise = Noise::new();
+        assert_eq!(sequence_length(&mut noise), 32767);
+        noise.write(0x400E, 0x80, Region::Ntsc);
+        assert_eq!(sequence_length(&mut noise), 93);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::apu::{Envelope, LengthCounter};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Read with the sequencer counting down from 0, which is why they look rotated
+static DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1]
+];
+
+// The two sweep units differ only in how they negate the change amount
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SweepNegate{
+    // Pulse 1 subtracts the change and one more
+    OnesComplement,
+    TwosComplement
+}
+
+// $4000-$4003 and $4004-$4007
+pub struct Pulse{
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+    negate_mode: SweepNegate,
+    duty: u8,
+    sequence: u8,
+    period: u16,
+    timer: u16,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool
+}
+
+impl Pulse{
+    pub fn new(negate_mode: SweepNegate) -> Self{
+        return Pulse{
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            negate_mode,
+            duty: 0,
+            sequence: 0,
+            period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false
+        };
+    }
+
+    // The negate mode is fixed by which channel this is
+    pub fn save_state(&self, w: &mut StateWriter){
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.u8(self.duty);
+        w.u8(self.sequence);
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_period);
+        w.bool(self.sweep_negate);
+        w.u8(self.sweep_shift);
+        w.u8(self.sweep_divider);
+        w.bool(self.sweep_reload);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        self.duty = r.u8()? & 0x03;
+        self.sequence = r.u8()? & 0x07;
+        self.period = r.u16()?;
+        self.timer = r.u16()?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_period = r.u8()?;
+        self.sweep_negate = r.bool()?;
+        self.sweep_shift = r.u8()?;
+        self.sweep_divider = r.u8()?;
+        self.sweep_reload = r.bool()?;
+        return Ok(());
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8){
+        match addr & 0x0003{
+            0 => {
+                self.duty = data >> 6;
+                self.length.set_halt(data & 0x20 != 0);
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data);
+                self.sequence = 0;
+                self.envelope.restart();
+            }
+        }
+    }
+
+    // The sweep unit computes this all the time, even when disabled
+    fn sweep_target(&self) -> u16{
+        let change = self.period >> self.sweep_shift;
+        if !self.sweep_negate{
+            return self.period + change;
+        }
+        match self.negate_mode{
+            SweepNegate::OnesComplement => return self.period.saturating_sub(change + 1),
+            SweepNegate::TwosComplement => return self.period.saturating_sub(change)
+        }
+    }
+
+    fn muted(&self) -> bool{
+        return self.period < 8 || self.sweep_target() > 0x07FF;
+    }
+
+    pub fn half_frame(&mut self){
+        self.length.half_frame();
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted(){
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload{
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        }else{
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // One APU cycle
+    pub fn clock(&mut self){
+        if self.timer == 0{
+            self.timer = self.period;
+            self.sequence = self.sequence.wrapping_sub(1) & 0x07;
+        }else{
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8{
+        if !self.length.active() || self.muted() || DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0{
+            return 0;
+        }
+        return self.envelope.output();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn sweep_negation_differs_between_channels(){
+        let mut pulse1 = Pulse::new(SweepNegate::OnesComplement);
+        let mut pulse2 = Pulse::new(SweepNegate::TwosComplement);
+        for pulse in [&mut pulse1, &mut pulse2]{
+            pulse.write(0, 0x00);
+            pulse.write(2, 0x00);
+            pulse.write(3, 0x01);
+            pulse.write(1, 0x89);
+        }
+        assert_eq!(pulse1.sweep_target(), 0x0100 - 0x80 - 1);
+        assert_eq!(pulse2.sweep_target(), 0x0100 - 0x80);
+    }
+
+    #[test]
+    fn sweep_overflow_mutes_even_when_disabled(){
+        let mut pulse = Pulse::new(SweepNegate::TwosComplement);
+        pulse.length.set_enabled(true);
+        pulse.write(0, 0xDF);
+        pulse.write(2, 0xFF);
+        pulse.write(3, 0x07);
+        pulse.write(1, 0x00);
+        assert!(pulse.muted());
+
+        pulse.write(3, 0x03);
+        pulse.write(1, 0x01);
+        assert!(!pulse.muted());
+    }
+
+    #[test]
+    fn sweep_updates_period_on_half_frames(){
+        let mut pulse = Pulse::new(SweepNegate::TwosComplement);
+        pulse.write(2, 0x00);
+        pulse.write(3, 0x01);
+        // Enabled, divider period 0, shift 1
+        pulse.write(1, 0x81);
+        pulse.half_frame();
+        assert_eq!(pulse.period, 0x0180);
+        pulse.half_frame();
+        assert_eq!(pulse.period, 0x0240);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::apu::LengthCounter;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+static SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+];
+
+// $4008-$400B. No volume control, just a second counter for finer lengths.
+pub struct Triangle{
+    pub length: LengthCounter,
+    control: bool, // doubles as the length counter halt
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    period: u16,
+    timer: u16,
+    sequence: u8
+}
+
+impl Triangle{
+    pub fn new() -> Self{
+        return Triangle{
+            length: LengthCounter::default(),
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            period: 0,
+            timer: 0,
+            sequence: 0
+        };
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter){
+        self.length.save_state(w);
+        w.bool(self.control);
+        w.u8(self.linear_reload_value);
+        w.u8(self.linear_counter);
+        w.bool(self.linear_reload);
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u8(self.sequence);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.length.load_state(r)?;
+        self.control = r.bool()?;
+        self.linear_reload_value = r.u8()?;
+        self.linear_counter = r.u8()?;
+        self.linear_reload = r.bool()?;
+        self.period = r.u16()?;
+        self.timer = r.u16()?;
+        self.sequence = r.u8()? & 0x1F;
+        return Ok(());
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8){
+        match addr & 0x0003{
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.set_halt(self.control);
+                self.linear_reload_value = data & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    pub fn quarter_frame(&mut self){
+        if self.linear_reload{
+            self.linear_counter = self.linear_reload_value;
+        }else if self.linear_counter > 0{
+            self.linear_counter -= 1;
+        }
+        if !self.control{
+            self.linear_reload = false;
+        }
+    }
+
+    // One CPU cycle, the triangle runs twice as fast as the other channels
+    pub fn clock(&mut self){
+        if self.timer == 0{
+            self.timer = self.period;
+            if self.length.active() && self.linear_counter > 0{
+                self.sequence = (self.sequence + 1) & 0x1F;
+            }
+        }else{
+            self.timer -= 1;
+        }
+    }
+
+    // Stopping the sequencer holds the last level rather than dropping to 0
+    pub fn output(&self) -> u8{
+        return SEQUENCE[self.sequence as usize];
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn linear_counter_gates_the_sequencer(){
+        let mut triangle = Triangle::new();
+        triangle.length.set_enabled(true);
+        triangle.write(0, 0x02);
+        triangle.write(2, 0x00);
+        triangle.write(3, 0x08);
+        triangle.quarter_frame();
+        for _ in 0..4{
+            triangle.clock();
+        }
+        assert_eq!(triangle.sequence, 4);
+
+        triangle.quarter_frame();
+        triangle.quarter_frame();
+        for _ in 0..4{
+            triangle.clock();
+        }
+        assert_eq!(triangle.sequence, 4);
+        assert_eq!(triangle.output(), 11);
+    }
+}
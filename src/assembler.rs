@@ -0,0 +1,647 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::cpu::{AddrMode, Operation, INSTRUCTIONS};
+use crate::disasm;
+
+// A small two pass assembler for tests and patching code in the debugger.
+// It reads what the disassembler writes, ca65 flavoured:
+//
+//         .org $C000
+// reset:  LDX #$FF
+//         TXS
+// wait:   BIT $2002
+//         BPL wait
+//         LDA table,X       ; labels can be used before they are defined
+//         JMP (vector)
+// table:  .byte 1, 2, "text"
+// vector: .word reset
+//
+// Operands are numbers ($hex, %binary, decimal, 'c'), labels and * for the
+// current address, added or subtracted, with < and > picking the low or
+// high byte. Zero page is used whenever the address is known to fit by the
+// time the instruction is reached; a: and z: force the size either way.
+// Unofficial opcodes go by their ca65 names, or the ones in Operation.
+
+#[derive(Debug, PartialEq)]
+pub enum AsmErrorKind{
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    // Operand text that doesn't parse
+    BadOperand(String),
+    // The instruction exists, just not with that addressing mode
+    BadMode(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    // Branch distance from the next instruction
+    BranchOutOfRange(i64),
+    // Value too big for the byte or word it has to fit in
+    OutOfRange(i64)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError{
+    pub line: usize,
+    pub kind: AsmErrorKind
+}
+
+impl fmt::Display for AsmError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind{
+            AsmErrorKind::UnknownMnemonic(name) => write!(f, "unknown instruction {}", name),
+            AsmErrorKind::UnknownDirective(name) => write!(f, "unknown directive {}", name),
+            AsmErrorKind::BadOperand(text) => write!(f, "can't parse operand \"{}\"", text),
+            AsmErrorKind::BadMode(name) => write!(f, "{} doesn't have that addressing mode", name),
+            AsmErrorKind::UndefinedLabel(name) => write!(f, "label {} is never defined", name),
+            AsmErrorKind::DuplicateLabel(name) => write!(f, "label {} is defined twice", name),
+            AsmErrorKind::BranchOutOfRange(distance) => write!(f, "branch is {} bytes away, more than a branch reaches", distance),
+            AsmErrorKind::OutOfRange(value) => write!(f, "{} doesn't fit", value)
+        }
+    }
+}
+
+impl Error for AsmError{}
+
+// A run of bytes placed at an address
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment{
+    pub origin: u16,
+    pub data: Vec<u8>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program{
+    // One per .org, in source order
+    pub segments: Vec<Segment>,
+    pub labels: HashMap<String, u16>
+}
+
+impl Program{
+    // Everything from the lowest address written to the highest, with any
+    // gaps between segments left as zero
+    pub fn bytes(&self) -> Vec<u8>{
+        let used = self.segments.iter().filter(|segment| !segment.data.is_empty());
+        let start = match used.clone().map(|segment| segment.origin as usize).min(){
+            Some(start) => start,
+            None => return Vec::new()
+        };
+        let end = used.clone().map(|segment| segment.origin as usize + segment.data.len()).max().unwrap();
+        let mut bytes = vec![0; end - start];
+        for segment in used{
+            let offset = segment.origin as usize - start;
+            bytes[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        return bytes;
+    }
+
+    pub fn label(&self, name: &str) -> Option<u16>{
+        return self.labels.get(name).copied();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Term{
+    Number(i64),
+    Label(String),
+    Here
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Part{
+    Whole,
+    Low,
+    High
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Expr{
+    part: Part,
+    // Added together, each negated when its flag is set
+    terms: Vec<(bool, Term)>
+}
+
+// How the operand was written, before zero page or absolute is decided
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Syntax{
+    Implied,
+    Immediate,
+    Direct,
+    IndexedX,
+    IndexedY,
+    Indirect,
+    IndirectX,
+    IndirectY
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Size{
+    Any,
+    ZeroPage,
+    Absolute
+}
+
+enum Statement{
+    Instruction{ opcode: u8, mode: AddrMode, operand: Option<Expr> },
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>)
+}
+
+// A statement with the address pass one gave it
+struct Placed{
+    line: usize,
+    addr: u16,
+    segment: usize,
+    statement: Statement
+}
+
+fn error(line: usize, kind: AsmErrorKind) -> AsmError{
+    return AsmError{ line, kind };
+}
+
+fn is_label(name: &str) -> bool{
+    let mut chars = name.chars();
+    match chars.next(){
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '@' => {}
+        _ => return false
+    }
+    return chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+}
+
+fn parse_number(text: &str) -> Option<i64>{
+    if let Some(hex) = text.strip_prefix('$'){
+        return i64::from_str_radix(hex, 16).ok();
+    }
+    if let Some(binary) = text.strip_prefix('%'){
+        return i64::from_str_radix(binary, 2).ok();
+    }
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() == 3 && chars[0] == '\'' && chars[2] == '\''{
+        return Some(chars[1] as i64);
+    }
+    if text.chars().all(|c| c.is_ascii_digit()) && !text.is_empty(){
+        return text.parse().ok();
+    }
+    return None;
+}
+
+fn parse_term(text: &str) -> Option<Term>{
+    if text == "*"{
+        return Some(Term::Here);
+    }
+    if let Some(number) = parse_number(text){
+        return Some(Term::Number(number));
+    }
+    if is_label(text){
+        return Some(Term::Label(String::from(text)));
+    }
+    return None;
+}
+
+fn parse_expr(text: &str) -> Option<Expr>{
+    let text = text.trim();
+    let (part, text) = match text.chars().next(){
+        Some('<') => (Part::Low, &text[1..]),
+        Some('>') => (Part::High, &text[1..]),
+        _ => (Part::Whole, text)
+    };
+
+    let mut terms = Vec::new();
+    let mut negative = false;
+    let mut start = 0;
+    let bytes = text.as_bytes();
+    for (i, &c) in bytes.iter().enumerate(){
+        // A sign right at the start of a term is a negative number
+        let operator = (c == b'+' || c == b'-') && i > start;
+        if operator{
+            terms.push((negative, parse_term(text[start..i].trim())?));
+            negative = c == b'-';
+            start = i + 1;
+        }
+    }
+    let last = text[start..].trim();
+    if let Some(rest) = last.strip_prefix('-'){
+        terms.push((!negative, parse_term(rest.trim())?));
+    }else{
+        terms.push((negative, parse_term(last)?));
+    }
+    return Some(Expr{ part, terms });
+}
+
+// Value of an expression, or None while a label it uses is still unknown
+fn evaluate(expr: &Expr, labels: &HashMap<String, u16>, here: u16) -> Option<i64>{
+    let mut total: i64 = 0;
+    for (negative, term) in expr.terms.iter(){
+        let value = match term{
+            Term::Number(number) => *number,
+            Term::Here => here as i64,
+            Term::Label(name) => {
+                match labels.get(name){
+                    Some(addr) => *addr as i64,
+                    None => return None
+                }
+            }
+        };
+        total += if *negative{ -value }else{ value };
+    }
+    match expr.part{
+        Part::Whole => return Some(total),
+        Part::Low => return Some(total & 0xFF),
+        Part::High => return Some((total >> 8) & 0xFF)
+    }
+}
+
+// Splits on commas that aren't inside quotes
+fn split_list(text: &str) -> Vec<&str>{
+    let mut items = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices(){
+        match quote{
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ',' => {
+                items.push(text[start..i].trim());
+                start = i + 1;
+            }
+            None => {}
+        }
+    }
+    items.push(text[start..].trim());
+    return items;
+}
+
+// Drops a ; comment, leaving any ; inside quotes alone
+fn strip_comment(line: &str) -> &str{
+    let mut quote = None;
+    for (i, c) in line.char_indices(){
+        match quote{
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => return &line[..i],
+            None => {}
+        }
+    }
+    return line;
+}
+
+fn parse_operand(text: &str) -> Option<(Syntax, Size, Option<Expr>)>{
+    let text = text.trim();
+    if text.is_empty() || text.eq_ignore_ascii_case("A"){
+        return Some((Syntax::Implied, Size::Any, None));
+    }
+    if let Some(value) = text.strip_prefix('#'){
+        return Some((Syntax::Immediate, Size::Any, Some(parse_expr(value)?)));
+    }
+
+    let upper = text.to_ascii_uppercase();
+    if text.starts_with('('){
+        let (syntax, inner) = if upper.ends_with(",X)"){
+            (Syntax::IndirectX, &text[1..text.len() - 3])
+        }else if upper.ends_with("),Y"){
+            (Syntax::IndirectY, &text[1..text.len() - 3])
+        }else if text.ends_with(')'){
+            (Syntax::Indirect, &text[1..text.len() - 1])
+        }else{
+            return None;
+        };
+        return Some((syntax, Size::Any, Some(parse_expr(inner)?)));
+    }
+
+    let (syntax, address) = if upper.ends_with(",X"){
+        (Syntax::IndexedX, &text[..text.len() - 2])
+    }else if upper.ends_with(",Y"){
+        (Syntax::IndexedY, &text[..text.len() - 2])
+    }else{
+        (Syntax::Direct, text)
+    };
+    let address = address.trim();
+    let lower = address.to_ascii_lowercase();
+    let (size, address) = if lower.starts_with("a:"){
+        (Size::Absolute, &address[2..])
+    }else if lower.starts_with("z:"){
+        (Size::ZeroPage, &address[2..])
+    }else{
+        (Size::Any, address)
+    };
+    return Some((syntax, size, Some(parse_expr(address)?)));
+}
+
+fn operation_named(name: &str) -> Option<Operation>{
+    let name = name.to_ascii_uppercase();
+    if name == "ISB"{
+        return Some(Operation::ISC);
+    }
+    return INSTRUCTIONS.iter()
+        .map(|instruction| instruction.oper())
+        .find(|&operation| disasm::mnemonic(operation) == name || format!("{:?}", operation) == name);
+}
+
+// The opcode for an operation in a mode, the documented one where there's
+// a choice
+fn opcode_for(operation: Operation, mode: AddrMode) -> Option<u8>{
+    let mut matching = INSTRUCTIONS.iter()
+        .filter(|instruction| instruction.oper() == operation && instruction.addr_mode() == mode);
+    let first = matching.clone().next()?.opcode();
+    return Some(matching.find(|instruction| !disasm::is_unofficial(instruction.opcode(), operation))
+        .map(|instruction| instruction.opcode())
+        .unwrap_or(first));
+}
+
+fn pick_mode(operation: Operation, syntax: Syntax, size: Size, value: Option<i64>) -> Option<(u8, AddrMode)>{
+    let fits = match size{
+        Size::ZeroPage => true,
+        Size::Absolute => false,
+        Size::Any => value.is_some_and(|v| (0..0x100).contains(&v))
+    };
+    let modes: Vec<AddrMode> = match syntax{
+        Syntax::Implied => vec![AddrMode::IMP],
+        Syntax::Immediate => vec![AddrMode::IMM],
+        Syntax::Indirect => vec![AddrMode::IND],
+        Syntax::IndirectX => vec![AddrMode::IZX],
+        Syntax::IndirectY => vec![AddrMode::IZY],
+        Syntax::Direct => vec![AddrMode::REL, AddrMode::ZP0, AddrMode::ABS],
+        Syntax::IndexedX => vec![AddrMode::ZPX, AddrMode::ABX],
+        Syntax::IndexedY => vec![AddrMode::ZPY, AddrMode::ABY]
+    };
+    for mode in modes{
+        let zero_page = mode == AddrMode::ZP0 || mode == AddrMode::ZPX || mode == AddrMode::ZPY;
+        let absolute = mode == AddrMode::ABS || mode == AddrMode::ABX || mode == AddrMode::ABY;
+        if (zero_page && !fits) || (absolute && size == Size::ZeroPage){
+            continue;
+        }
+        if let Some(opcode) = opcode_for(operation, mode){
+            return Some((opcode, mode));
+        }
+    }
+    return None;
+}
+
+fn parse_string(text: &str) -> Option<Vec<u8>>{
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"'){
+        return Some(text[1..text.len() - 1].bytes().collect());
+    }
+    return None;
+}
+
+// Assembles source with the first instruction at origin, unless a .org
+// says otherwise
+pub fn assemble(source: &str, origin: u16) -> Result<Program, AsmError>{
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut placed: Vec<Placed> = Vec::new();
+    let mut segments = vec![Segment{ origin, data: Vec::new() }];
+    let mut pc = origin as u32;
+
+    // Pass one: work out where everything goes
+    for (index, raw) in source.lines().enumerate(){
+        let line = index + 1;
+        let mut text = strip_comment(raw).trim();
+
+        if let Some(colon) = text.find(':'){
+            let name = text[..colon].trim();
+            // a:$1234 is an operand, not a label
+            if is_label(name) && !text[..colon].contains(char::is_whitespace){
+                if labels.insert(String::from(name), pc as u16).is_some(){
+                    return Err(error(line, AsmErrorKind::DuplicateLabel(String::from(name))));
+                }
+                text = text[colon + 1..].trim();
+            }
+        }
+        if text.is_empty(){
+            continue;
+        }
+
+        let (word, rest) = match text.find(char::is_whitespace){
+            Some(space) => (&text[..space], text[space..].trim()),
+            None => (text, "")
+        };
+
+        if word.starts_with('.'){
+            let statement = match word.to_ascii_lowercase().as_str(){
+                ".org" => {
+                    let expr = parse_expr(rest).ok_or_else(|| error(line, AsmErrorKind::BadOperand(String::from(rest))))?;
+                    let value = evaluate(&expr, &labels, pc as u16)
+                        .ok_or_else(|| error(line, AsmErrorKind::BadOperand(String::from(rest))))?;
+                    if !(0..0x10000).contains(&value){
+                        return Err(error(line, AsmErrorKind::OutOfRange(value)));
+                    }
+                    pc = value as u32;
+                    segments.push(Segment{ origin: pc as u16, data: Vec::new() });
+                    continue;
+                }
+                ".byte" => {
+                    let mut exprs = Vec::new();
+                    for item in split_list(rest){
+                        // A string is just its bytes, one value each
+                        if let Some(string) = parse_string(item){
+                            exprs.extend(string.iter().map(|&b| Expr{ part: Part::Whole, terms: vec![(false, Term::Number(b as i64))] }));
+                            continue;
+                        }
+                        exprs.push(parse_expr(item).ok_or_else(|| error(line, AsmErrorKind::BadOperand(String::from(item))))?);
+                    }
+                    Statement::Bytes(exprs)
+                }
+                ".word" => {
+                    let mut exprs = Vec::new();
+                    for item in split_list(rest){
+                        exprs.push(parse_expr(item).ok_or_else(|| error(line, AsmErrorKind::BadOperand(String::from(item))))?);
+                    }
+                    Statement::Words(exprs)
+                }
+                _ => return Err(error(line, AsmErrorKind::UnknownDirective(String::from(word))))
+            };
+            let size = match &statement{
+                Statement::Bytes(exprs) => exprs.len(),
+                Statement::Words(exprs) => exprs.len() * 2,
+                Statement::Instruction{ .. } => 0
+            };
+            placed.push(Placed{ line, addr: pc as u16, segment: segments.len() - 1, statement });
+            pc += size as u32;
+            continue;
+        }
+
+        let operation = operation_named(word).ok_or_else(|| error(line, AsmErrorKind::UnknownMnemonic(String::from(word))))?;
+        let (syntax, size, operand) = parse_operand(rest).ok_or_else(|| error(line, AsmErrorKind::BadOperand(String::from(rest))))?;
+        let value = match &operand{
+            Some(expr) => evaluate(expr, &labels, pc as u16),
+            None => None
+        };
+        let (opcode, mode) = pick_mode(operation, syntax, size, value)
+            .ok_or_else(|| error(line, AsmErrorKind::BadMode(disasm::mnemonic(operation))))?;
+        placed.push(Placed{ line, addr: pc as u16, segment: segments.len() - 1, statement: Statement::Instruction{ opcode, mode, operand } });
+        pc += disasm::length(mode) as u32;
+    }
+
+    // Pass two: every label is known, fill in the bytes
+    for item in placed.iter(){
+        let line = item.line;
+        let value_of = |expr: &Expr| -> Result<i64, AsmError>{
+            return evaluate(expr, &labels, item.addr)
+                .ok_or_else(|| {
+                    let missing = expr.terms.iter().find_map(|(_, term)| match term{
+                        Term::Label(name) if !labels.contains_key(name) => Some(name.clone()),
+                        _ => None
+                    });
+                    return error(line, AsmErrorKind::UndefinedLabel(missing.unwrap_or_default()));
+                });
+        };
+        let byte = |value: i64| -> Result<u8, AsmError>{
+            if !(-0x80..0x100).contains(&value){
+                return Err(error(line, AsmErrorKind::OutOfRange(value)));
+            }
+            return Ok(value as u8);
+        };
+        let word = |value: i64| -> Result<u16, AsmError>{
+            if !(-0x8000..0x10000).contains(&value){
+                return Err(error(line, AsmErrorKind::OutOfRange(value)));
+            }
+            return Ok(value as u16);
+        };
+
+        let mut out = Vec::new();
+        match &item.statement{
+            Statement::Instruction{ opcode, mode, operand } => {
+                out.push(*opcode);
+                if let Some(expr) = operand{
+                    let value = value_of(expr)?;
+                    match mode{
+                        AddrMode::REL => {
+                            let distance = value - (item.addr as i64 + 2);
+                            if !(-128..128).contains(&distance){
+                                return Err(error(line, AsmErrorKind::BranchOutOfRange(distance)));
+                            }
+                            out.push(distance as u8);
+                        }
+                        _ if disasm::length(*mode) == 3 => out.extend_from_slice(&word(value)?.to_le_bytes()),
+                        _ => out.push(byte(value)?)
+                    }
+                }
+            }
+            Statement::Bytes(exprs) => {
+                for expr in exprs.iter(){
+                    out.push(byte(value_of(expr)?)?);
+                }
+            }
+            Statement::Words(exprs) => {
+                for expr in exprs.iter(){
+                    out.extend_from_slice(&word(value_of(expr)?)?.to_le_bytes());
+                }
+            }
+        }
+        segments[item.segment].data.extend(out);
+    }
+
+    return Ok(Program{ segments, labels });
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn bytes(source: &str) -> Vec<u8>{
+        return assemble(source, 0xC000).unwrap().bytes();
+    }
+
+    fn error_kind(source: &str) -> AsmErrorKind{
+        return assemble(source, 0xC000).unwrap_err().kind;
+    }
+
+    #[test]
+    fn assembles_every_mode(){
+        assert_eq!(bytes("NOP"), vec![0xEA]);
+        assert_eq!(bytes("asl a\nLSR"), vec![0x0A, 0x4A]);
+        assert_eq!(bytes("LDA #$7F"), vec![0xA9, 0x7F]);
+        assert_eq!(bytes("LDA $10"), vec![0xA5, 0x10]);
+        assert_eq!(bytes("LDA $10,X"), vec![0xB5, 0x10]);
+        assert_eq!(bytes("LDX $10,y"), vec![0xB6, 0x10]);
+        assert_eq!(bytes("LDA $1234"), vec![0xAD, 0x34, 0x12]);
+        assert_eq!(bytes("LDA $1234,X"), vec![0xBD, 0x34, 0x12]);
+        assert_eq!(bytes("LDA $1234,Y"), vec![0xB9, 0x34, 0x12]);
+        assert_eq!(bytes("JMP ($FFFC)"), vec![0x6C, 0xFC, 0xFF]);
+        assert_eq!(bytes("LDA ($10,X)"), vec![0xA1, 0x10]);
+        assert_eq!(bytes("LDA ($10),Y"), vec![0xB1, 0x10]);
+        assert_eq!(bytes("BNE *"), vec![0xD0, 0xFE]);
+    }
+
+    #[test]
+    fn picks_zero_page_when_it_can(){
+        // No zero page,Y for LDA
+        assert_eq!(bytes("LDA $10,Y"), vec![0xB9, 0x10, 0x00]);
+        assert_eq!(bytes("LDA a:$10"), vec![0xAD, 0x10, 0x00]);
+        assert_eq!(bytes("JMP $0010"), vec![0x4C, 0x10, 0x00]);
+        // A forward label is assumed to be absolute, so sizes never change
+        // between passes
+        let program = assemble("LDA z:zp\nLDA zp\n.org $0010\nzp: .byte 0", 0xC000).unwrap();
+        assert_eq!(program.segments[0].data, vec![0xA5, 0x10, 0xAD, 0x10, 0x00]);
+    }
+
+    #[test]
+    fn labels_and_directives(){
+        let program = assemble("
+            .org $C000
+    reset:  LDX #$FF        ; set up the stack
+            TXS
+    wait:   BIT $2002
+            BPL wait
+            LDA table+1,X
+            JMP (vector)
+    table:  .byte 1, $02, %11, 'A', \"hi;\", <reset, >reset
+    vector: .word reset, $1234
+        ", 0).unwrap();
+        assert_eq!(program.label("wait"), Some(0xC003));
+        assert_eq!(program.label("table"), Some(0xC00E));
+        assert_eq!(program.segments[1].origin, 0xC000);
+        assert_eq!(program.bytes(), vec![
+            0xA2, 0xFF, 0x9A, 0x2C, 0x02, 0x20, 0x10, 0xFB, 0xBD, 0x0F, 0xC0, 0x6C, 0x17, 0xC0,
+            0x01, 0x02, 0x03, 0x41, 0x68, 0x69, 0x3B, 0x00, 0xC0,
+            0x00, 0xC0, 0x34, 0x12
+        ]);
+    }
+
+    #[test]
+    fn unofficial_mnemonics(){
+        assert_eq!(bytes("LAX $10"), vec![0xA7, 0x10]);
+        assert_eq!(bytes("DCP ($10),Y"), vec![0xD3, 0x10]);
+        assert_eq!(bytes("ISB $10\nISC $10"), vec![0xE7, 0x10, 0xE7, 0x10]);
+        assert_eq!(bytes("JAM\nKIL"), vec![0x02, 0x02]);
+        assert_eq!(bytes("SHA $0200,Y\nAHX $0200,Y"), vec![0x9F, 0x00, 0x02, 0x9F, 0x00, 0x02]);
+        // The documented opcode wins where there's a choice
+        assert_eq!(bytes("SBC #1\nNOP"), vec![0xE9, 0x01, 0xEA]);
+        assert_eq!(bytes("NOP #1\nNOP $10"), vec![0x80, 0x01, 0x04, 0x10]);
+    }
+
+    #[test]
+    fn reports_errors_with_their_line(){
+        let error = assemble("NOP\nFOO", 0).unwrap_err();
+        assert_eq!(error, AsmError{ line: 2, kind: AsmErrorKind::UnknownMnemonic(String::from("FOO")) });
+        assert_eq!(error.to_string(), "line 2: unknown instruction FOO");
+        assert_eq!(error_kind("JMP nowhere"), AsmErrorKind::UndefinedLabel(String::from("nowhere")));
+        assert_eq!(error_kind("a: NOP\na: NOP"), AsmErrorKind::DuplicateLabel(String::from("a")));
+        assert_eq!(error_kind("STA #1"), AsmErrorKind::BadMode(String::from("STA")));
+        assert_eq!(error_kind("LDA #$100"), AsmErrorKind::OutOfRange(0x100));
+        assert_eq!(error_kind("LDA (1"), AsmErrorKind::BadOperand(String::from("(1")));
+        assert_eq!(error_kind(".fill 1"), AsmErrorKind::UnknownDirective(String::from(".fill")));
+        assert_eq!(error_kind("BNE far\n.org $C100\nfar: NOP"), AsmErrorKind::BranchOutOfRange(0xFE));
+    }
+
+    #[test]
+    fn reassembles_the_disassembly_of_every_opcode(){
+        for opcode in 0..=0xFFu8{
+            let code = [opcode, 0x34, 0x12];
+            let decoded = disasm::decode_bytes(&code, 0xC000, 0xC000);
+            let program = assemble(&decoded.to_string(), 0xC000)
+                .unwrap_or_else(|e| panic!("{:02X} \"{}\": {}", opcode, decoded, e));
+            let again = disasm::decode_bytes(&program.bytes(), 0xC000, 0xC000);
+            assert_eq!((again.operation, again.mode), (decoded.operation, decoded.mode), "{:02X} \"{}\"", opcode, decoded);
+            assert_eq!(&again.bytes[1..], &decoded.bytes[1..], "{:02X} \"{}\"", opcode, decoded);
+        }
+        // Absolute addresses in zero page survive the trip as well
+        let decoded = disasm::decode_bytes(&[0xAD, 0x12, 0x00], 0xC000, 0xC000);
+        assert_eq!(bytes(&decoded.to_string()), vec![0xAD, 0x12, 0x00]);
+    }
+}
@@ -0,0 +1,362 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// Turns the APU's per-cycle channel levels into host rate samples. Levels
+// are mixed through the console's nonlinear DACs, resampled with band-limited
+// steps so nothing above the host Nyquist rate aliases back down, then run
+// through the same filters the NES has on its audio output. Nothing here
+// touches a sound device, so it works just as well headless.
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+// About a third of a second at 48kHz
+const DEFAULT_CAPACITY: usize = 16 * 1024;
+
+/******
+ * Mixer
+ ******/
+
+// The two DAC networks, as lookup tables indexed by the summed channel levels
+struct MixerTables{
+    pulse: [f32; 31],
+    tnd: [f32; 203]
+}
+
+impl MixerTables{
+    fn new() -> Self{
+        let mut tables = MixerTables{ pulse: [0.0; 31], tnd: [0.0; 203] };
+        for n in 1..tables.pulse.len(){
+            tables.pulse[n] = (95.52 / (8128.0 / n as f64 + 100.0)) as f32;
+        }
+        for n in 1..tables.tnd.len(){
+            tables.tnd[n] = (163.67 / (24329.0 / n as f64 + 100.0)) as f32;
+        }
+        return tables;
+    }
+
+    // Channel levels in Apu::channel_outputs order, mixed to 0.0-1.0
+    fn mix(&self, outputs: [u8; 5]) -> f32{
+        let [pulse1, pulse2, triangle, noise, dmc] = outputs;
+        let pulse = self.pulse[(pulse1 + pulse2) as usize];
+        let tnd = self.tnd[3 * triangle as usize + 2 * noise as usize + dmc as usize];
+        return pulse + tnd;
+    }
+}
+
+/******
+ * Band-limited step synthesis
+ ******/
+
+const KERNEL_PHASES: usize = 32;
+const KERNEL_WIDTH: usize = 16;
+// Fraction of the host Nyquist rate the kernel passes
+const KERNEL_CUTOFF: f64 = 0.9;
+
+// Each level change is drawn as a windowed sinc impulse into a buffer of
+// deltas, which integrates back into band-limited steps when read out.
+struct Blip{
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    samples_per_clock: f64,
+    // Sample position of clock 0, relative to the first unread delta
+    origin: f64,
+    clock: u64,
+    deltas: Vec<f32>,
+    integrator: f32
+}
+
+impl Blip{
+    fn new(clock_rate: f64, sample_rate: u32) -> Self{
+        let mut kernel = vec![[0.0; KERNEL_WIDTH]; KERNEL_PHASES];
+        for (phase, taps) in kernel.iter_mut().enumerate(){
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut sum = 0.0;
+            let mut raw = [0.0; KERNEL_WIDTH];
+            for (k, tap) in raw.iter_mut().enumerate(){
+                let t = k as f64 - offset - (KERNEL_WIDTH / 2) as f64 + 1.0;
+                let x = t * KERNEL_CUTOFF * PI;
+                let sinc = if x == 0.0{ 1.0 }else{ x.sin() / x };
+                // Blackman window across the kernel
+                let w = 2.0 * PI * (t + (KERNEL_WIDTH / 2) as f64) / KERNEL_WIDTH as f64;
+                let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+                *tap = sinc * window;
+                sum += *tap;
+            }
+            // Every phase has to add up to exactly one step
+            for k in 0..KERNEL_WIDTH{
+                taps[k] = (raw[k] / sum) as f32;
+            }
+        }
+
+        return Blip{
+            kernel,
+            samples_per_clock: sample_rate as f64 / clock_rate,
+            origin: 0.0,
+            clock: 0,
+            deltas: Vec::new(),
+            integrator: 0.0
+        };
+    }
+
+    fn position(&self) -> f64{
+        return self.origin + self.clock as f64 * self.samples_per_clock;
+    }
+
+    fn add_delta(&mut self, delta: f32){
+        let position = self.position();
+        let index = position as usize;
+        let phase = ((position - index as f64) * KERNEL_PHASES as f64) as usize;
+        if self.deltas.len() < index + KERNEL_WIDTH{
+            self.deltas.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (k, tap) in self.kernel[phase].iter().enumerate(){
+            self.deltas[index + k] += delta * tap;
+        }
+    }
+
+    // Hands over every sample no future delta can reach any more
+    fn read(&mut self, out: &mut impl FnMut(f32)){
+        let position = self.position();
+        let ready = position as usize;
+        if self.deltas.len() < ready{
+            self.deltas.resize(ready, 0.0);
+        }
+        for delta in self.deltas.drain(..ready){
+            self.integrator += delta;
+            out(self.integrator);
+        }
+        self.origin = position - ready as f64;
+        self.clock = 0;
+    }
+}
+
+/******
+ * Output filters
+ ******/
+
+// First order high and low pass filters, run at the host rate
+enum Filter{
+    HighPass{ alpha: f32, previous_in: f32, previous_out: f32 },
+    LowPass{ alpha: f32, previous_out: f32 }
+}
+
+impl Filter{
+    fn high_pass(sample_rate: u32, cutoff: f64) -> Self{
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f64;
+        return Filter::HighPass{ alpha: (rc / (rc + dt)) as f32, previous_in: 0.0, previous_out: 0.0 };
+    }
+
+    fn low_pass(sample_rate: u32, cutoff: f64) -> Self{
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f64;
+        return Filter::LowPass{ alpha: (dt / (rc + dt)) as f32, previous_out: 0.0 };
+    }
+
+    fn process(&mut self, sample: f32) -> f32{
+        match self{
+            Filter::HighPass{ alpha, previous_in, previous_out } => {
+                *previous_out = *alpha * (*previous_out + sample - *previous_in);
+                *previous_in = sample;
+                return *previous_out;
+            }
+            Filter::LowPass{ alpha, previous_out } => {
+                *previous_out += *alpha * (sample - *previous_out);
+                return *previous_out;
+            }
+        }
+    }
+}
+
+/******
+ * Sample ring buffer
+ ******/
+
+// Fixed size FIFO between the emulator and whoever plays the audio. When
+// nobody drains it the oldest samples are dropped.
+pub struct SampleRing{
+    samples: VecDeque<f32>,
+    capacity: usize
+}
+
+impl SampleRing{
+    pub fn new(capacity: usize) -> Self{
+        return SampleRing{ samples: VecDeque::with_capacity(capacity), capacity };
+    }
+
+    pub fn push(&mut self, sample: f32){
+        if self.samples.len() == self.capacity{
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn len(&self) -> usize{
+        return self.samples.len();
+    }
+
+    pub fn is_empty(&self) -> bool{
+        return self.samples.is_empty();
+    }
+
+    // Fills out with the oldest samples, returning how many were written
+    pub fn read(&mut self, out: &mut [f32]) -> usize{
+        let count = out.len().min(self.samples.len());
+        for (slot, sample) in out.iter_mut().zip(self.samples.drain(..count)){
+            *slot = sample;
+        }
+        return count;
+    }
+
+    pub fn clear(&mut self){
+        self.samples.clear();
+    }
+}
+
+/******
+ * The pipeline
+ ******/
+
+pub struct Audio{
+    mixer: MixerTables,
+    blip: Blip,
+    filters: [Filter; 3],
+    ring: SampleRing,
+    sample_rate: u32,
+    level: f32
+}
+
+// Clocks between moving samples out of the blip buffer
+const FLUSH_CLOCKS: u64 = 4096;
+
+impl Audio{
+    // clock_rate is the CPU clock in Hz, the rate clock() is called at
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self{
+        return Audio{
+            mixer: MixerTables::new(),
+            blip: Blip::new(clock_rate, sample_rate),
+            filters: [
+                Filter::high_pass(sample_rate, 90.0),
+                Filter::high_pass(sample_rate, 440.0),
+                Filter::low_pass(sample_rate, 14_000.0)
+            ],
+            ring: SampleRing::new(DEFAULT_CAPACITY),
+            sample_rate,
+            level: 0.0
+        };
+    }
+
+    pub fn sample_rate(&self) -> u32{
+        return self.sample_rate;
+    }
+
+    // One CPU cycle worth of APU output
+    pub fn clock(&mut self, outputs: [u8; 5]){
+        let level = self.mixer.mix(outputs);
+        if level != self.level{
+            self.blip.add_delta(level - self.level);
+            self.level = level;
+        }
+        self.blip.clock += 1;
+        if self.blip.clock >= FLUSH_CLOCKS{
+            self.flush();
+        }
+    }
+
+    // Moves everything finished so far into the ring buffer
+    pub fn flush(&mut self){
+        let filters = &mut self.filters;
+        let ring = &mut self.ring;
+        self.blip.read(&mut |sample|{
+            let mut sample = sample;
+            for filter in filters.iter_mut(){
+                sample = filter.process(sample);
+            }
+            ring.push(sample.clamp(-1.0, 1.0));
+        });
+    }
+
+    pub fn samples(&mut self) -> &mut SampleRing{
+        return &mut self.ring;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const NTSC_CPU_HZ: f64 = 21_477_272.0 / 12.0;
+
+    #[test]
+    fn mixer_tables_match_the_formulas(){
+        let tables = MixerTables::new();
+        assert_eq!(tables.mix([0, 0, 0, 0, 0]), 0.0);
+        assert!((tables.mix([15, 15, 0, 0, 0]) - 0.2575).abs() < 0.0001);
+        assert!((tables.mix([0, 0, 15, 15, 127]) - 0.7425).abs() < 0.0001);
+    }
+
+    #[test]
+    fn one_second_makes_one_second_of_samples(){
+        for rate in [44_100, 48_000]{
+            let mut audio = Audio::new(NTSC_CPU_HZ, rate);
+            audio.ring = SampleRing::new(rate as usize * 2);
+            for _ in 0..NTSC_CPU_HZ as u64{
+                audio.clock([0; 5]);
+            }
+            audio.flush();
+            assert!((audio.samples().len() as i64 - rate as i64).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn steps_settle_at_their_height(){
+        let mut blip = Blip::new(NTSC_CPU_HZ, 48_000);
+        blip.add_delta(0.5);
+        blip.clock += 1000;
+        let mut last = 0.0;
+        blip.read(&mut |sample| last = sample);
+        assert!((last - 0.5).abs() < 0.0001);
+    }
+
+    #[test]
+    fn square_wave_is_band_limited(){
+        // A 12.4kHz square wave, close enough to Nyquist that naive
+        // resampling would overshoot wildly or alias
+        let mut audio = Audio::new(NTSC_CPU_HZ, 48_000);
+        for cycle in 0..100_000u32{
+            let high = (cycle / 72).is_multiple_of(2);
+            audio.clock([if high{ 15 }else{ 0 }, 0, 0, 0, 0]);
+        }
+        audio.flush();
+        let mut out = vec![0.0; audio.samples().len()];
+        audio.samples().read(&mut out);
+        assert!(out.iter().all(|s| s.abs() < 0.3));
+    }
+
+    #[test]
+    fn output_is_deterministic(){
+        let run = ||{
+            let mut audio = Audio::new(NTSC_CPU_HZ, 44_100);
+            for cycle in 0..50_000u32{
+                audio.clock([(cycle / 200 % 16) as u8, 0, (cycle / 31 % 16) as u8, 0, 64]);
+            }
+            audio.flush();
+            let mut out = vec![0.0; audio.samples().len()];
+            audio.samples().read(&mut out);
+            return out;
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn ring_drops_oldest_samples(){
+        let mut ring = SampleRing::new(4);
+        for n in 0..6{
+            ring.push(n as f32);
+        }
+        let mut out = [0.0; 8];
+        assert_eq!(ring.read(&mut out), 4);
+        assert_eq!(&out[..4], &[2.0, 3.0, 4.0, 5.0]);
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::nes::Nes;
+
+// Battery backed PRG RAM, kept in a .sav file of the raw RAM contents
+// beside the ROM or in a save directory of its own. The file is read once
+// at startup and written back when the RAM has changed, every so often
+// while running and once more on exit.
+//
+// Writes go to a temporary file that is then renamed over the old save, so
+// a crash halfway through leaves the previous save in one piece.
+
+// About five seconds
+pub const DEFAULT_FLUSH_INTERVAL: u64 = 300;
+
+pub struct BatterySave{
+    path: PathBuf,
+    // What the file holds, to skip writes when nothing changed
+    saved: Vec<u8>,
+    interval: u64,
+    frames_since: u64
+}
+
+// game.nes -> game.sav, in save_dir if there is one
+pub fn save_path(rom: &Path, save_dir: Option<&Path>) -> PathBuf{
+    let file = rom.with_extension("sav");
+    match (save_dir, file.file_name()){
+        (Some(dir), Some(name)) => return dir.join(name),
+        _ => return file
+    }
+}
+
+// Replaces path's contents without ever leaving it half written
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()>{
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp = path.with_file_name(temp_name);
+
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    if let Err(e) = fs::rename(&temp, path){
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    return Ok(());
+}
+
+impl BatterySave{
+    // None when the cartridge has no battery. An existing save is loaded
+    // into the cartridge straight away; a missing one is fine, it will be
+    // created on the first flush.
+    pub fn attach(nes: &mut Nes, path: PathBuf) -> io::Result<Option<Self>>{
+        if nes.bus().cartridge().battery_ram().is_none(){
+            return Ok(None);
+        }
+        match fs::read(&path){
+            Ok(data) => nes.bus_mut().cartridge_mut().load_battery_ram(&data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e)
+        }
+        let saved = nes.bus().cartridge().battery_ram().unwrap_or(&[]).to_vec();
+        return Ok(Some(BatterySave{ path, saved, interval: DEFAULT_FLUSH_INTERVAL, frames_since: 0 }));
+    }
+
+    pub fn path(&self) -> &Path{
+        return &self.path;
+    }
+
+    // Frames between periodic flushes, 0 to only flush when asked
+    pub fn set_interval(&mut self, frames: u64){
+        self.interval = frames;
+    }
+
+    // Call once a frame
+    pub fn frame_done(&mut self, nes: &Nes) -> io::Result<()>{
+        self.frames_since += 1;
+        if self.interval != 0 && self.frames_since >= self.interval{
+            self.flush(nes)?;
+        }
+        return Ok(());
+    }
+
+    // Writes the RAM out if it has changed since the last write, and says
+    // whether it did. Call on exit as well.
+    pub fn flush(&mut self, nes: &Nes) -> io::Result<bool>{
+        self.frames_since = 0;
+        let ram = match nes.bus().cartridge().battery_ram(){
+            Some(ram) => ram,
+            None => return Ok(false)
+        };
+        if ram == self.saved.as_slice() && self.path.exists(){
+            return Ok(false);
+        }
+        write_atomic(&self.path, ram)?;
+        self.saved = ram.to_vec();
+        return Ok(true);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::cartridge::{nrom_cart, Cartridge};
+
+    // A scratch directory of the test's own
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("melones-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    // Writes a counter to $6000 every frame
+    fn cart(battery: bool) -> Cartridge{
+        // LDA #$80, STA $2000, loop: JMP loop, nmi: INC $6000, RTI
+        let program = [0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0xC0, 0xEE, 0x00, 0x60, 0x40];
+        let flags6 = if battery{ 0x02 }else{ 0x00 };
+        return nrom_cart(&program, [0xC008, 0xC000, 0xC000], flags6);
+    }
+
+    #[test]
+    fn save_path_follows_the_rom(){
+        assert_eq!(save_path(Path::new("roms/zelda.nes"), None), PathBuf::from("roms/zelda.sav"));
+        assert_eq!(save_path(Path::new("roms/zelda.nes"), Some(Path::new("saves"))), PathBuf::from("saves/zelda.sav"));
+    }
+
+    #[test]
+    fn ram_survives_a_restart(){
+        let dir = temp_dir("battery");
+        let path = dir.join("game.sav");
+
+        let mut nes = Nes::new(cart(true));
+        let mut save = BatterySave::attach(&mut nes, path.clone()).unwrap().unwrap();
+        save.set_interval(4);
+        for _ in 0..3{
+            nes.run_frame();
+            save.frame_done(&nes).unwrap();
+        }
+        assert!(!path.exists());
+        nes.run_frame();
+        save.frame_done(&nes).unwrap();
+        assert!(path.exists());
+        nes.run_frame();
+        // On exit
+        assert!(save.flush(&nes).unwrap());
+        assert!(!save.flush(&nes).unwrap());
+        let count = nes.bus().cpu_peek(0x6000);
+        assert_eq!(fs::read(&path).unwrap()[0], count);
+        assert!(!dir.join("game.sav.tmp").exists());
+
+        let mut next = Nes::new(cart(true));
+        BatterySave::attach(&mut next, path.clone()).unwrap().unwrap();
+        assert_eq!(next.bus().cpu_peek(0x6000), count);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn carts_without_a_battery_are_left_alone(){
+        let dir = temp_dir("no-battery");
+        let path = dir.join("game.sav");
+        fs::write(&path, [0x55; 0x2000]).unwrap();
+        let mut nes = Nes::new(cart(false));
+        assert!(BatterySave::attach(&mut nes, path).unwrap().is_none());
+        assert_eq!(nes.bus().cpu_peek(0x6000), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
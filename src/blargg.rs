@@ -0,0 +1,168 @@
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::nes::Nes;
+
+// Runs test ROMs that report through PRG RAM, the way blargg's do:
+//
+//   $6000        $80 while running, $81 when the ROM wants the reset button
+//                pressed, anything else is the result, $00 for a pass
+//   $6001-$6003  DE B0 61 once $6000 means any of that
+//   $6004...     what the ROM printed, zero terminated
+
+pub const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+pub const STATUS_RUNNING: u8 = 0x80;
+pub const STATUS_RESET: u8 = 0x81;
+
+// The ROMs want reset held off for at least 100ms
+const RESET_DELAY_FRAMES: u64 = 10;
+// Longest message that fits in the 8KB window
+const TEXT_LIMIT: u16 = 0x1FFC;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome{
+    Passed(String),
+    Failed{ code: u8, text: String },
+    // Never finished; the text is whatever was printed by then
+    TimedOut(String)
+}
+
+impl Outcome{
+    pub fn passed(&self) -> bool{
+        return matches!(self, Outcome::Passed(_));
+    }
+
+    pub fn text(&self) -> &str{
+        match self{
+            Outcome::Passed(text) | Outcome::Failed{ text, .. } | Outcome::TimedOut(text) => return text
+        }
+    }
+}
+
+// The status byte, once the signature says it is one
+pub fn status(nes: &Nes) -> Option<u8>{
+    let bus = nes.bus();
+    if (0..3).any(|i| bus.cpu_peek(0x6001 + i) != SIGNATURE[i as usize]){
+        return None;
+    }
+    return Some(bus.cpu_peek(0x6000));
+}
+
+// The message at $6004, without trailing blank lines
+pub fn text(nes: &Nes) -> String{
+    let bytes: Vec<u8> = (0..TEXT_LIMIT)
+        .map(|i| nes.bus().cpu_peek(0x6004 + i))
+        .take_while(|&byte| byte != 0)
+        .collect();
+    return String::from_utf8_lossy(&bytes).trim_end().to_string();
+}
+
+// Runs until the ROM reports a result, pressing reset whenever it asks
+pub fn run(nes: &mut Nes, timeout_frames: u64) -> Outcome{
+    let mut reset_asked: Option<u64> = None;
+    for frame in 0..timeout_frames{
+        nes.run_frame();
+        match status(nes){
+            None | Some(STATUS_RUNNING) => reset_asked = None,
+            Some(STATUS_RESET) => {
+                // $6000 still reads $81 for a while after the press, so
+                // only press once per request
+                match reset_asked{
+                    None => reset_asked = Some(frame),
+                    Some(asked) if frame - asked == RESET_DELAY_FRAMES => nes.reset(),
+                    Some(_) => {}
+                }
+            }
+            Some(0) => return Outcome::Passed(text(nes)),
+            Some(code) => return Outcome::Failed{ code, text: text(nes) }
+        }
+    }
+    return Outcome::TimedOut(text(nes));
+}
+
+pub fn run_rom<P: AsRef<Path>>(path: P, timeout_frames: u64) -> Result<Outcome, CartridgeError>{
+    let mut nes = Nes::new(Cartridge::from_file(path)?);
+    return Ok(run(&mut nes, timeout_frames));
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::assembler;
+    use crate::cartridge::nrom_cart;
+
+    // Signs $6001-$6003, then does whatever body says
+    fn test_cart(body: &str) -> Cartridge{
+        let source = format!("
+            reset:  LDA #$DE
+                    STA $6001
+                    LDA #$B0
+                    STA $6002
+                    LDA #$61
+                    STA $6003
+            {}
+            print:  LDX #0
+            copy:   LDA msg,X
+                    STA $6004,X
+                    BEQ done
+                    INX
+                    BNE copy
+            done:   RTS
+            msg:    .byte \"All 3 tests\", 10, 10, 0
+        ", body);
+        let program = assembler::assemble(&source, 0xC000).unwrap();
+        return nrom_cart(&program.bytes(), [0xC000; 3], 0);
+    }
+
+    #[test]
+    fn passes_after_the_reset_it_asks_for(){
+        // CPU RAM survives the reset, so $0300 counts them
+        let mut nes = Nes::new(test_cart("
+                    LDA $0300
+                    BNE again
+                    INC $0300
+                    LDA #$81
+                    STA $6000
+            wait:   JMP wait
+            again:  JSR print
+                    LDA #$00
+                    STA $6000
+            hang:   JMP hang
+        "));
+        assert_eq!(run(&mut nes, 100), Outcome::Passed(String::from("All 3 tests")));
+        assert_eq!(nes.bus().cpu_peek(0x0300), 1);
+    }
+
+    #[test]
+    fn reports_the_result_code(){
+        let mut nes = Nes::new(test_cart("
+                    LDA #$80
+                    STA $6000
+                    JSR print
+                    LDA #$03
+                    STA $6000
+            hang:   JMP hang
+        "));
+        let outcome = run(&mut nes, 100);
+        assert!(!outcome.passed());
+        assert_eq!(outcome, Outcome::Failed{ code: 3, text: String::from("All 3 tests") });
+    }
+
+    #[test]
+    fn gives_up_on_a_rom_that_never_finishes(){
+        let mut nes = Nes::new(test_cart("
+                    LDA #$80
+                    STA $6000
+                    JSR print
+            hang:   JMP hang
+        "));
+        assert_eq!(run(&mut nes, 20), Outcome::TimedOut(String::from("All 3 tests")));
+        assert_eq!(status(&nes), Some(STATUS_RUNNING));
+
+        // Without the signature $6000 is just memory
+        nes.bus_mut().cpu_write(0x6001, 0);
+        assert_eq!(status(&nes), None);
+    }
+}
@@ -1,30 +1,426 @@
+#![allow(dead_code)]
+
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::controller::Controller;
+use crate::cpu::AccessKind;
+use crate::memory::Memory;
+use crate::ppu::Ppu;
+use crate::region::Region;
+use crate::savestate::{StateError, StateFile, StateWriter};
+
+// Sources that can hold the CPU's IRQ line low, it stays asserted for as
+// long as any of them does
+pub const IRQ_FRAME_COUNTER: u8 = 1 << 0;
+pub const IRQ_DMC: u8 = 1 << 1;
+pub const IRQ_MAPPER: u8 = 1 << 2;
+
+// The 2A03's DMA unit. While it runs the CPU is halted and the DMA owns the
+// bus, reading on get (even) cycles and writing on put (odd) cycles.
+#[derive(Default)]
+struct Dma{
+    // Page being copied to OAM, and how far along it is
+    oam_page: Option<u8>,
+    oam_index: u16,
+    oam_latch: Option<u8>,
+    // A DMC sample fetch is waiting for a get cycle
+    dmc_pending: bool,
+    dmc_dummy_done: bool,
+    // The CPU has noticed the halt and stopped
+    halted: bool
+}
+
 pub struct Bus{
     cpu_ram: [u8; 2048],
-    system_clock_counter: u32
+    cart: Cartridge,
+    ppu: Ppu,
+    apu: Apu,
+    // Nothing plugged in reads as 0s
+    ports: [Option<Controller>; 2],
+    // Last value driven onto the CPU data bus, what unmapped reads see
+    open_bus: u8,
+    system_clock_counter: u64, // CPU cycles since power on
+    dma: Dma
 }
 
 impl Bus{
     pub fn new() -> Self{
         let b = Bus{
             cpu_ram: [0; 2048],
-            system_clock_counter: 0
+            cart: Cartridge::empty(),
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            ports: [Some(Controller::new()), Some(Controller::new())],
+            open_bus: 0,
+            system_clock_counter: 0,
+            dma: Dma::default()
         };
         return b;
     }
-    
+
+    pub fn insert_cartridge(&mut self, cart: Cartridge){
+        self.cart = cart;
+    }
+
+    pub fn cartridge(&self) -> &Cartridge{
+        return &self.cart;
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge{
+        return &mut self.cart;
+    }
+
+    pub fn ppu(&self) -> &Ppu{
+        return &self.ppu;
+    }
+
+    pub fn apu(&self) -> &Apu{
+        return &self.apu;
+    }
+
+    // port is 0 for $4016, 1 for $4017
+    pub fn plug_controller(&mut self, port: usize, controller: Option<Controller>){
+        self.ports[port] = controller;
+    }
+
+    pub fn controller_mut(&mut self, port: usize) -> Option<&mut Controller>{
+        return self.ports[port].as_mut();
+    }
+
+    // Buttons held on a port, 0 with nothing plugged in
+    pub fn buttons(&self, port: usize) -> u8{
+        return self.ports[port].as_ref().map_or(0, |controller| controller.buttons());
+    }
+
+    pub fn cycles(&self) -> u64{
+        return self.system_clock_counter;
+    }
+
+    pub fn set_region(&mut self, region: Region){
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    pub fn reset(&mut self){
+        self.cart.reset();
+        self.ppu.reset();
+        self.apu.reset();
+        self.dma = Dma::default();
+    }
+
+    // RAM, DMA and the controllers, then everything plugged into the bus
+    pub fn save_state(&self, state: &mut StateWriter){
+        state.section(*b"BUS ", 1, |w| {
+            w.bytes(&self.cpu_ram);
+            w.u8(self.open_bus);
+            w.u64(self.system_clock_counter);
+            w.option_u8(self.dma.oam_page);
+            w.u16(self.dma.oam_index);
+            w.option_u8(self.dma.oam_latch);
+            w.bool(self.dma.dmc_pending);
+            w.bool(self.dma.dmc_dummy_done);
+            w.bool(self.dma.halted);
+            for port in self.ports.iter(){
+                w.bool(port.is_some());
+                if let Some(controller) = port{
+                    controller.save_state(w);
+                }
+            }
+        });
+        self.cart.save_state(state);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &StateFile) -> Result<(), StateError>{
+        let mut r = state.section(*b"BUS ")?;
+        r.bytes(&mut self.cpu_ram)?;
+        self.open_bus = r.u8()?;
+        self.system_clock_counter = r.u64()?;
+        self.dma.oam_page = r.option_u8()?;
+        self.dma.oam_index = r.u16()?;
+        self.dma.oam_latch = r.option_u8()?;
+        self.dma.dmc_pending = r.bool()?;
+        self.dma.dmc_dummy_done = r.bool()?;
+        self.dma.halted = r.bool()?;
+        for port in self.ports.iter_mut(){
+            if r.bool()?{
+                let mut controller = Controller::new();
+                controller.load_state(&mut r)?;
+                *port = Some(controller);
+            }else{
+                *port = None;
+            }
+        }
+        self.cart.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        return Ok(());
+    }
+
+    // Everything on the CPU side that runs off M2, once per CPU cycle
+    pub fn clock(&mut self){
+        self.apu.clock();
+        self.cart.cpu_clock();
+        self.system_clock_counter += 1;
+
+        if self.apu.dmc_request().is_some() && !self.dma.dmc_pending{
+            self.dma.dmc_pending = true;
+            self.dma.dmc_dummy_done = false;
+        }
+    }
+
+    // True while a DMA is waiting to halt the CPU or running
+    pub fn dma_active(&self) -> bool{
+        return self.dma.oam_page.is_some() || self.dma.dmc_pending;
+    }
+
+    // Whether the DMA takes this cycle from the CPU. The CPU can only be
+    // halted on a read, so a DMA that turns up during writes waits for the
+    // next read; once halted the CPU stays halted until the DMA is done.
+    pub fn dma_takes_cycle(&self, cpu_access: AccessKind) -> bool{
+        return self.dma_active() && (self.dma.halted || cpu_access == AccessKind::Read);
+    }
+
+    // One cycle of DMA, run in place of a CPU cycle. OAM DMA takes 513
+    // cycles, or 514 when it has to wait for a get cycle to line up. A DMC
+    // fetch costs 3-4 cycles on its own, and 2 when it steals a get cycle
+    // from a running OAM DMA.
+    pub fn dma_cycle(&mut self){
+        if !self.dma.halted{
+            self.dma.halted = true;
+            return;
+        }
+
+        let get_cycle = self.system_clock_counter.is_multiple_of(2);
+        let oam_running = self.dma.oam_page.is_some();
+        if get_cycle{
+            if self.dma.dmc_pending && (self.dma.dmc_dummy_done || oam_running){
+                if let Some(addr) = self.apu.dmc_request(){
+                    let data = self.cpu_read(addr);
+                    self.apu.dmc_fill(data);
+                }
+                self.dma.dmc_pending = false;
+            }else if let (Some(page), None) = (self.dma.oam_page, self.dma.oam_latch){
+                let addr = ((page as u16) << 8) | self.dma.oam_index;
+                self.dma.oam_latch = Some(self.cpu_read(addr));
+            }else if self.dma.dmc_pending{
+                self.dma.dmc_dummy_done = true;
+            }
+        }else if let Some(data) = self.dma.oam_latch.take(){
+            self.ppu.cpu_write(&mut self.cart, 0x2004, data);
+            self.dma.oam_index += 1;
+            if self.dma.oam_index == 256{
+                self.dma.oam_page = None;
+                self.dma.oam_index = 0;
+            }
+        }else if self.dma.dmc_pending{
+            self.dma.dmc_dummy_done = true;
+        }
+
+        if !self.dma_active(){
+            self.dma.halted = false;
+        }
+    }
+
+    // One PPU dot
+    pub fn ppu_clock(&mut self){
+        self.ppu.clock(&mut self.cart);
+    }
+
+    // Level of the PPU's /NMI output, true when asserted
+    pub fn nmi(&self) -> bool{
+        return self.ppu.nmi_line();
+    }
+
+    // Everything currently pulling the IRQ line low, as IRQ_* bits
+    pub fn irq_sources(&self) -> u8{
+        let mut sources = 0;
+        if self.apu.frame_irq(){ sources |= IRQ_FRAME_COUNTER; }
+        if self.apu.dmc_irq(){ sources |= IRQ_DMC; }
+        if self.cart.irq(){ sources |= IRQ_MAPPER; }
+        return sources;
+    }
+
+    // Level of the shared IRQ line, true when any source is pulling it low
+    pub fn irq(&self) -> bool{
+        return self.irq_sources() != 0;
+    }
+
     pub fn cpu_write(&mut self, addr: u16, data: u8){
-        if addr >= 0x0000 && addr <= 0x1FFF{
+        self.open_bus = data;
+        if addr <= 0x1FFF{
             self.cpu_ram[(addr & 0x07FF) as usize] = data;
-        }else{
-
+        }else if addr <= 0x3FFF{
+            // Eight registers mirrored every 8 bytes
+            self.ppu.cpu_write(&mut self.cart, addr & 0x2007, data);
+        }else if addr == 0x4014{
+            self.dma.oam_page = Some(data);
+            self.dma.oam_index = 0;
+        }else if addr == 0x4016{
+            // One strobe line runs to both ports
+            for controller in self.ports.iter_mut().flatten(){
+                controller.write_strobe(data);
+            }
+        }else if addr <= 0x4017{
+            self.apu.cpu_write(addr, data);
+        }else if addr >= 0x4020{
+            self.cart.cpu_write(addr, data);
         }
     }
 
-    pub fn cpu_read(&self, addr: u16) -> u8{
-        if addr >= 0x0000 && addr <= 0x1FFF{
+    // cpu_read without side effects, for tracing and debuggers
+    pub fn cpu_peek(&self, addr: u16) -> u8{
+        if addr <= 0x1FFF{
             return self.cpu_ram[(addr & 0x07FF) as usize];
+        }else if addr <= 0x3FFF{
+            return self.ppu.cpu_peek(addr & 0x2007);
+        }else if addr == 0x4015{
+            return (self.apu.peek_status() & !0x20) | (self.open_bus & 0x20);
+        }else if addr == 0x4016 || addr == 0x4017{
+            let bit = match &self.ports[(addr & 0x0001) as usize]{
+                Some(controller) => controller.peek(),
+                None => 0
+            };
+            return (self.open_bus & 0xE0) | bit;
+        }else if addr >= 0x4020{
+            return self.cart.cpu_read(addr).unwrap_or(self.open_bus);
         }else{
-            return 0;
+            return self.open_bus;
         }
     }
-}
\ No newline at end of file
+
+    pub fn cpu_read(&mut self, addr: u16) -> u8{
+        let data = if addr <= 0x1FFF{
+            self.cpu_ram[(addr & 0x07FF) as usize]
+        }else if addr <= 0x3FFF{
+            self.ppu.cpu_read(&mut self.cart, addr & 0x2007)
+        }else if addr == 0x4015{
+            (self.apu.read_status() & !0x20) | (self.open_bus & 0x20)
+        }else if addr == 0x4016 || addr == 0x4017{
+            // Only the low bits are driven, the rest keep whatever was on the
+            // bus, usually the $40 high byte of the address
+            let bit = match &mut self.ports[(addr & 0x0001) as usize]{
+                Some(controller) => controller.read(),
+                None => 0
+            };
+            (self.open_bus & 0xE0) | bit
+        }else if addr >= 0x4020{
+            self.cart.cpu_read(addr).unwrap_or(self.open_bus)
+        }else{
+            self.open_bus
+        };
+        self.open_bus = data;
+        return data;
+    }
+}
+
+impl Memory for Bus{
+    fn read(&mut self, addr: u16) -> u8{
+        return self.cpu_read(addr);
+    }
+
+    fn write(&mut self, addr: u16, data: u8){
+        self.cpu_write(addr, data);
+    }
+
+    fn peek(&self, addr: u16) -> u8{
+        return self.cpu_peek(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // Cycles until the DMA lets go of the CPU
+    fn run_dma(bus: &mut Bus) -> u32{
+        let mut cycles = 0;
+        while bus.dma_active(){
+            bus.dma_cycle();
+            bus.clock();
+            cycles += 1;
+        }
+        return cycles;
+    }
+
+    fn start_dmc(bus: &mut Bus){
+        bus.cpu_write(0x4013, 0x01);
+        bus.cpu_write(0x4015, 0x10);
+    }
+
+    #[test]
+    fn irq_line_is_shared(){
+        let mut bus = Bus::new();
+        assert_eq!(bus.irq_sources(), 0);
+        // DMC IRQ enabled with a one byte sample, then let the frame
+        // counter run a full sequence with its IRQ enabled
+        bus.cpu_write(0x4010, 0x80);
+        bus.cpu_write(0x4013, 0x00);
+        bus.cpu_write(0x4015, 0x10);
+        bus.cpu_write(0x4017, 0x00);
+        for _ in 0..30_000{
+            if bus.dma_active(){
+                bus.dma_cycle();
+            }
+            bus.clock();
+        }
+        assert_eq!(bus.irq_sources(), IRQ_FRAME_COUNTER | IRQ_DMC);
+        // Reading $4015 only acknowledges the frame counter
+        bus.cpu_read(0x4015);
+        assert_eq!(bus.irq_sources(), IRQ_DMC);
+        assert!(bus.irq());
+        bus.cpu_write(0x4015, 0x00);
+        assert!(!bus.irq());
+    }
+
+    #[test]
+    fn oam_dma_copies_a_page(){
+        let mut bus = Bus::new();
+        for i in 0..256{
+            bus.cpu_write(0x0300 + i, i as u8);
+        }
+        bus.cpu_write(0x4014, 0x03);
+        run_dma(&mut bus);
+        assert_eq!(bus.ppu().oam()[0x00], 0x00);
+        assert_eq!(bus.ppu().oam()[0x7F], 0x7F);
+        assert_eq!(bus.ppu().oam()[0xFF], 0xFF);
+    }
+
+    #[test]
+    fn oam_dma_takes_513_or_514_cycles(){
+        let mut bus = Bus::new();
+        bus.cpu_write(0x4014, 0x02);
+        assert_eq!(run_dma(&mut bus), 514);
+        bus.clock();
+        bus.cpu_write(0x4014, 0x02);
+        assert_eq!(run_dma(&mut bus), 513);
+    }
+
+    #[test]
+    fn dmc_fetch_stalls_three_or_four_cycles(){
+        let mut bus = Bus::new();
+        start_dmc(&mut bus);
+        bus.clock();
+        assert_eq!(run_dma(&mut bus), 4);
+
+        let mut bus = Bus::new();
+        bus.clock();
+        start_dmc(&mut bus);
+        bus.clock();
+        assert_eq!(run_dma(&mut bus), 3);
+    }
+
+    #[test]
+    fn dmc_fetch_during_oam_dma_costs_two_cycles(){
+        let mut bus = Bus::new();
+        bus.cpu_write(0x4014, 0x02);
+        for _ in 0..100{
+            bus.dma_cycle();
+            bus.clock();
+        }
+        start_dmc(&mut bus);
+        assert_eq!(run_dma(&mut bus) + 100, 514 + 2);
+        assert!(bus.apu().dmc_request().is_none());
+    }
+}
@@ -0,0 +1,563 @@
+#![allow(dead_code)]
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::mapper::{self, CpuMapped, Mapper};
+use crate::savestate::{self, StateError, StateFile, StateWriter};
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_BANK_SIZE: usize = 16 * 1024;
+const CHR_BANK_SIZE: usize = 8 * 1024;
+// 1GB
+const MAX_ROM_SIZE_EXPONENT: u32 = 30;
+
+// Nametable arrangement wired up by the cartridge
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mirroring{
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen
+}
+
+// Which console the cartridge was made for (NES 2.0 byte 12, iNES byte 9)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Timing{
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HeaderFormat{
+    INes,
+    Nes2
+}
+
+#[derive(Debug)]
+pub enum CartridgeError{
+    Io(io::Error),
+    // File does not start with "NES<EOF>"
+    BadMagic,
+    // File ends before the sizes in the header say it should
+    Truncated{ expected: usize, found: usize },
+    // Header fields that can't describe a real cartridge
+    Malformed(&'static str),
+    UnsupportedMapper(u16)
+}
+
+impl fmt::Display for CartridgeError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            CartridgeError::Io(e) => write!(f, "could not read ROM: {}", e),
+            CartridgeError::BadMagic => write!(f, "not an iNES file (missing NES<EOF> signature)"),
+            CartridgeError::Truncated{ expected, found } => {
+                write!(f, "ROM is truncated: header describes {} bytes but file has {}", expected, found)
+            }
+            CartridgeError::Malformed(what) => write!(f, "malformed iNES header: {}", what),
+            CartridgeError::UnsupportedMapper(n) => write!(f, "mapper {} is not supported", n)
+        }
+    }
+}
+
+impl Error for CartridgeError{
+    fn source(&self) -> Option<&(dyn Error + 'static)>{
+        match self{
+            CartridgeError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError{
+    fn from(e: io::Error) -> Self{
+        CartridgeError::Io(e)
+    }
+}
+
+// Everything the 16 byte header tells us. Sizes are in bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header{
+    pub format: HeaderFormat,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing
+}
+
+impl Header{
+    pub fn parse(data: &[u8]) -> Result<Self, CartridgeError>{
+        if data.len() < 4 || &data[0..4] != b"NES\x1A"{
+            return Err(CartridgeError::BadMagic);
+        }
+        if data.len() < HEADER_SIZE{
+            return Err(CartridgeError::Truncated{ expected: HEADER_SIZE, found: data.len() });
+        }
+
+        let flags6 = data[6];
+        let flags7 = data[7];
+
+        let mirroring = if flags6 & 0x08 != 0{
+            Mirroring::FourScreen
+        }else if flags6 & 0x01 != 0{
+            Mirroring::Vertical
+        }else{
+            Mirroring::Horizontal
+        };
+        let battery = flags6 & 0x02 != 0;
+        let trainer = flags6 & 0x04 != 0;
+
+        if flags7 & 0x0C == 0x08{
+            // NES 2.0
+            let mapper = ((data[8] as u16 & 0x0F) << 8) | (flags7 as u16 & 0xF0) | (flags6 as u16 >> 4);
+            let submapper = data[8] >> 4;
+
+            let prg_rom_size = Header::nes2_rom_size(data[4], data[9] & 0x0F, PRG_BANK_SIZE)?;
+            let chr_rom_size = Header::nes2_rom_size(data[5], data[9] >> 4, CHR_BANK_SIZE)?;
+
+            let timing = match data[12] & 0x03{
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy
+            };
+
+            return Ok(Header{
+                format: HeaderFormat::Nes2,
+                prg_rom_size,
+                chr_rom_size,
+                mapper,
+                submapper,
+                mirroring,
+                battery,
+                trainer,
+                prg_ram_size: Header::nes2_ram_size(data[10] & 0x0F)?,
+                prg_nvram_size: Header::nes2_ram_size(data[10] >> 4)?,
+                chr_ram_size: Header::nes2_ram_size(data[11] & 0x0F)?,
+                chr_nvram_size: Header::nes2_ram_size(data[11] >> 4)?,
+                timing
+            });
+        }
+
+        // Plain iNES. Old dumping tools left text like "DiskDude!" in bytes
+        // 7-15, in which case only the low mapper nibble can be trusted.
+        let dirty = data[12..16].iter().any(|&b| b != 0);
+        let mapper = if dirty{
+            flags6 as u16 >> 4
+        }else{
+            (flags7 as u16 & 0xF0) | (flags6 as u16 >> 4)
+        };
+
+        let chr_rom_size = data[5] as usize * CHR_BANK_SIZE;
+        // A zero here means 8KB for compatibility
+        let prg_ram_size = if dirty || data[8] == 0{ 8 * 1024 }else{ data[8] as usize * 8 * 1024 };
+        let timing = if !dirty && data[9] & 0x01 != 0{ Timing::Pal }else{ Timing::Ntsc };
+
+        return Ok(Header{
+            format: HeaderFormat::INes,
+            prg_rom_size: data[4] as usize * PRG_BANK_SIZE,
+            chr_rom_size,
+            mapper,
+            submapper: 0,
+            mirroring,
+            battery,
+            trainer,
+            prg_ram_size: if battery{ 0 }else{ prg_ram_size },
+            prg_nvram_size: if battery{ prg_ram_size }else{ 0 },
+            chr_ram_size: if chr_rom_size == 0{ CHR_BANK_SIZE }else{ 0 },
+            chr_nvram_size: 0,
+            timing
+        });
+    }
+
+    // ROM size from the LSB byte and the MSB nibble in byte 9. An MSB of $F
+    // switches to exponent-multiplier notation: 2^E * (MM * 2 + 1).
+    fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Result<usize, CartridgeError>{
+        if msb == 0x0F{
+            let exponent = (lsb >> 2) as u32;
+            // Far past any real board, and keeps the size inside a usize
+            if exponent > MAX_ROM_SIZE_EXPONENT{
+                return Err(CartridgeError::Malformed("ROM size exponent out of range"));
+            }
+            let multiplier = (lsb & 0x03) as usize * 2 + 1;
+            return 1usize.checked_shl(exponent)
+                .and_then(|size| size.checked_mul(multiplier))
+                .ok_or(CartridgeError::Malformed("ROM size exponent out of range"));
+        }
+        return Ok((((msb as usize) << 8) | lsb as usize) * unit);
+    }
+
+    // RAM sizes are stored as a shift count: 64 << n bytes, or none for 0
+    fn nes2_ram_size(shift: u8) -> Result<usize, CartridgeError>{
+        if shift == 0{
+            return Ok(0);
+        }
+        if shift > 14{
+            return Err(CartridgeError::Malformed("RAM size shift out of range"));
+        }
+        return Ok(64 << shift);
+    }
+}
+
+pub struct Cartridge{
+    header: Header,
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,   // CHR ROM, or CHR RAM when the header has no CHR ROM
+    prg_ram: Vec<u8>, // Volatile and battery-backed PRG RAM share the $6000 window
+    trainer: Option<Vec<u8>>,
+    mapper: Box<dyn Mapper>
+}
+
+impl Cartridge{
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError>{
+        let data = fs::read(path)?;
+        return Cartridge::from_bytes(&data);
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CartridgeError>{
+        let header = Header::parse(data)?;
+
+        if header.prg_rom_size == 0{
+            return Err(CartridgeError::Malformed("no PRG ROM"));
+        }
+
+        let mapper = mapper::new_mapper(&header)?;
+
+        let trainer_size = if header.trainer{ TRAINER_SIZE }else{ 0 };
+        let expected = (HEADER_SIZE + trainer_size).checked_add(header.prg_rom_size)
+            .and_then(|size| size.checked_add(header.chr_rom_size))
+            .ok_or(CartridgeError::Malformed("ROM sizes add up past the address space"))?;
+        if data.len() < expected{
+            return Err(CartridgeError::Truncated{ expected, found: data.len() });
+        }
+
+        let mut offset = HEADER_SIZE;
+        let trainer = if header.trainer{
+            offset += TRAINER_SIZE;
+            Some(data[HEADER_SIZE..offset].to_vec())
+        }else{
+            None
+        };
+
+        let prg_rom = data[offset..offset + header.prg_rom_size].to_vec();
+        offset += header.prg_rom_size;
+
+        let chr = if header.chr_rom_size > 0{
+            data[offset..offset + header.chr_rom_size].to_vec()
+        }else{
+            vec![0; header.chr_ram_size + header.chr_nvram_size]
+        };
+
+        let mut prg_ram = vec![0; header.prg_ram_size + header.prg_nvram_size];
+        if let Some(t) = &trainer{
+            // The trainer lives at $7000-$71FF
+            if prg_ram.len() < 0x2000{
+                prg_ram.resize(0x2000, 0);
+            }
+            prg_ram[0x1000..0x1000 + TRAINER_SIZE].copy_from_slice(t);
+        }
+
+        return Ok(Cartridge{
+            header,
+            prg_rom,
+            chr,
+            prg_ram,
+            trainer,
+            mapper
+        });
+    }
+
+    // What the bus sees with nothing plugged in
+    pub fn empty() -> Self{
+        return Cartridge{
+            header: Header{
+                format: HeaderFormat::INes,
+                prg_rom_size: 0,
+                chr_rom_size: 0,
+                mapper: 0,
+                submapper: 0,
+                mirroring: Mirroring::Horizontal,
+                battery: false,
+                trainer: false,
+                prg_ram_size: 0,
+                prg_nvram_size: 0,
+                chr_ram_size: 0,
+                chr_nvram_size: 0,
+                timing: Timing::Ntsc
+            },
+            prg_rom: Vec::new(),
+            chr: Vec::new(),
+            prg_ram: Vec::new(),
+            trainer: None,
+            mapper: Box::new(mapper::nrom::Nrom::new(0, Mirroring::Horizontal))
+        };
+    }
+
+    pub fn header(&self) -> &Header{
+        return &self.header;
+    }
+
+    pub fn trainer(&self) -> Option<&[u8]>{
+        return self.trainer.as_deref();
+    }
+
+    // CPU side, $4020-$FFFF. None means nothing drove the data bus.
+    pub fn cpu_read(&self, addr: u16) -> Option<u8>{
+        match self.mapper.cpu_map_read(addr){
+            Some(CpuMapped::PrgRom(offset)) if !self.prg_rom.is_empty() => {
+                return Some(self.prg_rom[offset % self.prg_rom.len()]);
+            }
+            Some(CpuMapped::PrgRam(offset)) if !self.prg_ram.is_empty() => {
+                return Some(self.prg_ram[offset % self.prg_ram.len()]);
+            }
+            _ => return None
+        }
+    }
+
+    // Mapper registers always see the write. Returns false when no memory took it.
+    pub fn cpu_write(&mut self, addr: u16, data: u8) -> bool{
+        match self.mapper.cpu_map_write(addr, data){
+            Some(CpuMapped::PrgRam(offset)) if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[offset % len] = data;
+                return true;
+            }
+            _ => return false
+        }
+    }
+
+    // PPU side, pattern tables at $0000-$1FFF
+    pub fn ppu_read(&self, addr: u16) -> Option<u8>{
+        if addr <= 0x1FFF && !self.chr.is_empty(){
+            let offset = self.mapper.ppu_map_read(addr);
+            return Some(self.chr[offset % self.chr.len()]);
+        }
+        return None;
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8) -> bool{
+        if addr <= 0x1FFF && self.header.chr_rom_size == 0 && !self.chr.is_empty(){
+            let offset = self.mapper.ppu_map_write(addr);
+            let len = self.chr.len();
+            self.chr[offset % len] = data;
+            return true;
+        }
+        return false;
+    }
+
+    // Lets the mapper watch the PPU address bus
+    pub fn ppu_address(&mut self, addr: u16){
+        self.mapper.ppu_address(addr);
+    }
+
+    pub fn mirroring(&self) -> Mirroring{
+        return self.mapper.mirroring();
+    }
+
+    // Level of the cartridge IRQ line
+    pub fn irq(&self) -> bool{
+        return self.mapper.irq();
+    }
+
+    pub fn cpu_clock(&mut self){
+        self.mapper.cpu_clock();
+    }
+
+    pub fn reset(&mut self){
+        self.mapper.reset();
+    }
+
+    // PRG RAM kept alive by a battery, None on carts without one
+    pub fn battery_ram(&self) -> Option<&[u8]>{
+        if self.header.battery && !self.prg_ram.is_empty(){
+            return Some(&self.prg_ram);
+        }
+        return None;
+    }
+
+    // Puts back battery RAM from an earlier session. A file of the wrong
+    // size loads as much as fits.
+    pub fn load_battery_ram(&mut self, data: &[u8]){
+        if !self.header.battery{
+            return;
+        }
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    // Identifies the game a save state was made with
+    pub fn rom_hash(&self) -> u64{
+        if self.header.chr_rom_size > 0{
+            return savestate::hash(&[&self.prg_rom, &self.chr]);
+        }
+        return savestate::hash(&[&self.prg_rom]);
+    }
+
+    // The RAMs, then the mapper's registers in a section of their own
+    pub fn save_state(&self, state: &mut StateWriter){
+        state.section(*b"CART", 1, |w| {
+            w.blob(&self.prg_ram);
+            if self.header.chr_rom_size == 0{
+                w.blob(&self.chr);
+            }else{
+                w.blob(&[]);
+            }
+        });
+        state.section(*b"MAPR", 1, |w| self.mapper.save_state(w));
+    }
+
+    pub fn load_state(&mut self, state: &StateFile) -> Result<(), StateError>{
+        let mut r = state.section(*b"CART")?;
+        r.blob_into(&mut self.prg_ram)?;
+        if self.header.chr_rom_size == 0{
+            r.blob_into(&mut self.chr)?;
+        }else{
+            r.blob_into(&mut [])?;
+        }
+        return self.mapper.load_state(&mut state.section(*b"MAPR")?);
+    }
+}
+
+// NROM-128 with code at $C000 (mirrored at $8000), the rest NOPs, CHR RAM,
+// and the NMI, reset and IRQ vectors as given. flags6 carries the
+// mirroring and battery bits.
+#[cfg(test)]
+pub(crate) fn nrom_cart(code: &[u8], vectors: [u16; 3], flags6: u8) -> Cartridge{
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0xEA; PRG_BANK_SIZE];
+    prg[..code.len()].copy_from_slice(code);
+    for (i, vector) in vectors.iter().enumerate(){
+        prg[0x3FFA + i * 2] = *vector as u8;
+        prg[0x3FFB + i * 2] = (*vector >> 8) as u8;
+    }
+    rom.extend(prg);
+    return Cartridge::from_bytes(&rom).unwrap();
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // Header bytes 4-15 as given, followed by a trainer when flags 6 asks
+    // for one and then the PRG and CHR the header describes, PRG filled
+    // with its bank number
+    fn rom(header: [u8; 12]) -> Vec<u8>{
+        let mut data = b"NES\x1A".to_vec();
+        data.extend_from_slice(&header);
+        let parsed = Header::parse(&data).unwrap();
+        if parsed.trainer{
+            data.extend(vec![0x77; TRAINER_SIZE]);
+        }
+        for i in 0..parsed.prg_rom_size{
+            data.push((i / PRG_BANK_SIZE) as u8 + 1);
+        }
+        data.extend(vec![0xCC; parsed.chr_rom_size]);
+        return data;
+    }
+
+    #[test]
+    fn ines_header(){
+        // Mapper $21, vertical, battery, 2x16KB PRG, 1x8KB CHR
+        let header = Header::parse(&rom([2, 1, 0x13, 0x20, 0, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!((header.prg_rom_size, header.chr_rom_size), (0x8000, 0x2000));
+        assert_eq!(header.mapper, 0x21);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert!(header.battery && !header.trainer);
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size, header.chr_ram_size), (0, 0x2000, 0));
+
+        let header = Header::parse(&rom([1, 1, 0x08, 0, 0, 1, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert_eq!(header.mirroring, Mirroring::FourScreen);
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (0x2000, 0));
+        assert_eq!(header.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn dirty_ines_header_keeps_the_low_mapper_nibble(){
+        let mut data = rom([1, 1, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data[7..16].copy_from_slice(b"DiskDude!");
+        let header = Header::parse(&data).unwrap();
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.mapper, 4);
+    }
+
+    #[test]
+    fn nes2_header(){
+        // Mapper $234 submapper 1, 8KB PRG NVRAM, 8KB CHR RAM, Dendy
+        let header = Header::parse(&rom([1, 0, 0x42, 0x38, 0x12, 0, 0x70, 0x07, 3, 0, 0, 0])).unwrap();
+        assert_eq!(header.format, HeaderFormat::Nes2);
+        assert_eq!(header.mapper, 0x234);
+        assert_eq!(header.submapper, 1);
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (0, 0x2000));
+        assert_eq!((header.chr_rom_size, header.chr_ram_size), (0, 0x2000));
+        assert_eq!(header.timing, Timing::Dendy);
+
+        // Exponent-multiplier PRG size: 2^14 * 3
+        let header = Header::parse(&rom([(14 << 2) | 1, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert_eq!(header.prg_rom_size, 3 << 14);
+    }
+
+    #[test]
+    fn oversized_nes2_sizes_are_errors(){
+        let mut data = b"NES\x1A".to_vec();
+        data.extend_from_slice(&[0xFF, 0xFF, 0, 0x08, 0, 0xFF, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(Header::parse(&data), Err(CartridgeError::Malformed(_))));
+        assert!(matches!(Cartridge::from_bytes(&data), Err(CartridgeError::Malformed(_))));
+        data[4] = 31 << 2;
+        data[9] = 0x0F;
+        assert!(matches!(Cartridge::from_bytes(&data), Err(CartridgeError::Malformed(_))));
+    }
+
+    #[test]
+    fn trainer_sits_before_prg_and_loads_at_7000(){
+        let cart = Cartridge::from_bytes(&rom([1, 1, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert_eq!(cart.trainer(), Some(&[0x77; TRAINER_SIZE][..]));
+        assert_eq!(cart.cpu_read(0x7000), Some(0x77));
+        assert_eq!(cart.cpu_read(0x71FF), Some(0x77));
+        assert_eq!(cart.cpu_read(0x7200), Some(0x00));
+        assert_eq!(cart.cpu_read(0x8000), Some(1));
+    }
+
+    #[test]
+    fn no_chr_rom_means_chr_ram(){
+        let mut cart = Cartridge::from_bytes(&rom([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert_eq!(cart.header().chr_ram_size, CHR_BANK_SIZE);
+        assert!(cart.ppu_write(0x1234, 0x5A));
+        assert_eq!(cart.ppu_read(0x1234), Some(0x5A));
+
+        // CHR ROM ignores writes
+        let mut cart = Cartridge::from_bytes(&rom([1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        cart.ppu_write(0x1234, 0x5A);
+        assert_eq!(cart.ppu_read(0x1234), Some(0xCC));
+    }
+
+    #[test]
+    fn bad_files(){
+        assert!(matches!(Cartridge::from_bytes(b"NES"), Err(CartridgeError::BadMagic)));
+        assert!(matches!(Cartridge::from_bytes(b"UNIF\0\0\0\0"), Err(CartridgeError::BadMagic)));
+        assert!(matches!(Header::parse(b"NES\x1A\x01"), Err(CartridgeError::Truncated{ expected: 16, found: 5 })));
+
+        let data = rom([2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        match Cartridge::from_bytes(&data[..data.len() - 1]){
+            Err(CartridgeError::Truncated{ expected, found }) => assert_eq!((expected, found), (data.len(), data.len() - 1)),
+            _ => panic!("short file accepted")
+        }
+        assert!(matches!(Cartridge::from_bytes(&rom([0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])), Err(CartridgeError::Malformed(_))));
+        assert!(matches!(Cartridge::from_bytes(&rom([1, 1, 0xF0, 0xF0, 0, 0, 0, 0, 0, 0, 0, 0])), Err(CartridgeError::UnsupportedMapper(0xFF))));
+    }
+}
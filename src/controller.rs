@@ -0,0 +1,116 @@
+#![allow(dead_code)]
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Button bits, in the order the shift register reports them
+pub const BUTTON_A: u8 = 1 << 0;
+pub const BUTTON_B: u8 = 1 << 1;
+pub const BUTTON_SELECT: u8 = 1 << 2;
+pub const BUTTON_START: u8 = 1 << 3;
+pub const BUTTON_UP: u8 = 1 << 4;
+pub const BUTTON_DOWN: u8 = 1 << 5;
+pub const BUTTON_LEFT: u8 = 1 << 6;
+pub const BUTTON_RIGHT: u8 = 1 << 7;
+
+// Standard joypad: a 4021 shift register that is parallel loaded with the
+// buttons while the strobe line ($4016 bit 0) is high.
+#[derive(Default)]
+pub struct Controller{
+    buttons: u8,
+    shift: u8,
+    strobe: bool
+}
+
+impl Controller{
+    pub fn new() -> Self{
+        return Controller::default();
+    }
+
+    // Takes effect at the next strobe, like a real pad
+    pub fn set_buttons(&mut self, buttons: u8){
+        self.buttons = buttons;
+        if self.strobe{
+            self.shift = buttons;
+        }
+    }
+
+    pub fn buttons(&self) -> u8{
+        return self.buttons;
+    }
+
+    // $4016 writes, shared by both ports
+    pub fn write_strobe(&mut self, data: u8){
+        self.strobe = data & 0x01 != 0;
+        if self.strobe{
+            self.shift = self.buttons;
+        }
+    }
+
+    // Bit 0 of a $4016/$4017 read
+    pub fn read(&mut self) -> u8{
+        if self.strobe{
+            // Held in load mode the register keeps reporting A
+            return self.buttons & 0x01;
+        }
+        let bit = self.shift & 0x01;
+        // The serial input is tied high, so 1s come out after all 8 buttons
+        self.shift = (self.shift >> 1) | 0x80;
+        return bit;
+    }
+
+    pub fn peek(&self) -> u8{
+        if self.strobe{
+            return self.buttons & 0x01;
+        }
+        return self.shift & 0x01;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter){
+        w.u8(self.buttons);
+        w.u8(self.shift);
+        w.bool(self.strobe);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.buttons = r.u8()?;
+        self.shift = r.u8()?;
+        self.strobe = r.bool()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn reads_buttons_in_order_then_ones(){
+        let mut pad = Controller::new();
+        pad.set_buttons(BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+        pad.write_strobe(1);
+        pad.write_strobe(0);
+        let bits: Vec<u8> = (0..10).map(|_| pad.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn strobe_high_keeps_returning_a(){
+        let mut pad = Controller::new();
+        pad.set_buttons(BUTTON_A | BUTTON_B);
+        pad.write_strobe(1);
+        for _ in 0..4{
+            assert_eq!(pad.read(), 1);
+        }
+        pad.set_buttons(BUTTON_B);
+        assert_eq!(pad.read(), 0);
+    }
+
+    #[test]
+    fn buttons_latch_on_strobe(){
+        let mut pad = Controller::new();
+        pad.write_strobe(1);
+        pad.write_strobe(0);
+        pad.set_buttons(BUTTON_A);
+        assert_eq!(pad.read(), 0);
+    }
+}
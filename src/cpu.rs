@@ -1,10 +1,11 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 #![allow(non_camel_case_types)]
+#![allow(clippy::upper_case_acronyms)]
+#![allow(clippy::identity_op)]
 
-use std::convert::TryInto;
-
-use crate::bus;
+use crate::memory::Memory;
+use crate::savestate::{StateError, StateFile, StateWriter};
 
 // This is copied from FCEU.
 static CYCLE_TABLE: [u8; 256] = [
@@ -26,7 +27,7 @@ static CYCLE_TABLE: [u8; 256] = [
     /*0xF0*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7
 ];
 
-pub struct CPU_6502{
+pub struct CPU_6502<M: Memory>{
     accum: u8, // Accumulator register
     x: u8,     // X register
     y: u8,     // Y register
@@ -39,8 +40,25 @@ pub struct CPU_6502{
     addr_rel: u16,
     opcode: u8, // Instruction byte
     cycles: u8, // cycles remaining
-    clock_count: u32, // accumulation of the number of clocks
-    bus: bus::Bus
+    clock_count: u64, // accumulation of the number of clocks
+    halted: bool, // set by KIL, only a reset gets the CPU going again
+    bus: M,
+
+    // Cycle-stepped execution
+    cycle_stepped: bool,
+    step: u8,          // cycle of the current instruction, 0 when the next clock fetches
+    base: u16,         // indexed address before the page fix
+    prefetched: bool,  // fetch() hands back fetched instead of reading again
+
+    // Interrupt lines and what was sampled from them
+    irq_line: bool,
+    nmi_line: bool,
+    nmi_line_previous: bool,
+    need_nmi: bool,      // an NMI edge has been seen and not yet serviced
+    prev_need_nmi: bool, // need_nmi as of the end of the cycle before
+    run_irq: bool,       // IRQ asserted and not masked
+    prev_run_irq: bool,
+    interrupting: bool   // running an IRQ/NMI sequence rather than an opcode
 }
 
 fn FLAGS_6502(c: char) -> u8{
@@ -53,12 +71,12 @@ fn FLAGS_6502(c: char) -> u8{
 	    'U' => return 1 << 5,	// Unused
 	    'V' => return 1 << 6,	// Overflow
         'N' => return 1 << 7,	// Negative
-         _  => {println!("{}", 0); return 0;}
+         _  => unreachable!("no flag {:?}", c)
     }
 }
 
-impl CPU_6502{
-    pub fn new(xBus: bus::Bus) -> Self{
+impl<M: Memory> CPU_6502<M>{
+    pub fn new(xBus: M) -> Self{
         let cpu = CPU_6502{
             accum: 0,
             x: 0,
@@ -73,17 +91,30 @@ impl CPU_6502{
             opcode: 0x00,
             cycles: 0,
             clock_count: 0,
-            bus: xBus
+            halted: false,
+            bus: xBus,
+            cycle_stepped: false,
+            step: 0,
+            base: 0x0000,
+            prefetched: false,
+            irq_line: false,
+            nmi_line: false,
+            nmi_line_previous: false,
+            need_nmi: false,
+            prev_need_nmi: false,
+            run_irq: false,
+            prev_run_irq: false,
+            interrupting: false
         };
         return cpu; 
     }
 
     // Read and write a byte to a specific memory address
-    fn read_this(&self, a: u16) -> u8{
-        return self.bus.cpu_read(a);
+    fn read_this(&mut self, a: u16) -> u8{
+        return self.bus.read(a);
     }
     fn write_this(&mut self, a: u16, d: u8){
-        self.bus.cpu_write(a, d);
+        self.bus.write(a, d);
     }
 
     // Gets and sets flags for convienance
@@ -116,148 +147,248 @@ impl CPU_6502{
         self.x = 0;
         self.y = 0;
         self.stkp = 0xFD;
-        self.status = 0x00 | FLAGS_6502('U');
+        self.status = FLAGS_6502('U') | FLAGS_6502('I');
 
         // Clear helpers
         self.addr_rel = 0x0000;
         self.addr_abs = 0x0000;
         self.fetched = 0x00;
 
-        self.cycles = 8;
+        self.halted = false;
+        self.step = 0;
+        self.prefetched = false;
+        self.need_nmi = false;
+        self.prev_need_nmi = false;
+        self.run_irq = false;
+        self.prev_run_irq = false;
+        self.nmi_line_previous = self.nmi_line;
+        self.interrupting = false;
+        self.cycles = 7;
     }
 
-    // Interrupt Request - can be ignored
-    pub fn irq(&mut self){
-        if self.get_flag('I') == 0{
-            // Push program counter to the stack
-            self.write_this(0x0100 + self.stkp as u16, ((self.pc >> 8) & 0x00FF).try_into().unwrap());
-            self.stkp -= 1;
-            self.write_this(0x0100 + self.stkp as u16, (self.pc & 0x00FF).try_into().unwrap());
-            self.stkp -= 1;
+    // True between instructions, when the next clock fetches an opcode
+    pub fn complete(&self) -> bool{
+        return self.cycles == 0 && self.step == 0;
+    }
 
-            // Push status register to the stack
-            self.set_flag('B', false);
-            self.set_flag('U', true);
-            self.set_flag('I', true);
-            self.write_this(0x0100 + self.stkp as u16, self.status);
-            self.stkp -= 1;
+    // Off by default: whole instructions run on their first cycle. On,
+    // every cycle makes its own bus access. Only switch between
+    // instructions.
+    pub fn set_cycle_stepped(&mut self, cycle_stepped: bool){
+        self.cycle_stepped = cycle_stepped;
+    }
+
+    pub fn cycle_stepped(&self) -> bool{
+        return self.cycle_stepped;
+    }
+
+    pub fn clock_count(&self) -> u64{
+        return self.clock_count;
+    }
+
+    pub fn bus(&self) -> &M{
+        return &self.bus;
+    }
+
+    pub fn bus_mut(&mut self) -> &mut M{
+        return &mut self.bus;
+    }
+
+    pub fn pc(&self) -> u16{
+        return self.pc;
+    }
+
+    // For test harnesses that start somewhere other than the reset vector
+    pub fn set_pc(&mut self, pc: u16){
+        self.pc = pc;
+    }
+
+    pub fn a(&self) -> u8{
+        return self.accum;
+    }
+
+    pub fn x(&self) -> u8{
+        return self.x;
+    }
+
+    pub fn y(&self) -> u8{
+        return self.y;
+    }
+
+    pub fn status(&self) -> u8{
+        return self.status;
+    }
+
+    pub fn stkp(&self) -> u8{
+        return self.stkp;
+    }
+
+    // Register setters, for loading a known state in test harnesses
+    pub fn set_a(&mut self, a: u8){
+        self.accum = a;
+    }
+
+    pub fn set_x(&mut self, x: u8){
+        self.x = x;
+    }
+
+    pub fn set_y(&mut self, y: u8){
+        self.y = y;
+    }
+
+    pub fn set_status(&mut self, status: u8){
+        self.status = status;
+    }
+
+    pub fn set_stkp(&mut self, stkp: u8){
+        self.stkp = stkp;
+    }
+
+    // True once a KIL opcode has jammed the CPU
+    pub fn halted(&self) -> bool{
+        return self.halted;
+    }
+
+    // Whether the next clock reads or writes the bus, which decides if a DMA
+    // can halt the CPU on it. Only cycle-stepped mode knows; running whole
+    // instructions at a time always says Read.
+    pub fn next_access(&self) -> AccessKind{
+        if !self.cycle_stepped || self.halted || self.step == 0{
+            return AccessKind::Read;
+        }
+        // Stack pushes
+        let pushes = if self.interrupting{
+            (2..=4).contains(&self.step)
+        }else{
+            match INSTRUCTIONS[self.opcode as usize].oper(){
+                BRK => (2..=4).contains(&self.step),
+                JSR => (3..=4).contains(&self.step),
+                PHA | PHP => self.step == 2,
+                _ => false
+            }
+        };
+        if pushes{
+            return AccessKind::Write;
+        }
+
+        let instruction = &INSTRUCTIONS[self.opcode as usize];
+        let mode = instruction.addr_mode();
+        let kind = access_kind(instruction.oper());
+        if self.interrupting || kind == AccessKind::Read || matches!(mode, IMP | IMM | REL){
+            return AccessKind::Read;
+        }
+        let address_cycles = address_cycles(mode);
+        if self.step <= address_cycles{
+            return AccessKind::Read;
+        }
+        // Indexed writes always spend a cycle reading the unfixed address
+        let mut data_step = self.step - address_cycles;
+        if matches!(mode, ABX | ABY | IZY){
+            if data_step == 1{
+                return AccessKind::Read;
+            }
+            data_step -= 1;
+        }
+        // Read-modify-write reads once, then writes twice
+        if kind == AccessKind::Write || data_step >= 2{
+            return AccessKind::Write;
+        }
+        return AccessKind::Read;
+    }
 
-            // Read new program counter location
-            self.addr_abs = 0xFFFE;
-            let lo: u16 = self.read_this(self.addr_abs + 0).into();
-            let hi: u16 = self.read_this(self.addr_abs + 1).into();
-            self.pc = (hi << 8) | lo;
+    /******
+     * Interrupts
+     ******/
+    // IRQ is level triggered, shared by everything that can pull it low.
+    // NMI is edge triggered. Both lines are sampled at the end of every
+    // cycle, and an instruction acts on them as they stood at the end of
+    // its second to last cycle, which is where the one instruction delay
+    // after CLI, SEI and PLP comes from.
 
-            self.cycles = 7;
+    pub fn set_irq_line(&mut self, asserted: bool){
+        self.irq_line = asserted;
+    }
+
+    pub fn set_nmi_line(&mut self, asserted: bool){
+        self.nmi_line = asserted;
+    }
+
+    // Call at the end of every CPU cycle, DMA cycles included, once the
+    // lines are up to date
+    pub fn poll_interrupts(&mut self){
+        self.prev_need_nmi = self.need_nmi;
+        if self.nmi_line && !self.nmi_line_previous{
+            self.need_nmi = true;
         }
+        self.nmi_line_previous = self.nmi_line;
+
+        self.prev_run_irq = self.run_irq;
+        self.run_irq = self.irq_line && self.get_flag('I') == 0;
     }
 
-    // Non-Maskable Interrupt - cannot be ignored
-    pub fn nmi(&mut self){
-        self.write_this(0x0100 + self.stkp as u16, ((self.pc >> 8) & 0x00FF).try_into().unwrap());
-        self.stkp -= 1;
-        self.write_this(0x0100 + self.stkp as u16, (self.pc & 0x00FF).try_into().unwrap());
-        self.stkp -= 1;
+    // True when the next instruction boundary runs an interrupt sequence
+    // instead of fetching an opcode
+    pub fn interrupt_pending(&self) -> bool{
+        return self.prev_need_nmi || self.prev_run_irq;
+    }
 
-        self.set_flag('B', false);
-        self.set_flag('U', true);
+    // Where an interrupt sequence or BRK goes. An NMI that turns up before
+    // the vector is read takes the sequence over.
+    fn interrupt_vector(&mut self) -> u16{
+        if self.need_nmi{
+            self.need_nmi = false;
+            return 0xFFFA;
+        }
+        return 0xFFFE;
+    }
+
+    // The whole IRQ/NMI sequence at once, for instruction at a time mode
+    fn interrupt(&mut self){
+        self.write_this(0x0100 + self.stkp as u16, (self.pc >> 8) as u8);
+        self.stkp = self.stkp.wrapping_sub(1);
+        self.write_this(0x0100 + self.stkp as u16, self.pc as u8);
+        self.stkp = self.stkp.wrapping_sub(1);
+
+        // Push status register to the stack, then mask further IRQs
+        self.write_this(0x0100 + self.stkp as u16, (self.status & !FLAGS_6502('B')) | FLAGS_6502('U'));
+        self.stkp = self.stkp.wrapping_sub(1);
         self.set_flag('I', true);
-        self.write_this(0x0100 + self.stkp as u16, self.status);
-        self.stkp -= 1;
 
-        self.addr_abs = 0xFFFA;
+        self.addr_abs = self.interrupt_vector();
         let lo: u16 = self.read_this(self.addr_abs + 0).into();
         let hi: u16 = self.read_this(self.addr_abs + 1).into();
         self.pc = (hi << 8) | lo;
 
-        self.cycles = 8;
+        self.cycles = 7;
     }
 
     // One cycle of emulation
-    fn clock(&mut self){
-        if self.cycles == 0{
+    pub fn clock(&mut self){
+        self.clock_count += 1;
+
+        // A jammed CPU never fetches again
+        if self.halted{
+            return;
+        }
+
+        if self.cycle_stepped && self.cycles == 0{
+            self.clock_stepped();
+            return;
+        }
+
+        if self.cycles == 0 && self.interrupt_pending(){
+            self.interrupt();
+        }else if self.cycles == 0{
             self.opcode = self.read_this(self.pc);
 
             self.set_flag('U', true);
 
-            self.pc += 1;
+            self.pc = self.pc.wrapping_add(1);
 
             self.cycles = INSTRUCTIONS[self.opcode as usize].cycles();
 
-            let more_cycles1: u8 = match INSTRUCTIONS[self.opcode as usize].addr_mode(){
-                IMM => self.IMM(),
-                IMP => self.IMP(),
-                ZP0 => self.ZP0(),
-                ZPX => self.ZPX(),
-                ZPY => self.ZPY(),
-                ABS => self.ABS(),
-                ABX => self.ABX(),
-                ABY => self.ABY(),
-                IND => self.IND(),
-                IZX => self.IZX(),
-                IZY => self.IZX(),
-                REL => self.REL()
-            };
-
-            let more_cycles2: u8 = match INSTRUCTIONS[self.opcode as usize].oper(){
-                ADC => self.ADC(),
-                AND => self.AND(),
-                ASL => self.ASL(),
-                BCC => self.BCC(),
-                BCS => self.BCS(),
-                BEQ => self.BEQ(),
-                BIT => self.BIT(),
-                BMI => self.BMI(),
-                BNE => self.BNE(),
-                BPL => self.BPL(),
-                BRK => self.BRK(),
-                BVC => self.BVC(),
-                BVS => self.BVS(),
-                CLC => self.CLC(),
-                CLD => self.CLD(),
-                CLI => self.CLI(),
-                CLV => self.CLV(),
-                CMP => self.CMP(),
-                CPX => self.CPX(),
-                CPY => self.CPY(),
-                DEC => self.DEC(),
-                DEX => self.DEX(),
-                DEY => self.DEY(),
-                EOR => self.EOR(),
-                INC => self.INC(),
-                INX => self.INX(),
-                INY => self.INY(),
-                JMP => self.JMP(),
-                JSR => self.JSR(),
-                LDA => self.LDA(),
-                LDX => self.LDX(),
-                LDY => self.LDY(),
-                LSR => self.LSR(),
-                NOP => self.NOP(),
-                ORA => self.ORA(),
-                PHA => self.PHA(),
-                PHP => self.PHP(),
-                PLA => self.PLA(),
-                PLP => self.PLP(),
-                ROL => self.ROL(),
-                ROR => self.ROR(),
-                RTI => self.RTI(),
-                RTS => self.RTS(),
-                SBC => self.SBC(),
-                SEC => self.SEC(),
-                SED => self.SED(),
-                SEI => self.SEI(),
-                STA => self.STA(),
-                STX => self.STX(),
-                STY => self.STY(),
-                TAX => self.TAX(),
-                TAY => self.TAY(),
-                TSX => self.TSX(),
-                TXA => self.TXA(),
-                TXS => self.TXS(),
-                TYA => self.TYA(),
-                _   => self.XXX(INSTRUCTIONS[self.opcode as usize].opcode())
-            };
+            let more_cycles1: u8 = self.address();
+            let more_cycles2: u8 = self.execute();
 
             self.cycles += more_cycles1 & more_cycles2;
 
@@ -267,6 +398,561 @@ impl CPU_6502{
         self.cycles -= 1;
     }
 
+    // Works out addr_abs for the current opcode, all in one go. Returns 1
+    // when indexing crossed a page.
+    fn address(&mut self) -> u8{
+        match INSTRUCTIONS[self.opcode as usize].addr_mode(){
+            IMM => self.IMM(),
+            IMP => self.IMP(),
+            ZP0 => self.ZP0(),
+            ZPX => self.ZPX(),
+            ZPY => self.ZPY(),
+            ABS => self.ABS(),
+            ABX => self.ABX(),
+            ABY => self.ABY(),
+            IND => self.IND(),
+            IZX => self.IZX(),
+            IZY => self.IZY(),
+            REL => self.REL()
+        }
+    }
+
+    // Runs the current opcode's operation. Returns 1 when it pays the page
+    // crossing penalty.
+    fn execute(&mut self) -> u8{
+        match INSTRUCTIONS[self.opcode as usize].oper(){
+            ADC => self.ADC(),
+            AND => self.AND(),
+            ASL => self.ASL(),
+            BCC => self.BCC(),
+            BCS => self.BCS(),
+            BEQ => self.BEQ(),
+            BIT => self.BIT(),
+            BMI => self.BMI(),
+            BNE => self.BNE(),
+            BPL => self.BPL(),
+            BRK => self.BRK(),
+            BVC => self.BVC(),
+            BVS => self.BVS(),
+            CLC => self.CLC(),
+            CLD => self.CLD(),
+            CLI => self.CLI(),
+            CLV => self.CLV(),
+            CMP => self.CMP(),
+            CPX => self.CPX(),
+            CPY => self.CPY(),
+            DEC => self.DEC(),
+            DEX => self.DEX(),
+            DEY => self.DEY(),
+            EOR => self.EOR(),
+            INC => self.INC(),
+            INX => self.INX(),
+            INY => self.INY(),
+            JMP => self.JMP(),
+            JSR => self.JSR(),
+            LDA => self.LDA(),
+            LDX => self.LDX(),
+            LDY => self.LDY(),
+            LSR => self.LSR(),
+            NOP => self.NOP(),
+            ORA => self.ORA(),
+            PHA => self.PHA(),
+            PHP => self.PHP(),
+            PLA => self.PLA(),
+            PLP => self.PLP(),
+            ROL => self.ROL(),
+            ROR => self.ROR(),
+            RTI => self.RTI(),
+            RTS => self.RTS(),
+            SBC => self.SBC(),
+            SEC => self.SEC(),
+            SED => self.SED(),
+            SEI => self.SEI(),
+            STA => self.STA(),
+            STX => self.STX(),
+            STY => self.STY(),
+            TAX => self.TAX(),
+            TAY => self.TAY(),
+            TSX => self.TSX(),
+            TXA => self.TXA(),
+            TXS => self.TXS(),
+            TYA => self.TYA(),
+            AHX => self.AHX(),
+            ALR => self.ALR(),
+            ANC => self.ANC(),
+            ARR => self.ARR(),
+            AXS => self.AXS(),
+            DCP => self.DCP(),
+            ISC => self.ISC(),
+            KIL => self.KIL(),
+            LAS => self.LAS(),
+            LAX => self.LAX(),
+            RLA => self.RLA(),
+            RRA => self.RRA(),
+            SAX => self.SAX(),
+            SHX => self.SHX(),
+            SHY => self.SHY(),
+            SLO => self.SLO(),
+            SRE => self.SRE(),
+            TAS => self.TAS(),
+            XAA => self.XAA(),
+            XXX => self.XXX(INSTRUCTIONS[self.opcode as usize].opcode())
+        }
+    }
+
+    /******
+     * Save states
+     ******/
+    // Registers and every latch an instruction can be stopped halfway
+    // through with. The bus is saved by whoever owns it.
+
+    pub fn save_state(&self, state: &mut StateWriter){
+        state.section(*b"CPU ", 1, |w| {
+            w.u8(self.accum);
+            w.u8(self.x);
+            w.u8(self.y);
+            w.u8(self.stkp);
+            w.u16(self.pc);
+            w.u8(self.status);
+            w.u8(self.fetched);
+            w.u16(self.temp);
+            w.u16(self.addr_abs);
+            w.u16(self.addr_rel);
+            w.u8(self.opcode);
+            w.u8(self.cycles);
+            w.u64(self.clock_count);
+            w.bool(self.halted);
+            w.bool(self.cycle_stepped);
+            w.u8(self.step);
+            w.u16(self.base);
+            w.bool(self.prefetched);
+            w.bool(self.irq_line);
+            w.bool(self.nmi_line);
+            w.bool(self.nmi_line_previous);
+            w.bool(self.need_nmi);
+            w.bool(self.prev_need_nmi);
+            w.bool(self.run_irq);
+            w.bool(self.prev_run_irq);
+            w.bool(self.interrupting);
+        });
+    }
+
+    pub fn load_state(&mut self, state: &StateFile) -> Result<(), StateError>{
+        let mut r = state.section(*b"CPU ")?;
+        self.accum = r.u8()?;
+        self.x = r.u8()?;
+        self.y = r.u8()?;
+        self.stkp = r.u8()?;
+        self.pc = r.u16()?;
+        self.status = r.u8()?;
+        self.fetched = r.u8()?;
+        self.temp = r.u16()?;
+        self.addr_abs = r.u16()?;
+        self.addr_rel = r.u16()?;
+        self.opcode = r.u8()?;
+        self.cycles = r.u8()?;
+        self.clock_count = r.u64()?;
+        self.halted = r.bool()?;
+        self.cycle_stepped = r.bool()?;
+        self.step = r.u8()?;
+        self.base = r.u16()?;
+        self.prefetched = r.bool()?;
+        self.irq_line = r.bool()?;
+        self.nmi_line = r.bool()?;
+        self.nmi_line_previous = r.bool()?;
+        self.need_nmi = r.bool()?;
+        self.prev_need_nmi = r.bool()?;
+        self.run_irq = r.bool()?;
+        self.prev_run_irq = r.bool()?;
+        self.interrupting = r.bool()?;
+        return Ok(());
+    }
+
+    /**********************************
+     * 
+     * Cycle-stepped execution
+     * 
+     **********************************/
+    // Each clock does the one bus access the real CPU makes on that cycle,
+    // dummy reads and the double write of read-modify-write instructions
+    // included. The operations themselves are shared with the instruction
+    // at a time path above, they just get called on the cycle where their
+    // read or write belongs.
+
+    fn clock_stepped(&mut self){
+        if self.step == 0 && self.interrupt_pending(){
+            // The opcode is fetched and thrown away, and PC stays put
+            self.dummy_read_pc();
+            self.interrupting = true;
+            self.step = 1;
+            return;
+        }
+        if self.step == 0{
+            self.opcode = self.read_this(self.pc);
+            self.set_flag('U', true);
+            self.pc = self.pc.wrapping_add(1);
+            self.step = 1;
+            return;
+        }
+
+        let done = self.instruction_cycle();
+        self.set_flag('U', true);
+        self.step = if done{ 0 }else{ self.step + 1 };
+    }
+
+    // Runs the operation on the current cycle, with its read already done
+    // when prefetched is set
+    fn operate(&mut self, prefetched: bool){
+        self.prefetched = prefetched;
+        self.execute();
+        self.prefetched = false;
+    }
+
+    fn push(&mut self, data: u8){
+        self.write_this(0x0100 + self.stkp as u16, data);
+        self.stkp = self.stkp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8{
+        self.stkp = self.stkp.wrapping_add(1);
+        return self.read_this(0x0100 + self.stkp as u16);
+    }
+
+    // The cycle after the opcode fetch always reads the next byte, whether
+    // the instruction wants it or not
+    fn dummy_read_pc(&mut self){
+        self.read_this(self.pc);
+    }
+
+    fn branch_taken(&self) -> bool{
+        match INSTRUCTIONS[self.opcode as usize].oper(){
+            BCC => return self.get_flag('C') == 0,
+            BCS => return self.get_flag('C') == 1,
+            BEQ => return self.get_flag('Z') == 1,
+            BNE => return self.get_flag('Z') == 0,
+            BMI => return self.get_flag('N') == 1,
+            BPL => return self.get_flag('N') == 0,
+            BVC => return self.get_flag('V') == 0,
+            _ => return self.get_flag('V') == 1
+        }
+    }
+
+    // Cycle `step` of the current instruction, counting the opcode fetch as
+    // cycle 0. Returns true on its last cycle.
+    fn instruction_cycle(&mut self) -> bool{
+        if self.interrupting{
+            return self.interrupt_cycle();
+        }
+        let instruction = &INSTRUCTIONS[self.opcode as usize];
+        let mode = instruction.addr_mode();
+        match instruction.oper(){
+            BRK => return self.brk_cycle(),
+            JSR => return self.jsr_cycle(),
+            RTI => return self.rti_cycle(),
+            RTS => return self.rts_cycle(),
+            JMP => return self.jmp_cycle(mode),
+            PHA | PHP => {
+                if self.step == 1{
+                    self.dummy_read_pc();
+                    return false;
+                }
+                let data = if self.opcode == 0x48{ self.accum }else{ self.status | FLAGS_6502('B') | FLAGS_6502('U') };
+                self.push(data);
+                return true;
+            }
+            PLA | PLP => {
+                match self.step{
+                    1 => self.dummy_read_pc(),
+                    2 => { self.read_this(0x0100 + self.stkp as u16); }
+                    _ => {
+                        self.operate(false);
+                        return true;
+                    }
+                }
+                return false;
+            }
+            BCC | BCS | BEQ | BNE | BMI | BPL | BVC | BVS => return self.branch_cycle(),
+            KIL => {
+                self.dummy_read_pc();
+                self.halted = true;
+                return true;
+            }
+            _ => {}
+        }
+
+        match mode{
+            IMP => {
+                self.dummy_read_pc();
+                self.IMP();
+                self.operate(false);
+                return true;
+            }
+            IMM => {
+                self.addr_abs = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                self.operate(false);
+                return true;
+            }
+            _ => {}
+        }
+
+        // Cycles spent working out the address, then the data cycles
+        let address_cycles = address_cycles(mode);
+        if self.step <= address_cycles{
+            self.address_cycle(mode);
+            return false;
+        }
+        let data_step = self.step - address_cycles;
+        let indexed = matches!(mode, ABX | ABY | IZY);
+        return self.data_cycle(access_kind(instruction.oper()), indexed, data_step);
+    }
+
+    fn address_cycle(&mut self, mode: AddrMode){
+        match (mode, self.step){
+            (ZP0, _) | (ZPX, 1) | (ZPY, 1) | (ABS, 1) | (ABX, 1) | (ABY, 1) | (IZX, 1) | (IZY, 1) => {
+                self.addr_abs = self.read_this(self.pc) as u16;
+                self.pc = self.pc.wrapping_add(1);
+            }
+            (ZPX, _) | (ZPY, _) => {
+                // The base is read while the index is added, wrapping in page zero
+                self.read_this(self.addr_abs);
+                let index = if mode == ZPX{ self.x }else{ self.y };
+                self.addr_abs = (self.addr_abs as u8).wrapping_add(index) as u16;
+            }
+            (ABS, _) => {
+                self.addr_abs |= (self.read_this(self.pc) as u16) << 8;
+                self.pc = self.pc.wrapping_add(1);
+            }
+            (ABX, _) | (ABY, _) => {
+                let hi = (self.read_this(self.pc) as u16) << 8;
+                self.pc = self.pc.wrapping_add(1);
+                let index = if mode == ABX{ self.x }else{ self.y };
+                self.set_indexed(hi | self.addr_abs, index);
+            }
+            (IZX, 2) => {
+                self.read_this(self.addr_abs);
+                self.base = (self.addr_abs as u8).wrapping_add(self.x) as u16;
+            }
+            (IZX, 3) => {
+                self.addr_abs = self.read_this(self.base) as u16;
+            }
+            (IZX, _) => {
+                let pointer = (self.base as u8).wrapping_add(1) as u16;
+                self.addr_abs |= (self.read_this(pointer) as u16) << 8;
+            }
+            (IZY, 2) => {
+                self.base = self.addr_abs;
+                self.addr_abs = self.read_this(self.base) as u16;
+            }
+            (IZY, _) => {
+                let pointer = (self.base as u8).wrapping_add(1) as u16;
+                let hi = (self.read_this(pointer) as u16) << 8;
+                self.set_indexed(hi | self.addr_abs, self.y);
+            }
+            _ => {}
+        }
+    }
+
+    // Indexing only adds to the low byte at first. base is left holding
+    // the address before the carry into the high byte is fixed.
+    fn set_indexed(&mut self, address: u16, index: u8){
+        self.addr_abs = address.wrapping_add(index as u16);
+        self.base = (address & 0xFF00) | (self.addr_abs & 0x00FF);
+    }
+
+    fn data_cycle(&mut self, kind: AccessKind, indexed: bool, data_step: u8) -> bool{
+        // Indexed reads that stay on the page finish straight away,
+        // everything else indexed reads the unfixed address first
+        let data_step = if indexed{
+            if data_step == 1{
+                if kind == AccessKind::Read && self.base == self.addr_abs{
+                    self.operate(false);
+                    return true;
+                }
+                self.read_this(self.base);
+                return false;
+            }
+            data_step - 1
+        }else{
+            data_step
+        };
+
+        match (kind, data_step){
+            (AccessKind::Read, _) | (AccessKind::Write, _) => {
+                self.operate(false);
+                return true;
+            }
+            (AccessKind::Modify, 1) => {
+                self.fetched = self.read_this(self.addr_abs);
+                return false;
+            }
+            (AccessKind::Modify, 2) => {
+                // Writes the value back unchanged while the ALU works
+                self.write_this(self.addr_abs, self.fetched);
+                return false;
+            }
+            (AccessKind::Modify, _) => {
+                self.operate(true);
+                return true;
+            }
+        }
+    }
+
+    fn branch_cycle(&mut self) -> bool{
+        match self.step{
+            1 => {
+                self.addr_rel = self.read_this(self.pc) as u16;
+                if self.addr_rel & 0x80 != 0{
+                    self.addr_rel |= 0xFF00;
+                }
+                self.pc = self.pc.wrapping_add(1);
+                return !self.branch_taken();
+            }
+            2 => {
+                // A taken branch that stays on its page doesn't poll again
+                // on its last cycle, so an IRQ that only just arrived waits
+                // for the next instruction
+                if self.run_irq && !self.prev_run_irq{
+                    self.run_irq = false;
+                }
+                self.dummy_read_pc();
+                self.addr_abs = self.pc.wrapping_add(self.addr_rel);
+                // Only the low byte moves on this cycle
+                self.pc = (self.pc & 0xFF00) | (self.addr_abs & 0x00FF);
+                return self.pc == self.addr_abs;
+            }
+            _ => {
+                self.dummy_read_pc();
+                self.pc = self.addr_abs;
+                return true;
+            }
+        }
+    }
+
+    fn jmp_cycle(&mut self, mode: AddrMode) -> bool{
+        match self.step{
+            1 => {
+                self.addr_abs = self.read_this(self.pc) as u16;
+                self.pc = self.pc.wrapping_add(1);
+                return false;
+            }
+            2 => {
+                self.addr_abs |= (self.read_this(self.pc) as u16) << 8;
+                self.pc = self.pc.wrapping_add(1);
+                if mode == ABS{
+                    self.pc = self.addr_abs;
+                    return true;
+                }
+                return false;
+            }
+            3 => {
+                self.fetched = self.read_this(self.addr_abs);
+                return false;
+            }
+            _ => {
+                // The pointer's high byte comes from the same page
+                let hi_addr = (self.addr_abs & 0xFF00) | (self.addr_abs.wrapping_add(1) & 0x00FF);
+                self.pc = ((self.read_this(hi_addr) as u16) << 8) | self.fetched as u16;
+                return true;
+            }
+        }
+    }
+
+    fn jsr_cycle(&mut self) -> bool{
+        match self.step{
+            1 => {
+                self.addr_abs = self.read_this(self.pc) as u16;
+                self.pc = self.pc.wrapping_add(1);
+            }
+            2 => { self.read_this(0x0100 + self.stkp as u16); }
+            3 => self.push((self.pc >> 8) as u8),
+            4 => self.push(self.pc as u8),
+            _ => {
+                self.addr_abs |= (self.read_this(self.pc) as u16) << 8;
+                self.pc = self.addr_abs;
+                return true;
+            }
+        }
+        return false;
+    }
+
+    fn rts_cycle(&mut self) -> bool{
+        match self.step{
+            1 => self.dummy_read_pc(),
+            2 => { self.read_this(0x0100 + self.stkp as u16); }
+            3 => self.pc = self.pull() as u16,
+            4 => self.pc |= (self.pull() as u16) << 8,
+            _ => {
+                self.dummy_read_pc();
+                self.pc = self.pc.wrapping_add(1);
+                return true;
+            }
+        }
+        return false;
+    }
+
+    fn rti_cycle(&mut self) -> bool{
+        match self.step{
+            1 => self.dummy_read_pc(),
+            2 => { self.read_this(0x0100 + self.stkp as u16); }
+            3 => {
+                self.status = self.pull();
+                self.set_flag('B', false);
+                self.set_flag('U', true);
+            }
+            4 => self.pc = self.pull() as u16,
+            _ => {
+                self.pc |= (self.pull() as u16) << 8;
+                return true;
+            }
+        }
+        return false;
+    }
+
+    fn brk_cycle(&mut self) -> bool{
+        match self.step{
+            1 => {
+                self.dummy_read_pc();
+                self.pc = self.pc.wrapping_add(1);
+            }
+            2 => self.push((self.pc >> 8) as u8),
+            3 => self.push(self.pc as u8),
+            4 => {
+                // B only ever exists in the pushed copy
+                self.push(self.status | FLAGS_6502('B') | FLAGS_6502('U'));
+                self.set_flag('I', true);
+                self.addr_abs = self.interrupt_vector();
+            }
+            5 => self.pc = self.read_this(self.addr_abs) as u16,
+            _ => {
+                self.pc |= (self.read_this(self.addr_abs + 1) as u16) << 8;
+                return true;
+            }
+        }
+        return false;
+    }
+
+    // Same as BRK but without touching PC, and with B clear
+    fn interrupt_cycle(&mut self) -> bool{
+        match self.step{
+            1 => self.dummy_read_pc(),
+            2 => self.push((self.pc >> 8) as u8),
+            3 => self.push(self.pc as u8),
+            4 => {
+                self.push((self.status & !FLAGS_6502('B')) | FLAGS_6502('U'));
+                self.set_flag('I', true);
+                self.addr_abs = self.interrupt_vector();
+            }
+            5 => self.pc = self.read_this(self.addr_abs) as u16,
+            _ => {
+                self.pc |= (self.read_this(self.addr_abs + 1) as u16) << 8;
+                self.interrupting = false;
+                return true;
+            }
+        }
+        return false;
+    }
+
     /**********************************
      * 
      * Addressing Modes
@@ -280,35 +966,35 @@ impl CPU_6502{
      // Immediate
     fn IMM(&mut self) -> u8{
         self.addr_abs = self.pc;
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         return 0;
      }
      // Zero page
     fn ZP0(&mut self) -> u8{
         self.addr_abs = self.read_this(self.pc).into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         self.addr_abs &= 0x00FF;
         return 0;
      }
      // Zero page with X offset
     fn ZPX(&mut self) -> u8{
-        self.addr_abs = (self.read_this(self.pc) + self.x).into();
-        self.pc += 1;
+        self.addr_abs = self.read_this(self.pc).wrapping_add(self.x).into();
+        self.pc = self.pc.wrapping_add(1);
         self.addr_abs &= 0x00FF;
         return 0;
      }
      // Zero page with Y offset
     fn ZPY(&mut self) -> u8{
-        self.addr_abs = (self.read_this(self.pc) + self.y).into();
-        self.pc += 1;
+        self.addr_abs = self.read_this(self.pc).wrapping_add(self.y).into();
+        self.pc = self.pc.wrapping_add(1);
         self.addr_abs &= 0x00FF;
         return 0;
      }
      // Relative
     fn REL(&mut self) -> u8{
-        self.addr_abs = self.pc;
-        self.pc += 1;
-        if self.addr_rel & 0x80 == 1{
+        self.addr_rel = self.read_this(self.pc).into();
+        self.pc = self.pc.wrapping_add(1);
+        if self.addr_rel & 0x80 != 0{
             self.addr_rel |= 0xFF00;
         }
         return 0;
@@ -316,9 +1002,9 @@ impl CPU_6502{
      // Absolute
      fn ABS(&mut self) -> u8{
         let lo: u16 = self.read_this(self.pc) as u16;
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         let hi: u16 = self.read_this(self.pc) as u16;
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         
         self.addr_abs = (hi << 8) | lo;
 
@@ -327,12 +1013,12 @@ impl CPU_6502{
      // Absolute with X Offset
     fn ABX(&mut self) -> u8{
         let lo: u16 = self.read_this(self.pc) .into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         let hi: u16 = self.read_this(self.pc) .into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         
         self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.x as u16;
+        self.addr_abs = self.addr_abs.wrapping_add(self.x as u16);
 
         if (self.addr_abs & 0xFF00) != (hi << 8){
             return 1;
@@ -343,12 +1029,12 @@ impl CPU_6502{
      // Absolute with Y offset
     fn ABY(&mut self) -> u8{
         let lo: u16 = self.read_this(self.pc) .into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         let hi: u16 = self.read_this(self.pc) .into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         
         self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.y as u16;
+        self.addr_abs = self.addr_abs.wrapping_add(self.y as u16);
 
         if (self.addr_abs & 0xFF00) != (hi << 8){
             return 1;
@@ -359,15 +1045,15 @@ impl CPU_6502{
      // Indirect
     fn IND(&mut self) -> u8{
         let ptr_lo: u16 = self.read_this(self.pc) .into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         let ptr_hi: u16 = self.read_this(self.pc) .into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
 
         let ptr: u16 = (ptr_hi << 8) | ptr_lo;
 
         // Bug in NES
         if ptr_lo == 0x00FF{ // Should be fine 
-            self.addr_abs = ((self.read_this(ptr & 0xFF00) as u16) >> 8) | (self.read_this(ptr + 0) as u16);
+            self.addr_abs = ((self.read_this(ptr & 0xFF00) as u16) << 8) | (self.read_this(ptr + 0) as u16);
         }else{               // Should be fine
             self.addr_abs = ((self.read_this(ptr + 1) as u16) << 8) | (self.read_this(ptr + 0) as u16);
         }
@@ -377,7 +1063,7 @@ impl CPU_6502{
      // Indirect X
     fn IZX(&mut self) -> u8{
         let t: u16 = self.read_this(self.pc).into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
 
         let lo: u16 = self.read_this((t + self.x as u16) & 0x00FF).into();
         let hi: u16 = self.read_this((t + self.x as u16 + 1) & 0x00FF).into();
@@ -389,13 +1075,13 @@ impl CPU_6502{
      // Indirect Y
     fn IZY(&mut self) -> u8{
         let t: u16 = self.read_this(self.pc).into();
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
 
         let lo: u16 = self.read_this(t & 0x00FF).into();
         let hi: u16 = self.read_this((t + 1) & 0x00FF).into();
 
         self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.y as u16;
+        self.addr_abs = self.addr_abs.wrapping_add(self.y as u16);
 
         if (self.addr_abs & 0xFF00) != (hi << 8){
             return 1;
@@ -404,11 +1090,11 @@ impl CPU_6502{
         }
     }
     
-     /**************
-     DONT FORGET TO FINISH THIS
-    *************************/
     // Fetches the data used by the instruction
     fn fetch(&mut self) -> u8{
+        if self.prefetched{
+            return self.fetched;
+        }
         if INSTRUCTIONS[self.opcode as usize].addr_mode() != IMP{
             self.fetched = self.read_this(self.addr_abs);
         }
@@ -420,39 +1106,34 @@ impl CPU_6502{
      * Instruction Implementations
      * 
      **********************************/
-    // Add with Carry In
-     fn ADC(&mut self) -> u8{
-        // Grab data for accumulator
-        self.fetch();
-
+    // Shared by ADC, SBC and the unofficial RRA and ISC. Subtraction is
+    // addition of the one's complement, so both funnel through here.
+    fn add_with_carry(&mut self, value: u8){
         // Performed in 16 bit to capture a carry bit
         // This will exist in bit 8 of the 16 bit
-        self.temp = (self.accum + self.fetched + self.get_flag('C')) as u16;
+        self.temp = self.accum as u16 + value as u16 + self.get_flag('C') as u16;
 
         self.set_flag('C', self.temp > 255);
 
         self.set_flag('Z', (self.temp & 0x00FF) == 0);
 
-        self.set_flag('V', !((self.accum ^ self.fetched) as u16 & (self.accum as u16 ^ self.temp)) & 0x0080 != 0);
+        self.set_flag('V', ((self.temp ^ self.accum as u16) & (self.temp ^ value as u16) & 0x0080) != 0);
 
         self.set_flag('N', self.temp & 0x80 != 0);
 
         self.accum = (self.temp & 0x00FF) as u8;
-
+    }
+    // Add with Carry In
+     fn ADC(&mut self) -> u8{
+        // Grab data for accumulator
+        self.fetch();
+        self.add_with_carry(self.fetched);
         return 1;
     }
     // Subtraction with Borrow In
     fn SBC(&mut self) -> u8{
         self.fetch();
-
-        let value: u16 = self.fetched as u16 ^ 0x00FF;
-
-        self.temp = self.accum as u16 + value + self.get_flag('C') as u16;
-        self.set_flag('C', (self.temp & 0xFF00) != 0);
-        self.set_flag('Z', (self.temp & 0x00FF) == 0);
-        self.set_flag('V', ((self.temp ^ self.accum as u16) & (self.temp ^ value) & 0x0080) != 0);
-        self.set_flag('N', (self.temp & 0x0080) != 0);
-        self.accum = (self.temp & 0x00FF) as u8;
+        self.add_with_carry(self.fetched ^ 0xFF);
         return 1;
     }
     // Bitwise Logic AND
@@ -466,13 +1147,13 @@ impl CPU_6502{
     // Arithmetic Shift Left
     fn ASL(&mut self) -> u8{
         self.fetch();
-        self.temp = (self.fetched << 1) as u16;
+        self.temp = (self.fetched as u16) << 1;
         self.set_flag('C', (self.temp & 0xFF00) > 0);
         self.set_flag('Z', (self.temp & 0x00FF) == 0x00);
         self.set_flag('N', (self.temp & 0x80) != 0);
 
-        if INSTRUCTIONS[self.opcode as usize].addr_mode() != IMP{
-            self.fetched = self.read_this(self.addr_abs);
+        if INSTRUCTIONS[self.opcode as usize].addr_mode() == IMP{
+            self.accum = (self.temp & 0x00FF) as u8;
         }else{
             self.write_this(self.addr_abs, (self.temp & 0x00FF) as u8);
         }
@@ -482,7 +1163,7 @@ impl CPU_6502{
     fn BCC(&mut self) -> u8{
         if self.get_flag('C') == 0{
             self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
 
             if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00){
                 self.cycles += 1;
@@ -496,7 +1177,7 @@ impl CPU_6502{
     fn BCS(&mut self) -> u8{
         if self.get_flag('C') == 1{
             self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
 
             if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00){
                 self.cycles += 1;
@@ -510,7 +1191,7 @@ impl CPU_6502{
     fn BEQ(&mut self) -> u8{
         if self.get_flag('Z') == 1{
             self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
 
             if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00){
                 self.cycles += 1;
@@ -533,7 +1214,7 @@ impl CPU_6502{
     fn BMI(&mut self) -> u8{
         if self.get_flag('N') == 1{
             self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
 
             if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00){
                 self.cycles += 1;
@@ -547,7 +1228,7 @@ impl CPU_6502{
     fn BNE(&mut self) -> u8{
         if self.get_flag('Z') == 0{
             self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
 
             if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00){
                 self.cycles += 1;
@@ -559,9 +1240,9 @@ impl CPU_6502{
     }
     // Branch if Positive
     fn BPL(&mut self) -> u8{
-        if self.get_flag('N') == 1{
+        if self.get_flag('N') == 0{
             self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
 
             if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00){
                 self.cycles += 1;
@@ -573,27 +1254,27 @@ impl CPU_6502{
     }
     // Break
     fn BRK(&mut self) -> u8{
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
 
-        self.set_flag('I', true);
         self.write_this(0x0100 + self.stkp as u16, ((self.pc >> 8) & 0x00FF) as u8);
-        self.stkp -= 1;
+        self.stkp = self.stkp.wrapping_sub(1);
         self.write_this(0x0100 + self.stkp as u16, (self.pc & 0x00FF) as u8);
-        self.stkp -= 1;
+        self.stkp = self.stkp.wrapping_sub(1);
 
-        self.set_flag('B', true);
-        self.write_this(0x0100 + self.stkp as u16, self.status);
-        self.stkp -= 1;
-        self.set_flag('B', false);
+        // B only ever exists in the pushed copy
+        self.write_this(0x0100 + self.stkp as u16, self.status | FLAGS_6502('B') | FLAGS_6502('U'));
+        self.stkp = self.stkp.wrapping_sub(1);
+        self.set_flag('I', true);
 
-        self.pc = (self.read_this(0xFFFE) | ((self.read_this(0xFFFF) as u16) << 8) as u8) as u16;
+        let vector = self.interrupt_vector();
+        self.pc = (self.read_this(vector) as u16) | ((self.read_this(vector + 1) as u16) << 8);
         return 0;
     }
     // Branch if Overflow Clear
     fn BVC(&mut self) -> u8{
         if self.get_flag('V') == 0{
             self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
 
             if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00){
                 self.cycles += 1;
@@ -607,7 +1288,7 @@ impl CPU_6502{
     fn BVS(&mut self) -> u8{
         if self.get_flag('V') == 1{
             self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
 
             if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00){
                 self.cycles += 1;
@@ -640,7 +1321,7 @@ impl CPU_6502{
     // Compare Accumulator
     fn CMP(&mut self) -> u8{
         self.fetch();
-        self.temp = (self.accum - self.fetched) as u16;
+        self.temp = self.accum.wrapping_sub(self.fetched) as u16;
         self.set_flag('C', self.accum >= self.fetched);
         self.set_flag('Z', (self.temp & 0x00FF) == 0x0000);
         self.set_flag('N', (self.temp & 0x0080) != 0);
@@ -649,7 +1330,7 @@ impl CPU_6502{
     // Compare X Register
     fn CPX(&mut self) -> u8{
         self.fetch();
-        self.temp = (self.x - self.fetched) as u16;
+        self.temp = self.x.wrapping_sub(self.fetched) as u16;
         self.set_flag('C', self.x >= self.fetched);
         self.set_flag('Z', (self.temp & 0x00FF) == 0x0000);
         self.set_flag('N', (self.temp & 0x0080) != 0);
@@ -658,31 +1339,31 @@ impl CPU_6502{
     // Compare Y Register
     fn CPY(&mut self) -> u8{
         self.fetch();
-        self.temp = (self.y - self.fetched) as u16;
+        self.temp = self.y.wrapping_sub(self.fetched) as u16;
         self.set_flag('C', self.y >= self.fetched);
         self.set_flag('Z', (self.temp & 0x00FF) == 0x0000);
         self.set_flag('N', (self.temp & 0x0080) != 0);
-        return 1;
+        return 0;
     }
     // Decrement Value at Memory Location
     fn DEC(&mut self) -> u8{
         self.fetch();
-        self.temp = (self.fetched - 1) as u16;
+        self.temp = self.fetched.wrapping_sub(1) as u16;
         self.write_this(self.addr_abs, (self.temp & 0x00FF) as u8);
         self.set_flag('Z', (self.temp & 0x00FF) == 0x0000);
         self.set_flag('N', (self.temp & 0x0080) != 0);
-        return 1;
+        return 0;
     }
     // Decrement X Register
     fn DEX(&mut self) -> u8{
-        self.x -= 1;
+        self.x = self.x.wrapping_sub(1);
         self.set_flag('Z', self.x == 0x00);
         self.set_flag('N', (self.x & 0x80) != 0);
         return 0;
     }
     // Decrement Y Register
     fn DEY(&mut self) -> u8{
-        self.y -= 1;
+        self.y = self.y.wrapping_sub(1);
         self.set_flag('Z', self.y == 0x00);
         self.set_flag('N', (self.y & 0x80) != 0);
         return 0;
@@ -690,7 +1371,7 @@ impl CPU_6502{
     // Bitwise Logic XOR
     fn EOR(&mut self) -> u8{
         self.fetch();
-        self.accum = self.accum ^ self.fetched;
+        self.accum ^= self.fetched;
         self.set_flag('Z', self.accum == 0x00);
         self.set_flag('N', (self.accum & 0x80) != 0);
         return 1;
@@ -698,7 +1379,7 @@ impl CPU_6502{
     // Increment Value at Memory Location
     fn INC(&mut self) -> u8{
         self.fetch();
-        self.temp = (self.fetched + 1) as u16;
+        self.temp = self.fetched.wrapping_add(1) as u16;
         self.write_this(self.addr_abs, (self.temp & 0x00FF) as u8);
         self.set_flag('Z', (self.temp & 0x00FF) == 0x0000);
         self.set_flag('N', (self.temp& 0x0080) != 0);
@@ -706,14 +1387,14 @@ impl CPU_6502{
     }
     // Increment X Register
     fn INX(&mut self) -> u8{
-        self.x += 1;
+        self.x = self.x.wrapping_add(1);
         self.set_flag('Z', self.x == 0x00);
         self.set_flag('N', (self.x & 0x80) != 0);
         return 0;
     }
     // Increment Y Register
     fn INY(&mut self) -> u8{
-        self.y += 1;
+        self.y = self.y.wrapping_add(1);
         self.set_flag('Z', self.y == 0x00);
         self.set_flag('N', (self.y & 0x80) != 0);
         return 0;
@@ -725,12 +1406,12 @@ impl CPU_6502{
     }
     // Jump To Location
     fn JSR(&mut self) -> u8{
-        self.pc -= 1;
+        self.pc = self.pc.wrapping_sub(1);
 
         self.write_this(0x0100 + self.stkp as u16, ((self.pc >> 8) & 0x00FF) as u8);
-        self.stkp -= 1;
+        self.stkp = self.stkp.wrapping_sub(1);
         self.write_this(0x0100 + self.stkp as u16, (self.pc & 0x00FF) as u8);
-        self.stkp -= 1;
+        self.stkp = self.stkp.wrapping_sub(1);
 
         self.pc = self.addr_abs;
         return 0;
@@ -767,7 +1448,7 @@ impl CPU_6502{
         self.set_flag('Z', (self.temp & 0x00FF) == 0x0000);
         self.set_flag('N', (self.temp & 0x0080) != 0);
 
-        if INSTRUCTIONS[self.opcode as usize].addr_mode() != IMP{
+        if INSTRUCTIONS[self.opcode as usize].addr_mode() == IMP{
             self.accum = (self.temp & 0x00FF) as u8;
         }else{
             self.write_this(self.addr_abs, (self.temp & 0x00FF) as u8);
@@ -776,6 +1457,8 @@ impl CPU_6502{
     }
 
     fn NOP(&mut self) -> u8{
+        // The unofficial forms with an operand still read it
+        self.fetch();
         match self.opcode{
             0x1C|
             0x3C|
@@ -789,7 +1472,7 @@ impl CPU_6502{
     // Bitwise Logic OR
     fn ORA(&mut self) -> u8{
         self.fetch();
-        self.accum = self.accum | self.fetched;
+        self.accum |= self.fetched;
         self.set_flag('Z', self.accum == 0x00);
         self.set_flag('N', (self.accum & 0x80) != 0);
         return 1;
@@ -797,20 +1480,18 @@ impl CPU_6502{
     // Push Accumulator to Stack
     fn PHA(&mut self) -> u8{
         self.write_this(0x0100 + self.stkp as u16, self.accum);
-        self.stkp -= 1;
+        self.stkp = self.stkp.wrapping_sub(1);
         return 0;
     }
     // Push Status Register to Stack
     fn PHP(&mut self) -> u8{
         self.write_this(0x0100 + self.stkp as u16, self.status | FLAGS_6502('B') | FLAGS_6502('U'));
-        self.set_flag('B', false);
-        self.set_flag('U', false);
-        self.stkp -= 1;
+        self.stkp = self.stkp.wrapping_sub(1);
         return 0;
     }
     // Pop Accumulator off Stack
     fn PLA(&mut self) -> u8{
-        self.stkp += 1;
+        self.stkp = self.stkp.wrapping_add(1);
         self.accum = self.read_this(0x0100 + self.stkp as u16);
         self.set_flag('Z', self.accum == 0x00);
         self.set_flag('N', (self.accum & 0x80) != 0);
@@ -818,20 +1499,21 @@ impl CPU_6502{
     }
     // Pop Status Register off Stack
     fn PLP(&mut self) -> u8{
-        self.stkp += 1;
+        self.stkp = self.stkp.wrapping_add(1);
         self.status = self.read_this(0x0100 + self.stkp as u16);
+        self.set_flag('B', false);
         self.set_flag('U', true);
         return 0;
     }
 
     fn ROL(&mut self) -> u8{
         self.fetch();
-        self.temp = ((self.fetched << 1) | self.get_flag('C')) as u16;
+        self.temp = ((self.fetched as u16) << 1) | self.get_flag('C') as u16;
         self.set_flag('C', (self.temp & 0xFF00) != 0);
         self.set_flag('Z', (self.temp & 0x00FF) == 0x0000);
         self.set_flag('N', (self.temp & 0x0080) != 0);
         
-        if INSTRUCTIONS[self.opcode as usize].addr_mode() != IMP{
+        if INSTRUCTIONS[self.opcode as usize].addr_mode() == IMP{
             self.accum = (self.temp & 0x00FF) as u8;
         }else{
             self.write_this(self.addr_abs, (self.temp & 0x00FF) as u8);
@@ -846,7 +1528,7 @@ impl CPU_6502{
         self.set_flag('Z', (self.temp & 0x00FF) == 0x00);
         self.set_flag('N', (self.temp & 0x0080) != 0);
         
-        if INSTRUCTIONS[self.opcode as usize].addr_mode() != IMP{
+        if INSTRUCTIONS[self.opcode as usize].addr_mode() == IMP{
             self.accum = (self.temp & 0x00FF) as u8;
         }else{
             self.write_this(self.addr_abs, (self.temp & 0x00FF) as u8);
@@ -855,25 +1537,25 @@ impl CPU_6502{
     }
 
     fn RTI(&mut self) -> u8{
-        self.stkp += 1;
+        self.stkp = self.stkp.wrapping_add(1);
         self.status = self.read_this(0x0100 + self.stkp as u16);
-        self.status &= !FLAGS_6502('B');
-        self.status &= !FLAGS_6502('U');
+        self.set_flag('B', false);
+        self.set_flag('U', true);
 
-        self.stkp += 1;
+        self.stkp = self.stkp.wrapping_add(1);
         self.pc = self.read_this(0x0100 + self.stkp as u16) as u16;
-        self.stkp += 1;
-        self.pc |= self.read_this((0x0100 + self.stkp as u16) << 8) as u16;
+        self.stkp = self.stkp.wrapping_add(1);
+        self.pc |= (self.read_this(0x0100 + self.stkp as u16) as u16) << 8;
         return 0;
     }
 
     fn RTS(&mut self) -> u8{
-        self.stkp += 1;
+        self.stkp = self.stkp.wrapping_add(1);
         self.pc = self.read_this(0x0100 + self.stkp as u16) as u16;
-        self.stkp += 1;
-        self.pc |= self.read_this((0x0100 + self.stkp as u16) << 8) as u16;
+        self.stkp = self.stkp.wrapping_add(1);
+        self.pc |= (self.read_this(0x0100 + self.stkp as u16) as u16) << 8;
 
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         return 0;
     }
     // Set Carry Flag
@@ -930,8 +1612,8 @@ impl CPU_6502{
     // Transfer X Register to Accumulator
     fn TXA(&mut self) -> u8{
         self.accum = self.x;
-        self.set_flag('Z', self.x == 0x00);
-        self.set_flag('N', (self.x & 0x80) != 0);
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
         return 0;
     }
     // Transfer X Register to Stack Pointer
@@ -942,8 +1624,8 @@ impl CPU_6502{
     // Transfer X Register to Accumulator
     fn TYA(&mut self) -> u8{
         self.accum = self.y;
-        self.set_flag('Z', self.x == 0x00);
-        self.set_flag('N', (self.x & 0x80) != 0);
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
         return 0;
     }
 
@@ -952,16 +1634,191 @@ impl CPU_6502{
      * Illegal Opcodes
      * 
      **********************************/
-    fn XXX(&mut self, op: u8) -> u8{
-        println!("Illegal Opcode: {}", op);
+    // Shared tail of the unstable AHX/SHX/SHY/TAS stores. The value is ANDed with
+    // the high byte of the un-indexed address plus one, and when indexing crossed
+    // a page the stored value also replaces the high byte of the target address.
+    fn store_and_high(&mut self, value: u8, index: u8){
+        let base: u16 = self.addr_abs.wrapping_sub(index as u16);
+        let data: u8 = value & ((base >> 8) as u8).wrapping_add(1);
+        if (base & 0xFF00) != (self.addr_abs & 0xFF00){
+            self.addr_abs = ((data as u16) << 8) | (self.addr_abs & 0x00FF);
+        }
+        self.write_this(self.addr_abs, data);
+    }
+    // Store A AND X AND (high byte + 1)
+    fn AHX(&mut self) -> u8{
+        self.store_and_high(self.accum & self.x, self.y);
+        return 0;
+    }
+    // AND Immediate then Logical Shift Right Accumulator
+    fn ALR(&mut self) -> u8{
+        self.fetch();
+        self.accum &= self.fetched;
+        self.set_flag('C', (self.accum & 0x01) != 0);
+        self.accum >>= 1;
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        return 0;
+    }
+    // AND Immediate, Carry copied from Negative
+    fn ANC(&mut self) -> u8{
+        self.fetch();
+        self.accum &= self.fetched;
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        self.set_flag('C', (self.accum & 0x80) != 0);
+        return 0;
+    }
+    // AND Immediate then Rotate Right Accumulator, Carry from bit 6 and Overflow from bit 6 XOR bit 5
+    fn ARR(&mut self) -> u8{
+        self.fetch();
+        self.accum = ((self.accum & self.fetched) >> 1) | (self.get_flag('C') << 7);
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        self.set_flag('C', (self.accum & 0x40) != 0);
+        self.set_flag('V', (((self.accum >> 6) ^ (self.accum >> 5)) & 0x01) != 0);
+        return 0;
+    }
+    // X = (A AND X) - Immediate, Carry set like CMP
+    fn AXS(&mut self) -> u8{
+        self.fetch();
+        let value: u8 = self.accum & self.x;
+        self.x = value.wrapping_sub(self.fetched);
+        self.set_flag('C', value >= self.fetched);
+        self.set_flag('Z', self.x == 0x00);
+        self.set_flag('N', (self.x & 0x80) != 0);
+        return 0;
+    }
+    // Decrement Memory then Compare Accumulator
+    fn DCP(&mut self) -> u8{
+        self.fetch();
+        self.fetched = self.fetched.wrapping_sub(1);
+        self.write_this(self.addr_abs, self.fetched);
+        let value: u8 = self.accum.wrapping_sub(self.fetched);
+        self.set_flag('C', self.accum >= self.fetched);
+        self.set_flag('Z', value == 0x00);
+        self.set_flag('N', (value & 0x80) != 0);
+        return 0;
+    }
+    // Increment Memory then Subtract with Borrow In
+    fn ISC(&mut self) -> u8{
+        self.fetch();
+        self.fetched = self.fetched.wrapping_add(1);
+        self.write_this(self.addr_abs, self.fetched);
+        self.add_with_carry(self.fetched ^ 0xFF);
+        return 0;
+    }
+    // Jams the CPU, nothing but a reset recovers from this
+    fn KIL(&mut self) -> u8{
+        self.halted = true;
+        return 0;
+    }
+    // A, X and Stack Pointer = Memory AND Stack Pointer
+    fn LAS(&mut self) -> u8{
+        self.fetch();
+        self.stkp &= self.fetched;
+        self.accum = self.stkp;
+        self.x = self.stkp;
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        return 1;
+    }
+    // Load Accumulator and X Register
+    fn LAX(&mut self) -> u8{
+        self.fetch();
+        if self.opcode == 0xAB{
+            // The immediate form mixes in the same unstable constant as XAA
+            self.accum = (self.accum | 0xEE) & self.fetched;
+        }else{
+            self.accum = self.fetched;
+        }
+        self.x = self.accum;
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        return 1;
+    }
+    // Rotate Memory Left then AND Accumulator
+    fn RLA(&mut self) -> u8{
+        self.fetch();
+        let value: u8 = (self.fetched << 1) | self.get_flag('C');
+        self.set_flag('C', (self.fetched & 0x80) != 0);
+        self.write_this(self.addr_abs, value);
+        self.accum &= value;
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        return 0;
+    }
+    // Rotate Memory Right then Add with Carry In
+    fn RRA(&mut self) -> u8{
+        self.fetch();
+        let value: u8 = (self.fetched >> 1) | (self.get_flag('C') << 7);
+        self.set_flag('C', (self.fetched & 0x01) != 0);
+        self.write_this(self.addr_abs, value);
+        self.add_with_carry(value);
+        return 0;
+    }
+    // Store A AND X
+    fn SAX(&mut self) -> u8{
+        self.write_this(self.addr_abs, self.accum & self.x);
+        return 0;
+    }
+    // Store X AND (high byte + 1)
+    fn SHX(&mut self) -> u8{
+        self.store_and_high(self.x, self.y);
         return 0;
     }
+    // Store Y AND (high byte + 1)
+    fn SHY(&mut self) -> u8{
+        self.store_and_high(self.y, self.x);
+        return 0;
+    }
+    // Arithmetic Shift Memory Left then OR Accumulator
+    fn SLO(&mut self) -> u8{
+        self.fetch();
+        let value: u8 = self.fetched << 1;
+        self.set_flag('C', (self.fetched & 0x80) != 0);
+        self.write_this(self.addr_abs, value);
+        self.accum |= value;
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        return 0;
+    }
+    // Logical Shift Memory Right then XOR Accumulator
+    fn SRE(&mut self) -> u8{
+        self.fetch();
+        let value: u8 = self.fetched >> 1;
+        self.set_flag('C', (self.fetched & 0x01) != 0);
+        self.write_this(self.addr_abs, value);
+        self.accum ^= value;
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        return 0;
+    }
+    // Stack Pointer = A AND X, then store Stack Pointer AND (high byte + 1)
+    fn TAS(&mut self) -> u8{
+        self.stkp = self.accum & self.x;
+        self.store_and_high(self.stkp, self.y);
+        return 0;
+    }
+    // A = (A OR magic constant) AND X AND Immediate
+    fn XAA(&mut self) -> u8{
+        self.fetch();
+        self.accum = (self.accum | 0xEE) & self.x & self.fetched;
+        self.set_flag('Z', self.accum == 0x00);
+        self.set_flag('N', (self.accum & 0x80) != 0);
+        return 0;
+    }
+
+    // Every opcode is in the table, so nothing decodes to this
+    fn XXX(&mut self, op: u8) -> u8{
+        unreachable!("opcode {:02X} has no operation", op);
+    }
 }
 
 // Sets up opcodes and cycles in a 16x16 array
 // Will clean up later
-#[derive(Copy, Clone)]
-enum Operation{
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operation{
     ADC, AND, ASL, BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS, CLC, CLD, CLI, CLV, CMP, CPX,
     CPY, DEC, DEX, DEY, EOR, INC, INX, INY, JMP, JSR, LDA, LDX, LDY, LSR, NOP, ORA, PHA, PHP, PLA,
     PLP, ROL, ROR, RTI, RTS, SBC, SEC, SED, SEI, STA, STX, STY, TAX, TAY, TSX, TXA, TXS, TYA,
@@ -970,8 +1827,8 @@ enum Operation{
     // Catches invalid instructions
     XXX
 }
-#[derive(Copy, Clone, PartialEq)]
-enum AddrMode{
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddrMode{
     IMM, ZP0, 
     ZPX, ZPY,
     ABS, ABX, 
@@ -979,9 +1836,37 @@ enum AddrMode{
     IZX, IZY,
     REL, IMP
 }
+// Cycles an addressing mode spends working out the address, after the
+// opcode fetch
+fn address_cycles(mode: AddrMode) -> u8{
+    match mode{
+        ZP0 => return 1,
+        ZPX | ZPY | ABS | ABX | ABY => return 2,
+        IZY => return 3,
+        _ => return 4
+    }
+}
+
+// What an instruction does with the address it works out, or what a single
+// cycle does on the bus (Read or Write only)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AccessKind{
+    Read,
+    Write,
+    Modify
+}
+
+fn access_kind(operation: Operation) -> AccessKind{
+    match operation{
+        STA | STX | STY | SAX | SHX | SHY | AHX | TAS => return AccessKind::Write,
+        ASL | LSR | ROL | ROR | INC | DEC | SLO | RLA | SRE | RRA | DCP | ISC => return AccessKind::Modify,
+        _ => return AccessKind::Read
+    }
+}
+
 use Operation::*;
 use AddrMode::*;
-struct Instruction(u8, AddrMode, Operation, u8);
+pub struct Instruction(u8, AddrMode, Operation, u8);
 impl Instruction {
     pub fn opcode(&self) -> u8 {
         self.0
@@ -996,21 +1881,393 @@ impl Instruction {
         self.3
     }
 }
-const INSTRUCTIONS: [Instruction; 256] = [
-    Instruction(0x00, IMM, BRK, 7), Instruction(0x01, IZX, ORA, 6), Instruction(0x02, IMP, XXX, 2), Instruction(0x03, IZX, SLO, 8), Instruction(0x04, ZP0, NOP, 3), Instruction(0x05, ZP0, ORA, 3), Instruction(0x06, ZP0, ASL, 5), Instruction(0x07, ZP0, SLO, 5), Instruction(0x08, IMP, PHP, 3), Instruction(0x09, IMM, ORA, 2), Instruction(0x0A, IMP, ASL, 2), Instruction(0x0B, IMM, ANC, 2), Instruction(0x0C, ABS, NOP, 4), Instruction(0x0D, ABS, ORA, 4), Instruction(0x0E, ABS, ASL, 6), Instruction(0x0F, ABS, SLO, 6),
-    Instruction(0x10, REL, BPL, 2), Instruction(0x11, IZY, ORA, 5), Instruction(0x12, IMP, XXX, 2), Instruction(0x13, IZY, SLO, 8), Instruction(0x14, ZPX, NOP, 4), Instruction(0x15, ZPX, ORA, 4), Instruction(0x16, ZPX, ASL, 6), Instruction(0x17, ZPX, SLO, 6), Instruction(0x18, IMP, CLC, 2), Instruction(0x19, ABY, ORA, 4), Instruction(0x1A, IMP, NOP, 2), Instruction(0x1B, ABY, SLO, 7), Instruction(0x1C, ABX, NOP, 4), Instruction(0x1D, ABX, ORA, 4), Instruction(0x1E, ABX, ASL, 7), Instruction(0x1F, ABX, SLO, 7),
-    Instruction(0x20, ABS, JSR, 6), Instruction(0x21, IZX, AND, 6), Instruction(0x22, IMP, XXX, 2), Instruction(0x23, IZX, RLA, 8), Instruction(0x24, ZP0, BIT, 3), Instruction(0x25, ZP0, AND, 3), Instruction(0x26, ZP0, ROL, 5), Instruction(0x27, ZP0, RLA, 5), Instruction(0x28, IMP, PLP, 4), Instruction(0x29, IMM, AND, 2), Instruction(0x2A, IMP, ROL, 2), Instruction(0x2B, IMM, ANC, 2), Instruction(0x2C, ABS, BIT, 4), Instruction(0x2D, ABS, AND, 4), Instruction(0x2E, ABS, ROL, 6), Instruction(0x2F, ABS, RLA, 6),
-    Instruction(0x30, REL, BMI, 2), Instruction(0x31, IZY, AND, 5), Instruction(0x32, IMP, XXX, 2), Instruction(0x33, IZY, RLA, 8), Instruction(0x34, ZPX, NOP, 4), Instruction(0x35, ZPX, AND, 4), Instruction(0x36, ZPX, ROL, 6), Instruction(0x37, ZPX, RLA, 6), Instruction(0x38, IMP, SEC, 2), Instruction(0x39, ABY, AND, 4), Instruction(0x3A, IMP, NOP, 2), Instruction(0x3B, ABY, RLA, 7), Instruction(0x3C, ABX, NOP, 4), Instruction(0x3D, ABX, AND, 4), Instruction(0x3E, ABX, ROL, 7), Instruction(0x3F, ABX, RLA, 7),
-    Instruction(0x40, IMP, RTI, 6), Instruction(0x41, IZX, EOR, 6), Instruction(0x42, IMP, XXX, 2), Instruction(0x43, IZX, SRE, 8), Instruction(0x44, ZP0, NOP, 3), Instruction(0x45, ZP0, EOR, 3), Instruction(0x46, ZP0, LSR, 5), Instruction(0x47, ZP0, SRE, 5), Instruction(0x48, IMP, PHA, 3), Instruction(0x49, IMM, EOR, 2), Instruction(0x4A, IMP, LSR, 2), Instruction(0x4B, IMM, ALR, 2), Instruction(0x4C, ABS, JMP, 3), Instruction(0x4D, ABS, EOR, 4), Instruction(0x4E, ABS, LSR, 6), Instruction(0x4F, ABS, SRE, 6),
-    Instruction(0x50, REL, BVC, 2), Instruction(0x51, IZY, EOR, 5), Instruction(0x52, IMP, XXX, 2), Instruction(0x53, IZY, SRE, 8), Instruction(0x54, ZPX, NOP, 4), Instruction(0x55, ZPX, EOR, 4), Instruction(0x56, ZPX, LSR, 6), Instruction(0x57, ZPX, SRE, 6), Instruction(0x58, IMP, CLI, 2), Instruction(0x59, ABY, EOR, 4), Instruction(0x5A, IMP, NOP, 2), Instruction(0x5B, ABY, SRE, 7), Instruction(0x5C, ABX, NOP, 4), Instruction(0x5D, ABX, EOR, 4), Instruction(0x5E, ABX, LSR, 7), Instruction(0x5F, ABX, SRE, 7),
-    Instruction(0x60, IMP, RTS, 6), Instruction(0x61, IZX, ADC, 6), Instruction(0x62, IMP, XXX, 2), Instruction(0x63, IZX, RRA, 8), Instruction(0x64, ZP0, NOP, 3), Instruction(0x65, ZP0, ADC, 3), Instruction(0x66, ZP0, ROR, 5), Instruction(0x67, ZP0, RRA, 5), Instruction(0x68, IMP, PLA, 4), Instruction(0x69, IMM, ADC, 2), Instruction(0x6A, IMP, ROR, 2), Instruction(0x6B, IMM, ARR, 2), Instruction(0x6C, IND, JMP, 5), Instruction(0x6D, ABS, ADC, 4), Instruction(0x6E, ABS, ROR, 6), Instruction(0x6F, ABS, RRA, 6),
-    Instruction(0x70, REL, BVS, 2), Instruction(0x71, IZY, ADC, 5), Instruction(0x72, IMP, XXX, 2), Instruction(0x73, IZY, RRA, 8), Instruction(0x74, ZPX, NOP, 4), Instruction(0x75, ZPX, ADC, 4), Instruction(0x76, ZPX, ROR, 6), Instruction(0x77, ZPX, RRA, 6), Instruction(0x78, IMP, SEI, 2), Instruction(0x79, ABY, ADC, 4), Instruction(0x7A, IMP, NOP, 2), Instruction(0x7B, ABY, RRA, 7), Instruction(0x7C, ABX, NOP, 4), Instruction(0x7D, ABX, ADC, 4), Instruction(0x7E, ABX, ROR, 7), Instruction(0x7F, ABX, RRA, 7),
+pub const INSTRUCTIONS: [Instruction; 256] = [
+    Instruction(0x00, IMP, BRK, 7), Instruction(0x01, IZX, ORA, 6), Instruction(0x02, IMP, KIL, 2), Instruction(0x03, IZX, SLO, 8), Instruction(0x04, ZP0, NOP, 3), Instruction(0x05, ZP0, ORA, 3), Instruction(0x06, ZP0, ASL, 5), Instruction(0x07, ZP0, SLO, 5), Instruction(0x08, IMP, PHP, 3), Instruction(0x09, IMM, ORA, 2), Instruction(0x0A, IMP, ASL, 2), Instruction(0x0B, IMM, ANC, 2), Instruction(0x0C, ABS, NOP, 4), Instruction(0x0D, ABS, ORA, 4), Instruction(0x0E, ABS, ASL, 6), Instruction(0x0F, ABS, SLO, 6),
+    Instruction(0x10, REL, BPL, 2), Instruction(0x11, IZY, ORA, 5), Instruction(0x12, IMP, KIL, 2), Instruction(0x13, IZY, SLO, 8), Instruction(0x14, ZPX, NOP, 4), Instruction(0x15, ZPX, ORA, 4), Instruction(0x16, ZPX, ASL, 6), Instruction(0x17, ZPX, SLO, 6), Instruction(0x18, IMP, CLC, 2), Instruction(0x19, ABY, ORA, 4), Instruction(0x1A, IMP, NOP, 2), Instruction(0x1B, ABY, SLO, 7), Instruction(0x1C, ABX, NOP, 4), Instruction(0x1D, ABX, ORA, 4), Instruction(0x1E, ABX, ASL, 7), Instruction(0x1F, ABX, SLO, 7),
+    Instruction(0x20, ABS, JSR, 6), Instruction(0x21, IZX, AND, 6), Instruction(0x22, IMP, KIL, 2), Instruction(0x23, IZX, RLA, 8), Instruction(0x24, ZP0, BIT, 3), Instruction(0x25, ZP0, AND, 3), Instruction(0x26, ZP0, ROL, 5), Instruction(0x27, ZP0, RLA, 5), Instruction(0x28, IMP, PLP, 4), Instruction(0x29, IMM, AND, 2), Instruction(0x2A, IMP, ROL, 2), Instruction(0x2B, IMM, ANC, 2), Instruction(0x2C, ABS, BIT, 4), Instruction(0x2D, ABS, AND, 4), Instruction(0x2E, ABS, ROL, 6), Instruction(0x2F, ABS, RLA, 6),
+    Instruction(0x30, REL, BMI, 2), Instruction(0x31, IZY, AND, 5), Instruction(0x32, IMP, KIL, 2), Instruction(0x33, IZY, RLA, 8), Instruction(0x34, ZPX, NOP, 4), Instruction(0x35, ZPX, AND, 4), Instruction(0x36, ZPX, ROL, 6), Instruction(0x37, ZPX, RLA, 6), Instruction(0x38, IMP, SEC, 2), Instruction(0x39, ABY, AND, 4), Instruction(0x3A, IMP, NOP, 2), Instruction(0x3B, ABY, RLA, 7), Instruction(0x3C, ABX, NOP, 4), Instruction(0x3D, ABX, AND, 4), Instruction(0x3E, ABX, ROL, 7), Instruction(0x3F, ABX, RLA, 7),
+    Instruction(0x40, IMP, RTI, 6), Instruction(0x41, IZX, EOR, 6), Instruction(0x42, IMP, KIL, 2), Instruction(0x43, IZX, SRE, 8), Instruction(0x44, ZP0, NOP, 3), Instruction(0x45, ZP0, EOR, 3), Instruction(0x46, ZP0, LSR, 5), Instruction(0x47, ZP0, SRE, 5), Instruction(0x48, IMP, PHA, 3), Instruction(0x49, IMM, EOR, 2), Instruction(0x4A, IMP, LSR, 2), Instruction(0x4B, IMM, ALR, 2), Instruction(0x4C, ABS, JMP, 3), Instruction(0x4D, ABS, EOR, 4), Instruction(0x4E, ABS, LSR, 6), Instruction(0x4F, ABS, SRE, 6),
+    Instruction(0x50, REL, BVC, 2), Instruction(0x51, IZY, EOR, 5), Instruction(0x52, IMP, KIL, 2), Instruction(0x53, IZY, SRE, 8), Instruction(0x54, ZPX, NOP, 4), Instruction(0x55, ZPX, EOR, 4), Instruction(0x56, ZPX, LSR, 6), Instruction(0x57, ZPX, SRE, 6), Instruction(0x58, IMP, CLI, 2), Instruction(0x59, ABY, EOR, 4), Instruction(0x5A, IMP, NOP, 2), Instruction(0x5B, ABY, SRE, 7), Instruction(0x5C, ABX, NOP, 4), Instruction(0x5D, ABX, EOR, 4), Instruction(0x5E, ABX, LSR, 7), Instruction(0x5F, ABX, SRE, 7),
+    Instruction(0x60, IMP, RTS, 6), Instruction(0x61, IZX, ADC, 6), Instruction(0x62, IMP, KIL, 2), Instruction(0x63, IZX, RRA, 8), Instruction(0x64, ZP0, NOP, 3), Instruction(0x65, ZP0, ADC, 3), Instruction(0x66, ZP0, ROR, 5), Instruction(0x67, ZP0, RRA, 5), Instruction(0x68, IMP, PLA, 4), Instruction(0x69, IMM, ADC, 2), Instruction(0x6A, IMP, ROR, 2), Instruction(0x6B, IMM, ARR, 2), Instruction(0x6C, IND, JMP, 5), Instruction(0x6D, ABS, ADC, 4), Instruction(0x6E, ABS, ROR, 6), Instruction(0x6F, ABS, RRA, 6),
+    Instruction(0x70, REL, BVS, 2), Instruction(0x71, IZY, ADC, 5), Instruction(0x72, IMP, KIL, 2), Instruction(0x73, IZY, RRA, 8), Instruction(0x74, ZPX, NOP, 4), Instruction(0x75, ZPX, ADC, 4), Instruction(0x76, ZPX, ROR, 6), Instruction(0x77, ZPX, RRA, 6), Instruction(0x78, IMP, SEI, 2), Instruction(0x79, ABY, ADC, 4), Instruction(0x7A, IMP, NOP, 2), Instruction(0x7B, ABY, RRA, 7), Instruction(0x7C, ABX, NOP, 4), Instruction(0x7D, ABX, ADC, 4), Instruction(0x7E, ABX, ROR, 7), Instruction(0x7F, ABX, RRA, 7),
     Instruction(0x80, IMM, NOP, 2), Instruction(0x81, IZX, STA, 6), Instruction(0x82, IMM, NOP, 2), Instruction(0x83, IZX, SAX, 6), Instruction(0x84, ZP0, STY, 3), Instruction(0x85, ZP0, STA, 3), Instruction(0x86, ZP0, STX, 3), Instruction(0x87, ZP0, SAX, 3), Instruction(0x88, IMP, DEY, 2), Instruction(0x89, IMM, NOP, 2), Instruction(0x8A, IMP, TXA, 2), Instruction(0x8B, IMM, XAA, 2), Instruction(0x8C, ABS, STY, 4), Instruction(0x8D, ABS, STA, 4), Instruction(0x8E, ABS, STX, 4), Instruction(0x8F, ABS, SAX, 4),
-    Instruction(0x90, REL, BCC, 2), Instruction(0x91, IZY, STA, 6), Instruction(0x92, IMP, XXX, 2), Instruction(0x93, IZY, AHX, 6), Instruction(0x94, ZPX, STY, 4), Instruction(0x95, ZPX, STA, 4), Instruction(0x96, ZPY, STX, 4), Instruction(0x97, ZPY, SAX, 4), Instruction(0x98, IMP, TYA, 2), Instruction(0x99, ABY, STA, 5), Instruction(0x9A, IMP, TXS, 2), Instruction(0x9B, ABY, TAS, 5), Instruction(0x9C, ABX, SHY, 5), Instruction(0x9D, ABX, STA, 5), Instruction(0x9E, ABY, SHX, 5), Instruction(0x9F, ABY, AHX, 5),
+    Instruction(0x90, REL, BCC, 2), Instruction(0x91, IZY, STA, 6), Instruction(0x92, IMP, KIL, 2), Instruction(0x93, IZY, AHX, 6), Instruction(0x94, ZPX, STY, 4), Instruction(0x95, ZPX, STA, 4), Instruction(0x96, ZPY, STX, 4), Instruction(0x97, ZPY, SAX, 4), Instruction(0x98, IMP, TYA, 2), Instruction(0x99, ABY, STA, 5), Instruction(0x9A, IMP, TXS, 2), Instruction(0x9B, ABY, TAS, 5), Instruction(0x9C, ABX, SHY, 5), Instruction(0x9D, ABX, STA, 5), Instruction(0x9E, ABY, SHX, 5), Instruction(0x9F, ABY, AHX, 5),
     Instruction(0xA0, IMM, LDY, 2), Instruction(0xA1, IZX, LDA, 6), Instruction(0xA2, IMM, LDX, 2), Instruction(0xA3, IZX, LAX, 6), Instruction(0xA4, ZP0, LDY, 3), Instruction(0xA5, ZP0, LDA, 3), Instruction(0xA6, ZP0, LDX, 3), Instruction(0xA7, ZP0, LAX, 3), Instruction(0xA8, IMP, TAY, 2), Instruction(0xA9, IMM, LDA, 2), Instruction(0xAA, IMP, TAX, 2), Instruction(0xAB, IMM, LAX, 2), Instruction(0xAC, ABS, LDY, 4), Instruction(0xAD, ABS, LDA, 4), Instruction(0xAE, ABS, LDX, 4), Instruction(0xAF, ABS, LAX, 4),
-    Instruction(0xB0, REL, BCS, 2), Instruction(0xB1, IZY, LDA, 5), Instruction(0xB2, IMP, XXX, 2), Instruction(0xB3, IZY, LAX, 5), Instruction(0xB4, ZPX, LDY, 4), Instruction(0xB5, ZPX, LDA, 4), Instruction(0xB6, ZPY, LDX, 4), Instruction(0xB7, ZPY, LAX, 4), Instruction(0xB8, IMP, CLV, 2), Instruction(0xB9, ABY, LDA, 4), Instruction(0xBA, IMP, TSX, 2), Instruction(0xBB, ABY, LAS, 4), Instruction(0xBC, ABX, LDY, 4), Instruction(0xBD, ABX, LDA, 4), Instruction(0xBE, ABY, LDX, 4), Instruction(0xBF, ABY, LAX, 4),
+    Instruction(0xB0, REL, BCS, 2), Instruction(0xB1, IZY, LDA, 5), Instruction(0xB2, IMP, KIL, 2), Instruction(0xB3, IZY, LAX, 5), Instruction(0xB4, ZPX, LDY, 4), Instruction(0xB5, ZPX, LDA, 4), Instruction(0xB6, ZPY, LDX, 4), Instruction(0xB7, ZPY, LAX, 4), Instruction(0xB8, IMP, CLV, 2), Instruction(0xB9, ABY, LDA, 4), Instruction(0xBA, IMP, TSX, 2), Instruction(0xBB, ABY, LAS, 4), Instruction(0xBC, ABX, LDY, 4), Instruction(0xBD, ABX, LDA, 4), Instruction(0xBE, ABY, LDX, 4), Instruction(0xBF, ABY, LAX, 4),
     Instruction(0xC0, IMM, CPY, 2), Instruction(0xC1, IZX, CMP, 6), Instruction(0xC2, IMM, NOP, 2), Instruction(0xC3, IZX, DCP, 8), Instruction(0xC4, ZP0, CPY, 3), Instruction(0xC5, ZP0, CMP, 3), Instruction(0xC6, ZP0, DEC, 5), Instruction(0xC7, ZP0, DCP, 5), Instruction(0xC8, IMP, INY, 2), Instruction(0xC9, IMM, CMP, 2), Instruction(0xCA, IMP, DEX, 2), Instruction(0xCB, IMM, AXS, 2), Instruction(0xCC, ABS, CPY, 4), Instruction(0xCD, ABS, CMP, 4), Instruction(0xCE, ABS, DEC, 6), Instruction(0xCF, ABS, DCP, 6),
-    Instruction(0xD0, REL, BNE, 2), Instruction(0xD1, IZY, CMP, 5), Instruction(0xD2, IMP, XXX, 2), Instruction(0xD3, IZY, DCP, 8), Instruction(0xD4, ZPX, NOP, 4), Instruction(0xD5, ZPX, CMP, 4), Instruction(0xD6, ZPX, DEC, 6), Instruction(0xD7, ZPX, DCP, 6), Instruction(0xD8, IMP, CLD, 2), Instruction(0xD9, ABY, CMP, 4), Instruction(0xDA, IMP, NOP, 2), Instruction(0xDB, ABY, DCP, 7), Instruction(0xDC, ABX, NOP, 4), Instruction(0xDD, ABX, CMP, 4), Instruction(0xDE, ABX, DEC, 7), Instruction(0xDF, ABX, DCP, 7),
+    Instruction(0xD0, REL, BNE, 2), Instruction(0xD1, IZY, CMP, 5), Instruction(0xD2, IMP, KIL, 2), Instruction(0xD3, IZY, DCP, 8), Instruction(0xD4, ZPX, NOP, 4), Instruction(0xD5, ZPX, CMP, 4), Instruction(0xD6, ZPX, DEC, 6), Instruction(0xD7, ZPX, DCP, 6), Instruction(0xD8, IMP, CLD, 2), Instruction(0xD9, ABY, CMP, 4), Instruction(0xDA, IMP, NOP, 2), Instruction(0xDB, ABY, DCP, 7), Instruction(0xDC, ABX, NOP, 4), Instruction(0xDD, ABX, CMP, 4), Instruction(0xDE, ABX, DEC, 7), Instruction(0xDF, ABX, DCP, 7),
     Instruction(0xE0, IMM, CPX, 2), Instruction(0xE1, IZX, SBC, 6), Instruction(0xE2, IMM, NOP, 2), Instruction(0xE3, IZX, ISC, 8), Instruction(0xE4, ZP0, CPX, 3), Instruction(0xE5, ZP0, SBC, 3), Instruction(0xE6, ZP0, INC, 5), Instruction(0xE7, ZP0, ISC, 5), Instruction(0xE8, IMP, INX, 2), Instruction(0xE9, IMM, SBC, 2), Instruction(0xEA, IMP, NOP, 2), Instruction(0xEB, IMM, SBC, 2), Instruction(0xEC, ABS, CPX, 4), Instruction(0xED, ABS, SBC, 4), Instruction(0xEE, ABS, INC, 6), Instruction(0xEF, ABS, ISC, 6),
-    Instruction(0xF0, REL, BEQ, 2), Instruction(0xF1, IZY, SBC, 5), Instruction(0xF2, IMP, XXX, 2), Instruction(0xF3, IZY, ISC, 8), Instruction(0xF4, ZPX, NOP, 4), Instruction(0xF5, ZPX, SBC, 4), Instruction(0xF6, ZPX, INC, 6), Instruction(0xF7, ZPX, ISC, 6), Instruction(0xF8, IMP, SED, 2), Instruction(0xF9, ABY, SBC, 4), Instruction(0xFA, IMP, NOP, 2), Instruction(0xFB, ABY, ISC, 7), Instruction(0xFC, ABX, NOP, 4), Instruction(0xFD, ABX, SBC, 4), Instruction(0xFE, ABX, INC, 7), Instruction(0xFF, ABX, ISC, 7),
-];
\ No newline at end of file
+    Instruction(0xF0, REL, BEQ, 2), Instruction(0xF1, IZY, SBC, 5), Instruction(0xF2, IMP, KIL, 2), Instruction(0xF3, IZY, ISC, 8), Instruction(0xF4, ZPX, NOP, 4), Instruction(0xF5, ZPX, SBC, 4), Instruction(0xF6, ZPX, INC, 6), Instruction(0xF7, ZPX, ISC, 6), Instruction(0xF8, IMP, SED, 2), Instruction(0xF9, ABY, SBC, 4), Instruction(0xFA, IMP, NOP, 2), Instruction(0xFB, ABY, ISC, 7), Instruction(0xFC, ABX, NOP, 4), Instruction(0xFD, ABX, SBC, 4), Instruction(0xFE, ABX, INC, 7), Instruction(0xFF, ABX, ISC, 7),
+];
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::memory::{Access, FlatMemory};
+
+    fn flat_cpu(program: &[u8]) -> CPU_6502<FlatMemory>{
+        let mut memory = FlatMemory::new();
+        for (i, byte) in program.iter().enumerate(){
+            memory.load(0x8000 + i as u16, *byte);
+        }
+        memory.load(0xFFFC, 0x00);
+        memory.load(0xFFFD, 0x80);
+        let mut cpu = CPU_6502::new(memory);
+        cpu.reset();
+        while !cpu.complete(){
+            cpu.clock();
+        }
+        return cpu;
+    }
+
+    fn step(cpu: &mut CPU_6502<FlatMemory>){
+        cpu.clock();
+        while !cpu.complete(){
+            cpu.clock();
+        }
+    }
+
+    fn reads_and_writes(cpu: &mut CPU_6502<FlatMemory>) -> Vec<(Access, u16, u8)>{
+        return cpu.bus_mut().take_log().iter()
+            .map(|access| (access.access, access.addr, access.data))
+            .collect();
+    }
+
+    // Steps an instruction, or an interrupt sequence, with the lines held
+    // where they are and polled after every cycle like the Nes does
+    fn step_polled(cpu: &mut CPU_6502<FlatMemory>, irq: bool, nmi: bool){
+        loop{
+            cpu.clock();
+            cpu.set_irq_line(irq);
+            cpu.set_nmi_line(nmi);
+            cpu.poll_interrupts();
+            if cpu.complete(){
+                return;
+            }
+        }
+    }
+
+    // A cycle-stepped CPU with the NMI handler at $A000 and IRQ at $9000
+    fn interrupt_cpu(program: &[u8], status: u8) -> CPU_6502<FlatMemory>{
+        let mut cpu = flat_cpu(program);
+        cpu.set_cycle_stepped(true);
+        cpu.set_status(status);
+        cpu.bus_mut().load(0xFFFA, 0x00);
+        cpu.bus_mut().load(0xFFFB, 0xA0);
+        cpu.bus_mut().load(0xFFFE, 0x00);
+        cpu.bus_mut().load(0xFFFF, 0x90);
+        return cpu;
+    }
+
+    // Runs one instruction in both the instruction-stepped and cycle-stepped
+    // modes, with A, X, P and $10 set up first
+    fn run_both(program: &[u8], a: u8, x: u8, status: u8, zp: u8) -> Vec<CPU_6502<FlatMemory>>{
+        let mut cpus = Vec::new();
+        for &stepped in [false, true].iter(){
+            let mut cpu = flat_cpu(program);
+            cpu.set_cycle_stepped(stepped);
+            cpu.set_a(a);
+            cpu.set_x(x);
+            cpu.set_status(status);
+            cpu.bus_mut().load(0x0010, zp);
+            // A jammed CPU never completes its instruction
+            cpu.clock();
+            while !cpu.complete() && !cpu.halted(){
+                cpu.clock();
+            }
+            cpus.push(cpu);
+        }
+        return cpus;
+    }
+
+    #[test]
+    fn arr_sets_carry_and_overflow_from_bits_6_and_5(){
+        // (A, operand, P in, A out, P out)
+        let cases = [
+            (0xFF, 0xFF, 0x25, 0xFF, 0xA5),
+            (0xFF, 0x40, 0x24, 0x20, 0x64),
+            (0xFF, 0x80, 0x24, 0x40, 0x65),
+            (0xFF, 0x01, 0x24, 0x00, 0x26)
+        ];
+        for &(a, operand, status, result, flags) in cases.iter(){
+            for cpu in run_both(&[0x6B, operand], a, 0, status, 0){
+                assert_eq!((cpu.a(), cpu.status()), (result, flags), "ARR #${:02X} with A=${:02X}", operand, a);
+            }
+        }
+    }
+
+    #[test]
+    fn axs_subtracts_from_a_and_x_without_borrow(){
+        // (A, X, operand, P in, X out, P out); carry and decimal don't matter
+        let cases = [
+            (0xF0, 0x3C, 0x10, 0x24, 0x20, 0x25),
+            (0xFF, 0x01, 0x02, 0x2D, 0xFF, 0xAC),
+            (0x0F, 0xFF, 0x0F, 0x24, 0x00, 0x27)
+        ];
+        for &(a, x, operand, status, result, flags) in cases.iter(){
+            for cpu in run_both(&[0xCB, operand], a, x, status, 0){
+                assert_eq!((cpu.a(), cpu.x(), cpu.status()), (a, result, flags));
+            }
+        }
+    }
+
+    #[test]
+    fn read_modify_write_combos(){
+        // (opcode, A, P in, $10 in, A out, P out, $10 out)
+        let cases = [
+            (0xC7, 0x40, 0x24, 0x41, 0x40, 0x27, 0x40), // DCP
+            (0xE7, 0x20, 0x25, 0x0F, 0x10, 0x25, 0x10), // ISC
+            (0x07, 0x01, 0x24, 0x81, 0x03, 0x25, 0x02), // SLO
+            (0x27, 0xFF, 0x25, 0x80, 0x01, 0x25, 0x01), // RLA
+            (0x47, 0x01, 0x24, 0x03, 0x00, 0x27, 0x01), // SRE
+            (0x67, 0x01, 0x25, 0x02, 0x82, 0xA4, 0x81)  // RRA
+        ];
+        for &(opcode, a, status, zp, result, flags, memory) in cases.iter(){
+            for cpu in run_both(&[opcode, 0x10], a, 0, status, zp){
+                assert_eq!((cpu.a(), cpu.status(), cpu.bus().peek(0x0010)), (result, flags, memory), "opcode ${:02X}", opcode);
+                assert_eq!(cpu.clock_count(), 7 + 5);
+            }
+        }
+    }
+
+    #[test]
+    fn lax_sax_anc_and_alr(){
+        for cpu in run_both(&[0xA7, 0x10], 0, 0, 0x24, 0x80){
+            assert_eq!((cpu.a(), cpu.x(), cpu.status()), (0x80, 0x80, 0xA4));
+        }
+        for cpu in run_both(&[0x87, 0x10], 0xF0, 0x3C, 0x24, 0){
+            assert_eq!(cpu.bus().peek(0x0010), 0x30);
+        }
+        // ANC copies N into C
+        for cpu in run_both(&[0x0B, 0x80], 0xFF, 0, 0x24, 0){
+            assert_eq!((cpu.a(), cpu.status()), (0x80, 0xA5));
+        }
+        for cpu in run_both(&[0x4B, 0x03], 0xFF, 0, 0x24, 0){
+            assert_eq!((cpu.a(), cpu.status()), (0x01, 0x25));
+        }
+    }
+
+    #[test]
+    fn kil_jams_until_reset(){
+        for cpu in run_both(&[0x02, 0xE8], 0, 0, 0x24, 0).iter_mut(){
+            assert!(cpu.halted());
+            let pc = cpu.pc();
+            for _ in 0..20{
+                cpu.clock();
+            }
+            assert_eq!((cpu.pc(), cpu.x()), (pc, 0));
+            cpu.reset();
+            assert!(!cpu.halted());
+        }
+    }
+
+    #[test]
+    fn cli_delays_irq_by_one_instruction(){
+        // CLI, NOP, NOP
+        let mut cpu = interrupt_cpu(&[0x58, 0xEA, 0xEA], 0x24);
+        step_polled(&mut cpu, true, false);
+        assert!(!cpu.interrupt_pending());
+        step_polled(&mut cpu, true, false);
+        assert_eq!(cpu.pc(), 0x8002);
+        assert!(cpu.interrupt_pending());
+        step_polled(&mut cpu, true, false);
+        assert_eq!(cpu.pc(), 0x9000);
+        // Return address, then status with B clear
+        assert_eq!(cpu.bus().peek(0x01FD), 0x80);
+        assert_eq!(cpu.bus().peek(0x01FC), 0x02);
+        assert_eq!(cpu.bus().peek(0x01FB), 0x20);
+        assert_eq!(cpu.status() & FLAGS_6502('I'), FLAGS_6502('I'));
+    }
+
+    #[test]
+    fn sei_still_takes_a_pending_irq(){
+        // SEI, NOP
+        let mut cpu = interrupt_cpu(&[0x78, 0xEA], 0x20);
+        step_polled(&mut cpu, true, false);
+        assert!(cpu.interrupt_pending());
+        step_polled(&mut cpu, true, false);
+        assert_eq!(cpu.pc(), 0x9000);
+        // Pushed with I already set by SEI
+        assert_eq!(cpu.bus().peek(0x01FB), 0x24);
+    }
+
+    #[test]
+    fn irq_sequence_takes_seven_cycles(){
+        let mut cpu = interrupt_cpu(&[0xEA, 0xEA], 0x20);
+        step_polled(&mut cpu, true, false);
+        cpu.bus_mut().take_log();
+        let start = cpu.clock_count();
+        step_polled(&mut cpu, true, false);
+        assert_eq!(cpu.clock_count() - start, 7);
+        assert_eq!(reads_and_writes(&mut cpu), vec![
+            (Access::Read, 0x8001, 0xEA), (Access::Read, 0x8001, 0xEA),
+            (Access::Write, 0x01FD, 0x80), (Access::Write, 0x01FC, 0x01), (Access::Write, 0x01FB, 0x20),
+            (Access::Read, 0xFFFE, 0x00), (Access::Read, 0xFFFF, 0x90)
+        ]);
+    }
+
+    #[test]
+    fn taken_branch_delays_a_new_irq(){
+        // BNE +0, NOP with Z clear
+        let mut cpu = interrupt_cpu(&[0xD0, 0x00, 0xEA], 0x20);
+        // The line goes low once the opcode has been fetched
+        cpu.clock();
+        cpu.poll_interrupts();
+        step_polled(&mut cpu, true, false);
+        assert_eq!(cpu.pc(), 0x8002);
+        assert!(!cpu.interrupt_pending());
+        step_polled(&mut cpu, true, false);
+        assert_eq!(cpu.pc(), 0x8003);
+        step_polled(&mut cpu, true, false);
+        assert_eq!(cpu.pc(), 0x9000);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk(){
+        let mut cpu = interrupt_cpu(&[0x00, 0x00], 0x24);
+        // NMI arrives after BRK has pushed the return address
+        for _ in 0..3{
+            cpu.clock();
+            cpu.poll_interrupts();
+        }
+        step_polled(&mut cpu, false, true);
+        assert_eq!(cpu.pc(), 0xA000);
+        // Still BRK's push, B set and returning past the padding byte
+        assert_eq!(cpu.bus().peek(0x01FC), 0x02);
+        assert_eq!(cpu.bus().peek(0x01FB), 0x34);
+        // and the NMI has been used up
+        assert!(!cpu.interrupt_pending());
+    }
+
+    #[test]
+    fn nmi_fires_once_per_edge(){
+        let mut cpu = interrupt_cpu(&[0xEA; 16], 0x24);
+        cpu.bus_mut().load(0xA000, 0xEA);
+        cpu.bus_mut().load(0xA001, 0xEA);
+        cpu.bus_mut().load(0xA002, 0xEA);
+        step_polled(&mut cpu, false, true);
+        step_polled(&mut cpu, false, true);
+        assert_eq!(cpu.pc(), 0xA000);
+        for _ in 0..3{
+            step_polled(&mut cpu, false, true);
+        }
+        assert_eq!(cpu.pc(), 0xA003);
+        assert_eq!(cpu.stkp(), 0xFA);
+    }
+
+    #[test]
+    fn indexed_read_across_a_page_reads_the_unfixed_address(){
+        // LDX #$10, LDA $20F8,X
+        let mut cpu = flat_cpu(&[0xA2, 0x10, 0xBD, 0xF8, 0x20]);
+        cpu.set_cycle_stepped(true);
+        step(&mut cpu);
+        cpu.bus_mut().take_log();
+        step(&mut cpu);
+        assert_eq!(reads_and_writes(&mut cpu), vec![
+            (Access::Read, 0x8002, 0xBD), (Access::Read, 0x8003, 0xF8), (Access::Read, 0x8004, 0x20),
+            (Access::Read, 0x2008, 0x00), (Access::Read, 0x2108, 0x00)
+        ]);
+    }
+
+    #[test]
+    fn read_modify_write_writes_twice(){
+        // INC $10
+        let mut cpu = flat_cpu(&[0xE6, 0x10]);
+        cpu.set_cycle_stepped(true);
+        cpu.bus_mut().load(0x0010, 0x05);
+        cpu.bus_mut().take_log();
+        step(&mut cpu);
+        assert_eq!(reads_and_writes(&mut cpu), vec![
+            (Access::Read, 0x8000, 0xE6), (Access::Read, 0x8001, 0x10), (Access::Read, 0x0010, 0x05),
+            (Access::Write, 0x0010, 0x05), (Access::Write, 0x0010, 0x06)
+        ]);
+    }
+
+    #[test]
+    fn taken_branch_across_a_page_reads_pc_twice(){
+        // BNE +$10 from $80F0 with Z clear
+        let mut cpu = flat_cpu(&[]);
+        cpu.set_cycle_stepped(true);
+        cpu.set_pc(0x80F0);
+        cpu.set_status(0x24);
+        cpu.bus_mut().load(0x80F0, 0xD0);
+        cpu.bus_mut().load(0x80F1, 0x10);
+        cpu.bus_mut().take_log();
+        step(&mut cpu);
+        assert_eq!(cpu.pc(), 0x8102);
+        let reads: Vec<u16> = reads_and_writes(&mut cpu).iter().map(|access| access.1).collect();
+        assert_eq!(reads, vec![0x80F0, 0x80F1, 0x80F2, 0x8002]);
+    }
+
+    // Every opcode ends up in the same place whichever way it is run
+    #[test]
+    fn cycle_stepped_matches_whole_instructions(){
+        let mut seed: u32 = 0x1234_5678;
+        let mut random = move ||{
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            return (seed >> 16) as u8;
+        };
+        let mut background = FlatMemory::new();
+        for addr in 0..=0xFFFFu16{
+            background.load(addr, random());
+        }
+        for opcode in 0..=0xFFu8{
+            if INSTRUCTIONS[opcode as usize].oper() == KIL{
+                continue;
+            }
+            for _ in 0..16{
+                let pc = ((random() as u16) << 8) | random() as u16;
+                let registers = [random(), random(), random(), random() | 0x20, random()];
+                let operands = [random(), random()];
+
+                let run = |cycle_stepped: bool|{
+                    let mut cpu = CPU_6502::new(background.clone());
+                    cpu.bus_mut().load(pc, opcode);
+                    cpu.bus_mut().load(pc.wrapping_add(1), operands[0]);
+                    cpu.bus_mut().load(pc.wrapping_add(2), operands[1]);
+                    cpu.set_cycle_stepped(cycle_stepped);
+                    cpu.set_pc(pc);
+                    cpu.set_a(registers[0]);
+                    cpu.set_x(registers[1]);
+                    cpu.set_y(registers[2]);
+                    cpu.set_status(registers[3]);
+                    cpu.set_stkp(registers[4]);
+                    let start = cpu.clock_count();
+                    step(&mut cpu);
+                    let writes: Vec<(u16, u8)> = cpu.bus_mut().take_log().iter()
+                        .filter(|access| access.access == Access::Write)
+                        .map(|access| (access.addr, cpu.bus().peek(access.addr)))
+                        .collect();
+                    return (cpu.pc(), cpu.a(), cpu.x(), cpu.y(), cpu.status(), cpu.stkp(),
+                        cpu.clock_count() - start, writes);
+                };
+                let (whole, stepped) = (run(false), run(true));
+                // The stepped run also makes the dummy write, so only the
+                // final value of each address counts
+                let settled = |writes: &Vec<(u16, u8)>| -> Vec<(u16, u8)>{
+                    let mut settled: Vec<(u16, u8)> = writes.clone();
+                    settled.sort_unstable();
+                    settled.dedup();
+                    return settled;
+                };
+                assert_eq!((whole.0, whole.1, whole.2, whole.3, whole.4, whole.5, whole.6, settled(&whole.7)),
+                    (stepped.0, stepped.1, stepped.2, stepped.3, stepped.4, stepped.5, stepped.6, settled(&stepped.7)),
+                    "opcode {:02X} at {:04X}", opcode, pc);
+            }
+        }
+    }
+
+    #[test]
+    fn runs_on_any_memory(){
+        // LDA #$42, STA $0200
+        let mut cpu = flat_cpu(&[0xA9, 0x42, 0x8D, 0x00, 0x02]);
+        cpu.bus_mut().take_log();
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.bus().peek(0x0200), 0x42);
+        let writes: Vec<(u16, u8)> = cpu.bus_mut().take_log().iter()
+            .filter(|access| access.access == Access::Write)
+            .map(|access| (access.addr, access.data))
+            .collect();
+        assert_eq!(writes, vec![(0x0200, 0x42)]);
+    }
+}
@@ -0,0 +1,235 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+use crate::cpu::{AddrMode, Operation, INSTRUCTIONS};
+use crate::memory::Memory;
+
+// Turns machine code back into text, in the syntax ca65 accepts with
+// .setcpu "6502X":
+//
+// C72F  B0 04     BCS $C735
+// C731  BD 12 00  LDA a:$0012,X
+//
+// Decoding only ever peeks, so it is safe on live memory.
+
+// One decoded instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoded{
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub operation: Operation,
+    pub mode: AddrMode,
+    // Where a branch or jump goes, when that is known without running it
+    pub target: Option<u16>,
+    // Not one of the 151 documented opcodes
+    pub unofficial: bool
+}
+
+// Bytes taken by an instruction in a given mode, opcode included
+pub fn length(mode: AddrMode) -> u16{
+    match mode{
+        AddrMode::IMP => return 1,
+        AddrMode::ABS | AddrMode::ABX | AddrMode::ABY | AddrMode::IND => return 3,
+        _ => return 2
+    }
+}
+
+pub fn is_unofficial(opcode: u8, operation: Operation) -> bool{
+    use Operation::*;
+    match operation{
+        NOP => return opcode != 0xEA,
+        SBC => return opcode == 0xEB,
+        AHX | ALR | ANC | ARR | AXS | DCP | ISC | KIL | LAS | LAX | RLA | RRA | SAX
+            | SHX | SHY | SLO | SRE | TAS | XAA | XXX => return true,
+        _ => return false
+    }
+}
+
+// ca65's name for an operation, where it differs from ours
+pub fn mnemonic(operation: Operation) -> String{
+    match operation{
+        Operation::AHX => return String::from("SHA"),
+        Operation::KIL => return String::from("JAM"),
+        Operation::XAA => return String::from("ANE"),
+        _ => return format!("{:?}", operation)
+    }
+}
+
+// Decodes the instruction at addr, with read giving the bytes. Operands
+// wrap around the top of memory like the CPU's fetches do.
+pub fn decode<F: Fn(u16) -> u8>(addr: u16, read: F) -> Decoded{
+    let opcode = read(addr);
+    let instruction = &INSTRUCTIONS[opcode as usize];
+    let mode = instruction.addr_mode();
+    let operation = instruction.oper();
+    let bytes: Vec<u8> = (0..length(mode)).map(|i| read(addr.wrapping_add(i))).collect();
+
+    let target = match (mode, operation){
+        (AddrMode::REL, _) => Some(addr.wrapping_add(2).wrapping_add(bytes[1] as i8 as u16)),
+        (AddrMode::ABS, Operation::JMP) | (AddrMode::ABS, Operation::JSR) => Some(word(&bytes)),
+        _ => None
+    };
+
+    return Decoded{ addr, bytes, operation, mode, target, unofficial: is_unofficial(opcode, operation) };
+}
+
+// Decodes straight from memory
+pub fn decode_at<M: Memory>(memory: &M, addr: u16) -> Decoded{
+    return decode(addr, |a| memory.peek(a));
+}
+
+// Decodes code in a byte slice loaded at origin. Bytes past the end of
+// the slice read as zero.
+pub fn decode_bytes(code: &[u8], origin: u16, addr: u16) -> Decoded{
+    return decode(addr, |a| {
+        let offset = a.wrapping_sub(origin) as usize;
+        return *code.get(offset).unwrap_or(&0);
+    });
+}
+
+// Every instruction that starts between start and end inclusive, one after
+// the other. Stops rather than wrapping past $FFFF.
+pub fn disassemble<M: Memory>(memory: &M, start: u16, end: u16) -> Vec<Decoded>{
+    let mut lines = Vec::new();
+    let mut addr = start as u32;
+    while addr <= end as u32{
+        let decoded = decode_at(memory, addr as u16);
+        addr += decoded.bytes.len() as u32;
+        lines.push(decoded);
+    }
+    return lines;
+}
+
+fn word(bytes: &[u8]) -> u16{
+    return ((bytes[2] as u16) << 8) | bytes[1] as u16;
+}
+
+impl Decoded{
+    pub fn opcode(&self) -> u8{
+        return self.bytes[0];
+    }
+
+    pub fn mnemonic(&self) -> String{
+        return mnemonic(self.operation);
+    }
+
+    // The operand as ca65 wants it, empty for implied instructions
+    pub fn operand(&self) -> String{
+        let bytes = &self.bytes;
+        match self.mode{
+            AddrMode::IMP => {
+                match self.operation{
+                    Operation::ASL | Operation::LSR | Operation::ROL | Operation::ROR => return String::from("A"),
+                    _ => return String::new()
+                }
+            }
+            AddrMode::IMM => return format!("#${:02X}", bytes[1]),
+            AddrMode::ZP0 => return format!("${:02X}", bytes[1]),
+            AddrMode::ZPX => return format!("${:02X},X", bytes[1]),
+            AddrMode::ZPY => return format!("${:02X},Y", bytes[1]),
+            AddrMode::ABS => return format!("{}${:04X}", self.force_absolute(), word(bytes)),
+            AddrMode::ABX => return format!("{}${:04X},X", self.force_absolute(), word(bytes)),
+            AddrMode::ABY => return format!("{}${:04X},Y", self.force_absolute(), word(bytes)),
+            AddrMode::IND => return format!("(${:04X})", word(bytes)),
+            AddrMode::IZX => return format!("(${:02X},X)", bytes[1]),
+            AddrMode::IZY => return format!("(${:02X}),Y", bytes[1]),
+            AddrMode::REL => return format!("${:04X}", self.target.unwrap_or(0))
+        }
+    }
+
+    // ca65 picks zero page for any address that fits, so an absolute
+    // instruction aimed there needs the a: prefix to assemble back the same
+    fn force_absolute(&self) -> &'static str{
+        if word(&self.bytes) < 0x100{
+            return "a:";
+        }
+        return "";
+    }
+
+    // Address, bytes and source, as a debugger would list it
+    pub fn listing(&self) -> String{
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        return format!("{:04X}  {:<8}  {}", self.addr, bytes.join(" "), self);
+    }
+}
+
+impl fmt::Display for Decoded{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        let operand = self.operand();
+        if operand.is_empty(){
+            return write!(f, "{}", self.mnemonic());
+        }
+        return write!(f, "{} {}", self.mnemonic(), operand);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::memory::FlatMemory;
+
+    fn text(code: &[u8]) -> String{
+        return decode_bytes(code, 0xC000, 0xC000).to_string();
+    }
+
+    #[test]
+    fn formats_every_mode(){
+        assert_eq!(text(&[0xEA]), "NOP");
+        assert_eq!(text(&[0x0A]), "ASL A");
+        assert_eq!(text(&[0xA9, 0x7F]), "LDA #$7F");
+        assert_eq!(text(&[0xA5, 0x10]), "LDA $10");
+        assert_eq!(text(&[0xB5, 0x10]), "LDA $10,X");
+        assert_eq!(text(&[0xB6, 0x10]), "LDX $10,Y");
+        assert_eq!(text(&[0xAD, 0x34, 0x12]), "LDA $1234");
+        assert_eq!(text(&[0xBD, 0x34, 0x12]), "LDA $1234,X");
+        assert_eq!(text(&[0xB9, 0x34, 0x12]), "LDA $1234,Y");
+        assert_eq!(text(&[0x6C, 0xFC, 0xFF]), "JMP ($FFFC)");
+        assert_eq!(text(&[0xA1, 0x10]), "LDA ($10,X)");
+        assert_eq!(text(&[0xB1, 0x10]), "LDA ($10),Y");
+        assert_eq!(text(&[0xD0, 0xFE]), "BNE $C000");
+    }
+
+    #[test]
+    fn absolute_in_zero_page_is_forced(){
+        assert_eq!(text(&[0xAD, 0x12, 0x00]), "LDA a:$0012");
+        assert_eq!(text(&[0x9D, 0x00, 0x00]), "STA a:$0000,X");
+    }
+
+    #[test]
+    fn unofficial_opcodes_use_ca65_names(){
+        let decoded = decode_bytes(&[0x02], 0xC000, 0xC000);
+        assert_eq!(decoded.to_string(), "JAM");
+        assert!(decoded.unofficial);
+        assert_eq!(text(&[0x9F, 0x00, 0x02]), "SHA $0200,Y");
+        assert_eq!(text(&[0xC7, 0x10]), "DCP $10");
+        assert!(decode_bytes(&[0xEB, 0x01], 0xC000, 0xC000).unofficial);
+        assert!(!decode_bytes(&[0xE9, 0x01], 0xC000, 0xC000).unofficial);
+        assert!(decode_bytes(&[0x04, 0x01], 0xC000, 0xC000).unofficial);
+    }
+
+    #[test]
+    fn branch_and_jump_targets(){
+        assert_eq!(decode_bytes(&[0x10, 0x04], 0xC72F, 0xC72F).target, Some(0xC735));
+        assert_eq!(decode_bytes(&[0x20, 0x00, 0x80], 0xC000, 0xC000).target, Some(0x8000));
+        assert_eq!(decode_bytes(&[0x6C, 0x00, 0x80], 0xC000, 0xC000).target, None);
+        assert_eq!(decode_bytes(&[0xA9, 0x00], 0xC000, 0xC000).target, None);
+    }
+
+    #[test]
+    fn disassembles_a_range(){
+        let mut memory = FlatMemory::new();
+        // LDA #$7F, STA $00, JMP $C000
+        for (i, byte) in [0xA9, 0x7F, 0x85, 0x00, 0x4C, 0x00, 0xC0].iter().enumerate(){
+            memory.load(0xC000 + i as u16, *byte);
+        }
+        let lines: Vec<String> = disassemble(&memory, 0xC000, 0xC004).iter().map(|d| d.listing()).collect();
+        assert_eq!(lines, vec![
+            "C000  A9 7F     LDA #$7F",
+            "C002  85 00     STA $00",
+            "C004  4C 00 C0  JMP $C000"
+        ]);
+        // Doesn't run off the top of memory
+        assert_eq!(disassemble(&memory, 0xFFFF, 0xFFFF).len(), 1);
+    }
+}
@@ -0,0 +1,468 @@
+#![allow(dead_code)]
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::controller::*;
+use crate::nes::Nes;
+use crate::ppu::{PALETTE, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::savestate;
+
+// Runs a ROM with no window and no sound device, for build machines:
+//
+//   melones --headless game.nes --frames 600 --input title.txt \
+//       --screenshot out.png --expect-hash 0123456789ABCDEF
+//
+// Prints the frame count and a hash of the last frame, and exits with 1 when
+// the hash isn't the expected one or an --until condition never came true,
+// 2 when something couldn't be loaded or written.
+
+pub const USAGE: &str = "\
+usage: melones --headless ROM [options]
+  --frames N           frames to run, at most (default 600)
+  --until ADDR=VALUE   stop once the CPU reads VALUE at ADDR (both hex)
+  --input FILE         input script, see below
+  --screenshot FILE    PNG of the last frame
+  --ram FILE           dump of the 2KB CPU RAM
+  --wav FILE           audio, 16 bit mono
+  --expect-hash HEX    fail unless the last frame hashes to HEX
+
+Input scripts hold buttons from a frame on, until the next line:
+  # frame  port 1    port 2
+  60       START
+  62       .
+  100      A+RIGHT   B";
+
+pub const DEFAULT_FRAMES: u64 = 600;
+
+pub const EXIT_PASS: i32 = 0;
+pub const EXIT_MISMATCH: i32 = 1;
+pub const EXIT_ERROR: i32 = 2;
+
+#[derive(Debug)]
+pub enum HeadlessError{
+    Usage(String),
+    Cartridge(CartridgeError),
+    Io(PathBuf, io::Error),
+    Script{ line: usize, what: String },
+    Image(image::ImageError)
+}
+
+impl fmt::Display for HeadlessError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            HeadlessError::Usage(what) => write!(f, "{}\n\n{}", what, USAGE),
+            HeadlessError::Cartridge(e) => write!(f, "{}", e),
+            HeadlessError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            HeadlessError::Script{ line, what } => write!(f, "input script line {}: {}", line, what),
+            HeadlessError::Image(e) => write!(f, "could not write screenshot: {}", e)
+        }
+    }
+}
+
+impl Error for HeadlessError{}
+
+/******
+ * Options
+ ******/
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options{
+    pub rom: PathBuf,
+    pub frames: u64,
+    pub until: Option<(u16, u8)>,
+    pub input: Option<PathBuf>,
+    pub screenshot: Option<PathBuf>,
+    pub ram: Option<PathBuf>,
+    pub wav: Option<PathBuf>,
+    pub expect_hash: Option<u64>
+}
+
+fn parse_hex<T: TryFrom<u32>>(text: &str, what: &str) -> Result<T, HeadlessError>{
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    return u32::from_str_radix(digits, 16).ok()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| HeadlessError::Usage(format!("bad {} '{}'", what, text)));
+}
+
+impl Options{
+    // Everything after --headless
+    pub fn parse(args: &[String]) -> Result<Self, HeadlessError>{
+        let mut options = Options{ frames: DEFAULT_FRAMES, ..Default::default() };
+        let mut rom = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next(){
+            if !arg.starts_with("--"){
+                if rom.is_some(){
+                    return Err(HeadlessError::Usage(format!("unexpected argument '{}'", arg)));
+                }
+                rom = Some(PathBuf::from(arg));
+                continue;
+            }
+            let value = match args.next(){
+                Some(value) => value,
+                None => return Err(HeadlessError::Usage(format!("{} needs a value", arg)))
+            };
+            match arg.as_str(){
+                "--frames" => {
+                    options.frames = value.parse()
+                        .map_err(|_| HeadlessError::Usage(format!("bad frame count '{}'", value)))?;
+                }
+                "--until" => {
+                    let (addr, data) = match value.find('='){
+                        Some(i) => (&value[..i], &value[i + 1..]),
+                        None => return Err(HeadlessError::Usage(format!("--until wants ADDR=VALUE, not '{}'", value)))
+                    };
+                    options.until = Some((parse_hex(addr, "address")?, parse_hex(data, "value")?));
+                }
+                "--input" => options.input = Some(PathBuf::from(value)),
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value)),
+                "--ram" => options.ram = Some(PathBuf::from(value)),
+                "--wav" => options.wav = Some(PathBuf::from(value)),
+                "--expect-hash" => {
+                    let hash = u64::from_str_radix(value, 16)
+                        .map_err(|_| HeadlessError::Usage(format!("bad hash '{}'", value)))?;
+                    options.expect_hash = Some(hash);
+                }
+                _ => return Err(HeadlessError::Usage(format!("unknown option {}", arg)))
+            }
+        }
+        options.rom = rom.ok_or_else(|| HeadlessError::Usage(String::from("no ROM given")))?;
+        return Ok(options);
+    }
+}
+
+/******
+ * Input scripts
+ ******/
+
+// Button changes, by the frame they start on
+#[derive(Debug, PartialEq)]
+pub struct InputScript{
+    events: Vec<(u64, [u8; 2])>
+}
+
+fn parse_buttons(text: &str) -> Option<u8>{
+    if text == "."{
+        return Some(0);
+    }
+    let mut buttons = 0;
+    for name in text.split('+'){
+        buttons |= match name.to_ascii_uppercase().as_str(){
+            "A" => BUTTON_A,
+            "B" => BUTTON_B,
+            "SELECT" => BUTTON_SELECT,
+            "START" => BUTTON_START,
+            "UP" => BUTTON_UP,
+            "DOWN" => BUTTON_DOWN,
+            "LEFT" => BUTTON_LEFT,
+            "RIGHT" => BUTTON_RIGHT,
+            _ => return None
+        };
+    }
+    return Some(buttons);
+}
+
+impl InputScript{
+    pub fn parse(text: &str) -> Result<Self, HeadlessError>{
+        let mut events: Vec<(u64, [u8; 2])> = Vec::new();
+        for (i, line) in text.lines().enumerate(){
+            let error = |what: String| HeadlessError::Script{ line: i + 1, what };
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty(){
+                continue;
+            }
+            if fields.len() > 3{
+                return Err(error(String::from("expected a frame and up to two ports")));
+            }
+            let frame: u64 = fields[0].parse().map_err(|_| error(format!("bad frame '{}'", fields[0])))?;
+            if events.last().is_some_and(|(last, _)| *last >= frame){
+                return Err(error(format!("frame {} is out of order", frame)));
+            }
+            let mut buttons = [0; 2];
+            for (port, field) in fields[1..].iter().enumerate(){
+                buttons[port] = parse_buttons(field).ok_or_else(|| error(format!("bad buttons '{}'", field)))?;
+            }
+            events.push((frame, buttons));
+        }
+        return Ok(InputScript{ events });
+    }
+
+    // What's held during a frame, counting from 0
+    pub fn buttons_at(&self, frame: u64) -> [u8; 2]{
+        return self.events.iter()
+            .take_while(|(start, _)| *start <= frame)
+            .last()
+            .map_or([0, 0], |(_, buttons)| *buttons);
+    }
+}
+
+/******
+ * Output
+ ******/
+
+pub fn frame_hash(nes: &Nes) -> u64{
+    return savestate::hash(&[nes.bus().ppu().frame_buffer()]);
+}
+
+// The frame buffer as 8 bit RGB
+pub fn frame_rgb(nes: &Nes) -> Vec<u8>{
+    return nes.bus().ppu().frame_buffer().iter()
+        .flat_map(|&index| PALETTE[(index & 0x3F) as usize].iter().copied())
+        .collect();
+}
+
+// 16 bit mono PCM
+pub fn write_wav<W: Write>(mut out: W, sample_rate: u32, samples: &[f32]) -> io::Result<()>{
+    let data_len = samples.len() as u32 * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // channels
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?; // bytes per second
+    out.write_all(&2u16.to_le_bytes())?; // bytes per frame
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for sample in samples.iter(){
+        let value = (sample.clamp(-1.0, 1.0) * 32767.0) as i16;
+        out.write_all(&value.to_le_bytes())?;
+    }
+    return out.flush();
+}
+
+/******
+ * Running
+ ******/
+
+#[derive(Debug, PartialEq)]
+pub struct Outcome{
+    pub frames: u64,
+    pub hash: u64,
+    // Whether the --until condition came true, None without one
+    pub until_met: Option<bool>
+}
+
+impl Outcome{
+    pub fn passed(&self, options: &Options) -> bool{
+        return self.until_met != Some(false) && options.expect_hash.is_none_or(|hash| hash == self.hash);
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> HeadlessError + '_{
+    return move |e| HeadlessError::Io(path.to_path_buf(), e);
+}
+
+// Runs the machine as the options say, writing out whatever they ask for
+pub fn run(nes: &mut Nes, options: &Options, script: &InputScript) -> Result<Outcome, HeadlessError>{
+    let mut audio = Vec::new();
+    let mut buffer = [0.0; 1024];
+    let mut until_met = options.until.map(|_| false);
+    let mut frames = 0;
+    while frames < options.frames{
+        let buttons = script.buttons_at(frames);
+        nes.set_buttons(0, buttons[0]);
+        nes.set_buttons(1, buttons[1]);
+        nes.run_frame();
+        frames += 1;
+
+        // Drained every frame so the ring never drops any
+        loop{
+            let count = nes.audio_mut().samples().read(&mut buffer);
+            if count == 0{
+                break;
+            }
+            if options.wav.is_some(){
+                audio.extend_from_slice(&buffer[..count]);
+            }
+        }
+        if let Some((addr, value)) = options.until{
+            if nes.bus().cpu_peek(addr) == value{
+                until_met = Some(true);
+                break;
+            }
+        }
+    }
+
+    if let Some(path) = &options.screenshot{
+        image::save_buffer(path, &frame_rgb(nes), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, image::ColorType::Rgb8)
+            .map_err(HeadlessError::Image)?;
+    }
+    if let Some(path) = &options.ram{
+        let ram: Vec<u8> = (0..0x0800).map(|addr| nes.bus().cpu_peek(addr)).collect();
+        fs::write(path, ram).map_err(io_error(path))?;
+    }
+    if let Some(path) = &options.wav{
+        let file = fs::File::create(path).map_err(io_error(path))?;
+        write_wav(io::BufWriter::new(file), nes.audio_mut().sample_rate(), &audio).map_err(io_error(path))?;
+    }
+    return Ok(Outcome{ frames, hash: frame_hash(nes), until_met });
+}
+
+fn load_and_run(options: &Options) -> Result<Outcome, HeadlessError>{
+    let cart = Cartridge::from_file(&options.rom).map_err(HeadlessError::Cartridge)?;
+    let script = match &options.input{
+        Some(path) => InputScript::parse(&fs::read_to_string(path).map_err(io_error(path))?)?,
+        None => InputScript::parse("")?
+    };
+    let mut nes = Nes::new(cart);
+    return run(&mut nes, options, &script);
+}
+
+// The whole command, returning the exit code
+pub fn main(args: &[String]) -> i32{
+    let options = match Options::parse(args){
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let outcome = match load_and_run(&options){
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_ERROR;
+        }
+    };
+
+    println!("frames: {}", outcome.frames);
+    println!("hash: {:016X}", outcome.hash);
+    if outcome.until_met == Some(false){
+        let (addr, value) = options.until.unwrap();
+        println!("mismatch: ${:04X} never read ${:02X}", addr, value);
+    }
+    if let Some(expected) = options.expect_hash{
+        if expected != outcome.hash{
+            println!("mismatch: expected hash {:016X}", expected);
+        }
+    }
+    if outcome.passed(&options){
+        return EXIT_PASS;
+    }
+    return EXIT_MISMATCH;
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::assembler;
+    use crate::cartridge::nrom_cart;
+
+    fn args(text: &str) -> Vec<String>{
+        return text.split_whitespace().map(String::from).collect();
+    }
+
+    #[test]
+    fn parses_options(){
+        let options = Options::parse(&args("game.nes --frames 30 --until 6000=80 --expect-hash 00FF --wav out.wav")).unwrap();
+        assert_eq!(options, Options{
+            rom: PathBuf::from("game.nes"),
+            frames: 30,
+            until: Some((0x6000, 0x80)),
+            wav: Some(PathBuf::from("out.wav")),
+            expect_hash: Some(0xFF),
+            ..Default::default()
+        });
+        assert_eq!(Options::parse(&args("game.nes")).unwrap().frames, DEFAULT_FRAMES);
+        assert!(Options::parse(&args("--frames 30")).is_err());
+        assert!(Options::parse(&args("game.nes --until 6000")).is_err());
+        assert!(Options::parse(&args("game.nes --until 10000=1")).is_err());
+        assert!(Options::parse(&args("game.nes --bogus 1")).is_err());
+    }
+
+    #[test]
+    fn input_script_holds_buttons_until_the_next_line(){
+        let script = InputScript::parse("
+            # frame  port 1    port 2
+            60       START
+            62       .         # let go
+            100      a+Right   B
+        ").unwrap();
+        assert_eq!(script.buttons_at(0), [0, 0]);
+        assert_eq!(script.buttons_at(60), [BUTTON_START, 0]);
+        assert_eq!(script.buttons_at(61), [BUTTON_START, 0]);
+        assert_eq!(script.buttons_at(62), [0, 0]);
+        assert_eq!(script.buttons_at(500), [BUTTON_A | BUTTON_RIGHT, BUTTON_B]);
+
+        match InputScript::parse("10 A\n5 B"){
+            Err(HeadlessError::Script{ line, .. }) => assert_eq!(line, 2),
+            _ => panic!("out of order frames accepted")
+        }
+        assert!(InputScript::parse("10 JUMP").is_err());
+    }
+
+    #[test]
+    fn wav_header(){
+        let mut out = Vec::new();
+        write_wav(&mut out, 48_000, &[0.0, 1.0, -1.0]).unwrap();
+        assert_eq!(out.len(), 44 + 6);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(&out[4..8], &42u32.to_le_bytes());
+        assert_eq!(&out[24..28], &48_000u32.to_le_bytes());
+        assert_eq!(&out[40..44], &6u32.to_le_bytes());
+        assert_eq!(&out[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+
+    // Counts frames at $10 and copies the pad to the backdrop colour
+    fn pad_cart() -> Cartridge{
+        let program = assembler::assemble("
+            reset:  LDA #$80
+                    STA $2000
+            loop:   JMP loop
+            nmi:    LDA #1
+                    STA $4016
+                    LDA #0
+                    STA $4016
+                    LDA $4016
+                    AND #1
+                    TAX
+                    LDA #$3F
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+                    STX $2007
+                    LDA #$00
+                    STA $2006
+                    STA $2006
+                    INC $10
+                    RTI
+        ", 0xC000).unwrap();
+        let nmi = program.label("nmi").unwrap();
+        return nrom_cart(&program.bytes(), [nmi, 0xC000, 0xC000], 0);
+    }
+
+    #[test]
+    fn runs_until_the_condition_and_hashes_the_frame(){
+        let options = Options{ frames: 100, until: Some((0x0010, 5)), ..Default::default() };
+        let mut nes = Nes::new(pad_cart());
+        let outcome = run(&mut nes, &options, &InputScript::parse("").unwrap()).unwrap();
+        assert_eq!(outcome.until_met, Some(true));
+        assert!(outcome.frames < 100);
+        assert!(outcome.passed(&options));
+
+        // Same run, same hash; pressing A changes the picture
+        let options = Options{ frames: 10, ..Default::default() };
+        let first = run(&mut Nes::new(pad_cart()), &options, &InputScript::parse("").unwrap()).unwrap();
+        let again = run(&mut Nes::new(pad_cart()), &options, &InputScript::parse("").unwrap()).unwrap();
+        let pressed = run(&mut Nes::new(pad_cart()), &options, &InputScript::parse("0 A").unwrap()).unwrap();
+        assert_eq!(first.hash, again.hash);
+        assert_ne!(first.hash, pressed.hash);
+
+        let wrong = Options{ expect_hash: Some(!first.hash), ..options };
+        assert!(!first.passed(&wrong));
+        let never = Options{ frames: 3, until: Some((0x0010, 0xFF)), ..Default::default() };
+        let outcome = run(&mut Nes::new(pad_cart()), &never, &InputScript::parse("").unwrap()).unwrap();
+        assert_eq!(outcome, Outcome{ frames: 3, hash: outcome.hash, until_met: Some(false) });
+        assert!(!outcome.passed(&never));
+    }
+}
@@ -0,0 +1,24 @@
+// Explicit returns are the house style
+#![allow(clippy::needless_return)]
+// Components are built with new(), nothing asks for Default
+#![allow(clippy::new_without_default)]
+
+pub mod cpu;
+pub mod bus;
+pub mod memory;
+pub mod cartridge;
+pub mod ppu;
+pub mod apu;
+pub mod audio;
+pub mod controller;
+pub mod mapper;
+pub mod nes;
+pub mod disasm;
+pub mod assembler;
+pub mod trace;
+pub mod region;
+pub mod savestate;
+pub mod rewind;
+pub mod battery;
+pub mod headless;
+pub mod blargg;
@@ -0,0 +1,81 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{CpuMapped, Mapper, PRG_BANK_32K};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Mapper 7. Switchable 32KB of PRG and a register picked single-screen
+// nametable. CHR is always 8KB of RAM.
+pub struct Axrom{
+    prg_banks: usize,
+    prg_bank: u8,
+    nametable_upper: bool
+}
+
+impl Axrom{
+    pub fn new(prg_size: usize) -> Self{
+        return Axrom{
+            prg_banks: (prg_size / PRG_BANK_32K).max(1),
+            prg_bank: 0,
+            nametable_upper: false
+        };
+    }
+}
+
+impl Mapper for Axrom{
+    fn cpu_map_read(&self, addr: u16) -> Option<CpuMapped>{
+        if addr >= 0x8000{
+            let bank = self.prg_bank as usize % self.prg_banks;
+            return Some(CpuMapped::PrgRom(bank * PRG_BANK_32K + (addr & 0x7FFF) as usize));
+        }
+        return None;
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> Option<CpuMapped>{
+        if addr >= 0x8000{
+            self.prg_bank = data & 0x07;
+            self.nametable_upper = data & 0x10 != 0;
+        }
+        return None;
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize{
+        return addr as usize;
+    }
+
+    fn mirroring(&self) -> Mirroring{
+        if self.nametable_upper{
+            return Mirroring::SingleScreenUpper;
+        }
+        return Mirroring::SingleScreenLower;
+    }
+
+    fn save_state(&self, state: &mut StateWriter){
+        state.u8(self.prg_bank);
+        state.bool(self.nametable_upper);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>{
+        self.prg_bank = state.u8()?;
+        self.nametable_upper = state.bool()?;
+        return Ok(());
+    }
+
+    fn reset(&mut self){
+        self.prg_bank = 0;
+        self.nametable_upper = false;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn switches_32k_and_nametable(){
+        let mut m = Axrom::new(8 * PRG_BANK_32K);
+        assert_eq!(m.mirroring(), Mirroring::SingleScreenLower);
+        m.cpu_map_write(0x8000, 0x13);
+        assert_eq!(m.cpu_map_read(0x8000), Some(CpuMapped::PrgRom(3 * PRG_BANK_32K)));
+        assert_eq!(m.cpu_map_read(0xFFFF), Some(CpuMapped::PrgRom(4 * PRG_BANK_32K - 1)));
+        assert_eq!(m.mirroring(), Mirroring::SingleScreenUpper);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{prg_ram_window, CpuMapped, Mapper, CHR_BANK_8K};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Mapper 3. Fixed PRG like NROM, switchable 8KB of CHR.
+pub struct Cnrom{
+    chr_banks: usize,
+    chr_bank: u8,
+    mirroring: Mirroring
+}
+
+impl Cnrom{
+    pub fn new(chr_size: usize, mirroring: Mirroring) -> Self{
+        return Cnrom{
+            chr_banks: (chr_size / CHR_BANK_8K).max(1),
+            chr_bank: 0,
+            mirroring
+        };
+    }
+}
+
+impl Mapper for Cnrom{
+    fn cpu_map_read(&self, addr: u16) -> Option<CpuMapped>{
+        if addr >= 0x8000{
+            // Cartridge mirrors 16KB boards for us
+            return Some(CpuMapped::PrgRom((addr & 0x7FFF) as usize));
+        }
+        return prg_ram_window(addr);
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> Option<CpuMapped>{
+        if addr >= 0x8000{
+            self.chr_bank = data;
+            return None;
+        }
+        return prg_ram_window(addr);
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize{
+        let bank = self.chr_bank as usize % self.chr_banks;
+        return bank * CHR_BANK_8K + (addr & 0x1FFF) as usize;
+    }
+
+    fn mirroring(&self) -> Mirroring{
+        return self.mirroring;
+    }
+
+    fn save_state(&self, state: &mut StateWriter){
+        state.u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>{
+        self.chr_bank = state.u8()?;
+        return Ok(());
+    }
+
+    fn reset(&mut self){
+        self.chr_bank = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn switches_chr(){
+        let mut m = Cnrom::new(4 * CHR_BANK_8K, Mirroring::Horizontal);
+        assert_eq!(m.ppu_map_read(0x1234), 0x1234);
+        m.cpu_map_write(0x8000, 0x02);
+        assert_eq!(m.ppu_map_read(0x1234), 2 * CHR_BANK_8K + 0x1234);
+        m.cpu_map_write(0x8000, 0x07);
+        assert_eq!(m.ppu_map_read(0x0000), 3 * CHR_BANK_8K);
+    }
+
+    #[test]
+    fn prg_is_fixed(){
+        let mut m = Cnrom::new(4 * CHR_BANK_8K, Mirroring::Horizontal);
+        m.cpu_map_write(0x8000, 0x03);
+        assert_eq!(m.cpu_map_read(0xFFFC), Some(CpuMapped::PrgRom(0x7FFC)));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{prg_ram_window, CpuMapped, Mapper, CHR_BANK_4K, PRG_BANK_16K};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Mapper 1. Registers are loaded one bit at a time through a 5 bit serial
+// shift register mapped across $8000-$FFFF.
+pub struct Mmc1{
+    prg_banks: usize, // in 16KB units
+    chr_banks: usize, // in 4KB units
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    cycle: u64,
+    last_write_cycle: Option<u64>
+}
+
+impl Mmc1{
+    pub fn new(prg_size: usize, chr_size: usize) -> Self{
+        let mut m = Mmc1{
+            prg_banks: (prg_size / PRG_BANK_16K).max(1),
+            chr_banks: (chr_size / CHR_BANK_4K).max(1),
+            shift: 0,
+            shift_count: 0,
+            control: 0,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycle: 0,
+            last_write_cycle: None
+        };
+        m.reset();
+        return m;
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8){
+        match addr & 0x6000{
+            0x0000 => self.control = value,
+            0x2000 => self.chr_bank_0 = value,
+            0x4000 => self.chr_bank_1 = value,
+            _      => self.prg_bank = value
+        }
+    }
+
+    // SUROM and friends use CHR bank bit 4 to pick a 256KB half of PRG
+    fn prg_outer_bank(&self) -> usize{
+        if self.prg_banks > 16{
+            return self.chr_bank_0 as usize & 0x10;
+        }
+        return 0;
+    }
+
+    fn prg_bank_16k(&self, addr: u16) -> usize{
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let outer = self.prg_outer_bank();
+        let last = (outer | 0x0F).min(self.prg_banks - 1);
+        let selected = match (self.control >> 2) & 0x03{
+            // 32KB mode ignores the low bit of the bank number
+            0 | 1 => (outer | (bank & 0x0E)) + ((addr as usize >> 14) & 0x01),
+            2 => if addr < 0xC000{ outer }else{ outer | bank },
+            _ => if addr < 0xC000{ outer | bank }else{ last }
+        };
+        return selected % self.prg_banks;
+    }
+}
+
+impl Mapper for Mmc1{
+    fn cpu_map_read(&self, addr: u16) -> Option<CpuMapped>{
+        if addr >= 0x8000{
+            let bank = self.prg_bank_16k(addr);
+            return Some(CpuMapped::PrgRom(bank * PRG_BANK_16K + (addr & 0x3FFF) as usize));
+        }
+        return prg_ram_window(addr);
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> Option<CpuMapped>{
+        if addr < 0x8000{
+            return prg_ram_window(addr);
+        }
+
+        // The serial port only sees the first of two writes on back to back
+        // cycles, which is what read-modify-write instructions produce.
+        let consecutive = self.last_write_cycle.is_some_and(|c| self.cycle - c < 2);
+        self.last_write_cycle = Some(self.cycle);
+        if consecutive{
+            return None;
+        }
+
+        if data & 0x80 != 0{
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return None;
+        }
+
+        self.shift = (self.shift >> 1) | ((data & 0x01) << 4);
+        self.shift_count += 1;
+        if self.shift_count == 5{
+            let value = self.shift;
+            self.write_register(addr, value);
+            self.shift = 0;
+            self.shift_count = 0;
+        }
+        return None;
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize{
+        let bank = if self.control & 0x10 == 0{
+            // 8KB mode ignores the low bit
+            (self.chr_bank_0 as usize & 0x1E) | ((addr as usize >> 12) & 0x01)
+        }else if addr < 0x1000{
+            self.chr_bank_0 as usize
+        }else{
+            self.chr_bank_1 as usize
+        };
+        return (bank % self.chr_banks) * CHR_BANK_4K + (addr & 0x0FFF) as usize;
+    }
+
+    fn mirroring(&self) -> Mirroring{
+        match self.control & 0x03{
+            0 => return Mirroring::SingleScreenLower,
+            1 => return Mirroring::SingleScreenUpper,
+            2 => return Mirroring::Vertical,
+            _ => return Mirroring::Horizontal
+        }
+    }
+
+    fn cpu_clock(&mut self){
+        self.cycle += 1;
+    }
+
+    fn save_state(&self, state: &mut StateWriter){
+        state.u8(self.shift);
+        state.u8(self.shift_count);
+        state.u8(self.control);
+        state.u8(self.chr_bank_0);
+        state.u8(self.chr_bank_1);
+        state.u8(self.prg_bank);
+        state.u64(self.cycle);
+        state.bool(self.last_write_cycle.is_some());
+        state.u64(self.last_write_cycle.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>{
+        self.shift = state.u8()?;
+        self.shift_count = state.u8()?;
+        self.control = state.u8()?;
+        self.chr_bank_0 = state.u8()?;
+        self.chr_bank_1 = state.u8()?;
+        self.prg_bank = state.u8()?;
+        self.cycle = state.u64()?;
+        let written = state.bool()?;
+        let last_write_cycle = state.u64()?;
+        self.last_write_cycle = if written{ Some(last_write_cycle) }else{ None };
+        return Ok(());
+    }
+
+    fn reset(&mut self){
+        self.shift = 0;
+        self.shift_count = 0;
+        // Power on with the last bank fixed at $C000
+        self.control = 0x0C;
+        self.last_write_cycle = None;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // Five serial writes, spaced out like separate STA instructions
+    fn load(m: &mut Mmc1, addr: u16, value: u8){
+        for i in 0..5{
+            m.cpu_map_write(addr, (value >> i) & 0x01);
+            m.cpu_clock();
+            m.cpu_clock();
+        }
+    }
+
+    fn rom_offset(m: &Mmc1, addr: u16) -> usize{
+        match m.cpu_map_read(addr){
+            Some(CpuMapped::PrgRom(offset)) => return offset,
+            other => panic!("expected PRG ROM, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn powers_on_with_last_bank_fixed(){
+        let m = Mmc1::new(8 * PRG_BANK_16K, 0x2000);
+        assert_eq!(rom_offset(&m, 0xC000), 7 * PRG_BANK_16K);
+        assert_eq!(rom_offset(&m, 0x8000), 0);
+    }
+
+    #[test]
+    fn shift_register_loads_prg_bank(){
+        let mut m = Mmc1::new(8 * PRG_BANK_16K, 0x2000);
+        load(&mut m, 0xE000, 0x03);
+        assert_eq!(rom_offset(&m, 0x8000), 3 * PRG_BANK_16K);
+        assert_eq!(rom_offset(&m, 0xFFFF), 8 * PRG_BANK_16K - 1);
+    }
+
+    #[test]
+    fn prg_modes(){
+        let mut m = Mmc1::new(8 * PRG_BANK_16K, 0x2000);
+        load(&mut m, 0xE000, 0x05);
+
+        // Fix first bank, switch $C000
+        load(&mut m, 0x8000, 0x08);
+        assert_eq!(rom_offset(&m, 0x8000), 0);
+        assert_eq!(rom_offset(&m, 0xC000), 5 * PRG_BANK_16K);
+
+        // 32KB mode drops the low bit
+        load(&mut m, 0x8000, 0x00);
+        assert_eq!(rom_offset(&m, 0x8000), 4 * PRG_BANK_16K);
+        assert_eq!(rom_offset(&m, 0xC000), 5 * PRG_BANK_16K);
+    }
+
+    #[test]
+    fn reset_bit_clears_shift_register(){
+        let mut m = Mmc1::new(8 * PRG_BANK_16K, 0x2000);
+        m.cpu_map_write(0xE000, 0x01);
+        m.cpu_clock();
+        m.cpu_clock();
+        m.cpu_map_write(0xE000, 0x80);
+        m.cpu_clock();
+        m.cpu_clock();
+        load(&mut m, 0xE000, 0x02);
+        assert_eq!(rom_offset(&m, 0x8000), 2 * PRG_BANK_16K);
+    }
+
+    #[test]
+    fn ignores_write_on_consecutive_cycle(){
+        let mut m = Mmc1::new(8 * PRG_BANK_16K, 0x2000);
+        // What INC $FFFF does: write the old value, then the new one a cycle later
+        m.cpu_map_write(0xE000, 0x01);
+        m.cpu_clock();
+        m.cpu_map_write(0xE000, 0x00);
+        m.cpu_clock();
+        m.cpu_clock();
+        for _ in 0..4{
+            m.cpu_map_write(0xE000, 0x00);
+            m.cpu_clock();
+            m.cpu_clock();
+        }
+        assert_eq!(rom_offset(&m, 0x8000), PRG_BANK_16K);
+    }
+
+    #[test]
+    fn chr_4k_and_8k_modes(){
+        let mut m = Mmc1::new(2 * PRG_BANK_16K, 32 * CHR_BANK_4K);
+        load(&mut m, 0xA000, 0x05);
+        load(&mut m, 0xC000, 0x09);
+        // 8KB mode: $A000 picks the pair, low bit ignored
+        assert_eq!(m.ppu_map_read(0x0000), 4 * CHR_BANK_4K);
+        assert_eq!(m.ppu_map_read(0x1000), 5 * CHR_BANK_4K);
+
+        load(&mut m, 0x8000, 0x1C);
+        assert_eq!(m.ppu_map_read(0x0010), 5 * CHR_BANK_4K + 0x10);
+        assert_eq!(m.ppu_map_read(0x1010), 9 * CHR_BANK_4K + 0x10);
+    }
+
+    #[test]
+    fn mirroring_control(){
+        let mut m = Mmc1::new(2 * PRG_BANK_16K, 0x2000);
+        load(&mut m, 0x8000, 0x0E);
+        assert_eq!(m.mirroring(), Mirroring::Vertical);
+        load(&mut m, 0x8000, 0x0F);
+        assert_eq!(m.mirroring(), Mirroring::Horizontal);
+        load(&mut m, 0x8000, 0x0D);
+        assert_eq!(m.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn surom_outer_bank(){
+        let mut m = Mmc1::new(32 * PRG_BANK_16K, 0x2000);
+        load(&mut m, 0xA000, 0x10);
+        load(&mut m, 0xE000, 0x02);
+        assert_eq!(rom_offset(&m, 0x8000), 18 * PRG_BANK_16K);
+        assert_eq!(rom_offset(&m, 0xC000), 31 * PRG_BANK_16K);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{CpuMapped, Mapper, CHR_BANK_1K, PRG_BANK_8K};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Chip revisions that behave differently enough to matter
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Variant{
+    // NEC MMC3A: with a latch of 0 the IRQ only fires once after a reload
+    Mmc3A,
+    // Sharp MMC3B/MMC3C: the IRQ fires on every clock that leaves the counter at 0
+    Mmc3B,
+    // MMC3B IRQs, plus 1KB of internal PRG RAM at $7000 with per-half protection
+    Mmc6
+}
+
+// Mapper 4. 8KB PRG banks, 1KB/2KB CHR banks and a scanline counter that is
+// clocked by rising edges on PPU address line A12.
+pub struct Mmc3{
+    variant: Variant,
+    prg_banks: usize, // in 8KB units
+    chr_banks: usize, // in 1KB units
+    four_screen: bool,
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+    ram_protect: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_active: bool,
+    // A12 is filtered by M2: it has to sit low for a few CPU cycles before a
+    // rise counts, which hides the toggling during sprite pattern fetches.
+    a12_high: bool,
+    a12_low_cycle: u64,
+    cycle: u64
+}
+
+// CPU cycles A12 has to stay low for the next rise to clock the counter
+const A12_LOW_CYCLES: u64 = 3;
+
+impl Mmc3{
+    pub fn new(prg_size: usize, chr_size: usize, mirroring: Mirroring, variant: Variant) -> Self{
+        let mut m = Mmc3{
+            variant,
+            prg_banks: (prg_size / PRG_BANK_8K).max(1),
+            chr_banks: (chr_size / CHR_BANK_1K).max(1),
+            four_screen: mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring,
+            ram_protect: 0,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_active: false,
+            a12_high: false,
+            a12_low_cycle: 0,
+            cycle: 0
+        };
+        m.reset();
+        return m;
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize{
+        let second_last = self.prg_banks.saturating_sub(2);
+        let bank = match ((addr >> 13) & 0x03, self.bank_select & 0x40 != 0){
+            (0, false) => self.registers[6] as usize,
+            (0, true)  => second_last,
+            (1, _)     => self.registers[7] as usize,
+            (2, false) => second_last,
+            (2, true)  => self.registers[6] as usize,
+            _          => self.prg_banks - 1
+        };
+        return bank % self.prg_banks;
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize{
+        // Inversion swaps the 2KB and 1KB halves of the pattern tables
+        let a = if self.bank_select & 0x80 != 0{ addr ^ 0x1000 }else{ addr };
+        let bank = match a >> 10{
+            0 => self.registers[0] & 0xFE,
+            1 => self.registers[0] | 0x01,
+            2 => self.registers[1] & 0xFE,
+            3 => self.registers[1] | 0x01,
+            n => self.registers[(n - 2) as usize]
+        };
+        return bank as usize % self.chr_banks;
+    }
+
+    fn clock_irq_counter(&mut self){
+        let previous = self.irq_counter;
+        if self.irq_counter == 0 || self.irq_reload{
+            self.irq_counter = self.irq_latch;
+        }else{
+            self.irq_counter -= 1;
+        }
+
+        let fire = match self.variant{
+            Variant::Mmc3A => self.irq_counter == 0 && (previous > 0 || self.irq_reload),
+            _ => self.irq_counter == 0
+        };
+        if fire && self.irq_enabled{
+            self.irq_active = true;
+        }
+        self.irq_reload = false;
+    }
+
+    // MMC6 RAM: 1KB mirrored through $7000-$7FFF, each 512 byte half with its
+    // own read and write enables in $A001, all gated by $8000 bit 5.
+    fn mmc6_ram(&self, addr: u16, write: bool) -> Option<CpuMapped>{
+        if !(0x7000..=0x7FFF).contains(&addr) || self.bank_select & 0x20 == 0{
+            return None;
+        }
+        let high = addr & 0x0200 != 0;
+        let (read_bit, write_bit) = if high{ (0x80, 0x40) }else{ (0x20, 0x10) };
+        let readable = self.ram_protect & read_bit != 0;
+        let writable = readable && self.ram_protect & write_bit != 0;
+        if (write && writable) || (!write && readable){
+            return Some(CpuMapped::PrgRam((addr & 0x03FF) as usize));
+        }
+        return None;
+    }
+
+    fn prg_ram(&self, addr: u16, write: bool) -> Option<CpuMapped>{
+        if self.variant == Variant::Mmc6{
+            return self.mmc6_ram(addr, write);
+        }
+        if (0x6000..=0x7FFF).contains(&addr){
+            let enabled = self.ram_protect & 0x80 != 0;
+            let protected = self.ram_protect & 0x40 != 0;
+            if enabled && !(write && protected){
+                return Some(CpuMapped::PrgRam((addr & 0x1FFF) as usize));
+            }
+        }
+        return None;
+    }
+}
+
+impl Mapper for Mmc3{
+    fn cpu_map_read(&self, addr: u16) -> Option<CpuMapped>{
+        if addr >= 0x8000{
+            let bank = self.prg_bank(addr);
+            return Some(CpuMapped::PrgRom(bank * PRG_BANK_8K + (addr & 0x1FFF) as usize));
+        }
+        return self.prg_ram(addr, false);
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> Option<CpuMapped>{
+        if addr < 0x8000{
+            return self.prg_ram(addr, true);
+        }
+
+        match (addr & 0xE000, addr & 0x0001){
+            (0x8000, 0) => self.bank_select = data,
+            (0x8000, _) => self.registers[(self.bank_select & 0x07) as usize] = data,
+            (0xA000, 0) => {
+                if !self.four_screen{
+                    self.mirroring = if data & 0x01 != 0{ Mirroring::Horizontal }else{ Mirroring::Vertical };
+                }
+            }
+            (0xA000, _) => {
+                // MMC6 ignores the protect register until RAM is enabled in $8000
+                if self.variant != Variant::Mmc6 || self.bank_select & 0x20 != 0{
+                    self.ram_protect = data;
+                }
+            }
+            (0xC000, 0) => self.irq_latch = data,
+            (0xC000, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000, 0) => {
+                self.irq_enabled = false;
+                self.irq_active = false;
+            }
+            _ => self.irq_enabled = true
+        }
+        return None;
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize{
+        return self.chr_bank(addr) * CHR_BANK_1K + (addr & 0x03FF) as usize;
+    }
+
+    fn ppu_address(&mut self, addr: u16){
+        if addr & 0x1000 != 0{
+            if !self.a12_high && self.cycle - self.a12_low_cycle >= A12_LOW_CYCLES{
+                self.clock_irq_counter();
+            }
+            self.a12_high = true;
+        }else if self.a12_high{
+            self.a12_high = false;
+            self.a12_low_cycle = self.cycle;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring{
+        return self.mirroring;
+    }
+
+    fn irq(&self) -> bool{
+        return self.irq_active;
+    }
+
+    fn cpu_clock(&mut self){
+        self.cycle += 1;
+    }
+
+    fn save_state(&self, state: &mut StateWriter){
+        state.u8(self.bank_select);
+        state.bytes(&self.registers);
+        state.u8(match self.mirroring{
+            Mirroring::Horizontal => 0,
+            Mirroring::Vertical => 1,
+            _ => 2
+        });
+        state.u8(self.ram_protect);
+        state.u8(self.irq_latch);
+        state.u8(self.irq_counter);
+        state.bool(self.irq_reload);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_active);
+        state.bool(self.a12_high);
+        state.u64(self.a12_low_cycle);
+        state.u64(self.cycle);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>{
+        self.bank_select = state.u8()?;
+        state.bytes(&mut self.registers)?;
+        // Four-screen boards ignore the mirroring register
+        self.mirroring = match state.u8()?{
+            0 => Mirroring::Horizontal,
+            1 => Mirroring::Vertical,
+            2 => Mirroring::FourScreen,
+            _ => return Err(StateError::Invalid("MMC3 mirroring"))
+        };
+        self.ram_protect = state.u8()?;
+        self.irq_latch = state.u8()?;
+        self.irq_counter = state.u8()?;
+        self.irq_reload = state.bool()?;
+        self.irq_enabled = state.bool()?;
+        self.irq_active = state.bool()?;
+        self.a12_high = state.bool()?;
+        self.a12_low_cycle = state.u64()?;
+        self.cycle = state.u64()?;
+        return Ok(());
+    }
+
+    fn reset(&mut self){
+        self.bank_select = 0;
+        self.irq_enabled = false;
+        self.irq_active = false;
+        self.irq_reload = false;
+        self.ram_protect = if self.variant == Variant::Mmc6{ 0 }else{ 0x80 };
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // One rendering scanline as the board sees it: background fetches from
+    // $0xxx, then sprite fetches from $1xxx during hblank.
+    fn scanline(m: &mut Mmc3){
+        for _ in 0..85{
+            m.ppu_address(0x0000);
+            m.cpu_clock();
+        }
+        for _ in 0..29{
+            m.ppu_address(0x1000);
+            m.cpu_clock();
+        }
+    }
+
+    fn mmc3(variant: Variant) -> Mmc3{
+        return Mmc3::new(32 * PRG_BANK_8K, 256 * CHR_BANK_1K, Mirroring::Vertical, variant);
+    }
+
+    fn irq_setup(m: &mut Mmc3, latch: u8){
+        m.cpu_map_write(0xC000, latch);
+        m.cpu_map_write(0xC001, 0);
+        m.cpu_map_write(0xE001, 0);
+    }
+
+    #[test]
+    fn irq_fires_after_latch_plus_one_scanlines(){
+        let mut m = mmc3(Variant::Mmc3B);
+        irq_setup(&mut m, 3);
+        // First rise reloads to 3, then 2, 1, 0
+        for _ in 0..3{
+            scanline(&mut m);
+            assert!(!m.irq());
+        }
+        scanline(&mut m);
+        assert!(m.irq());
+    }
+
+    #[test]
+    fn e000_acknowledges_and_disables(){
+        let mut m = mmc3(Variant::Mmc3B);
+        irq_setup(&mut m, 0);
+        scanline(&mut m);
+        assert!(m.irq());
+        m.cpu_map_write(0xE000, 0);
+        assert!(!m.irq());
+        scanline(&mut m);
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn short_a12_low_time_is_filtered(){
+        let mut m = mmc3(Variant::Mmc3B);
+        irq_setup(&mut m, 1);
+        // Rapid toggling like 8x16 sprites pulling from both pattern tables
+        m.ppu_address(0x0000);
+        for _ in 0..10{ m.cpu_clock(); }
+        for _ in 0..8{
+            m.ppu_address(0x1000);
+            m.cpu_clock();
+            m.ppu_address(0x0000);
+            m.cpu_clock();
+        }
+        m.ppu_address(0x1000);
+        // Only the first rise counted: reload to 1
+        assert_eq!(m.irq_counter, 1);
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn revision_b_fires_every_scanline_with_zero_latch(){
+        let mut m = mmc3(Variant::Mmc3B);
+        irq_setup(&mut m, 0);
+        for _ in 0..3{
+            scanline(&mut m);
+            assert!(m.irq());
+            m.cpu_map_write(0xE000, 0);
+            m.cpu_map_write(0xE001, 0);
+        }
+    }
+
+    #[test]
+    fn revision_a_fires_once_with_zero_latch(){
+        let mut m = mmc3(Variant::Mmc3A);
+        irq_setup(&mut m, 0);
+        scanline(&mut m);
+        assert!(m.irq());
+        m.cpu_map_write(0xE000, 0);
+        m.cpu_map_write(0xE001, 0);
+        scanline(&mut m);
+        scanline(&mut m);
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn revision_a_counts_down_normally(){
+        let mut m = mmc3(Variant::Mmc3A);
+        irq_setup(&mut m, 2);
+        scanline(&mut m);
+        scanline(&mut m);
+        assert!(!m.irq());
+        scanline(&mut m);
+        assert!(m.irq());
+    }
+
+    #[test]
+    fn prg_modes(){
+        let mut m = mmc3(Variant::Mmc3B);
+        m.cpu_map_write(0x8000, 6);
+        m.cpu_map_write(0x8001, 4);
+        m.cpu_map_write(0x8000, 7);
+        m.cpu_map_write(0x8001, 9);
+        assert_eq!(m.cpu_map_read(0x8000), Some(CpuMapped::PrgRom(4 * PRG_BANK_8K)));
+        assert_eq!(m.cpu_map_read(0xA000), Some(CpuMapped::PrgRom(9 * PRG_BANK_8K)));
+        assert_eq!(m.cpu_map_read(0xC000), Some(CpuMapped::PrgRom(30 * PRG_BANK_8K)));
+        assert_eq!(m.cpu_map_read(0xE000), Some(CpuMapped::PrgRom(31 * PRG_BANK_8K)));
+
+        m.cpu_map_write(0x8000, 0x40);
+        assert_eq!(m.cpu_map_read(0x8000), Some(CpuMapped::PrgRom(30 * PRG_BANK_8K)));
+        assert_eq!(m.cpu_map_read(0xC000), Some(CpuMapped::PrgRom(4 * PRG_BANK_8K)));
+    }
+
+    #[test]
+    fn chr_inversion(){
+        let mut m = mmc3(Variant::Mmc3B);
+        for (r, bank) in [(0u8, 9u8), (1, 20), (2, 40), (3, 41), (4, 42), (5, 43)].iter(){
+            m.cpu_map_write(0x8000, *r);
+            m.cpu_map_write(0x8001, *bank);
+        }
+        assert_eq!(m.ppu_map_read(0x0000), 8 * CHR_BANK_1K);
+        assert_eq!(m.ppu_map_read(0x0400), 9 * CHR_BANK_1K);
+        assert_eq!(m.ppu_map_read(0x0800), 20 * CHR_BANK_1K);
+        assert_eq!(m.ppu_map_read(0x1C00), 43 * CHR_BANK_1K);
+
+        m.cpu_map_write(0x8000, 0x80);
+        assert_eq!(m.ppu_map_read(0x0000), 40 * CHR_BANK_1K);
+        assert_eq!(m.ppu_map_read(0x1400), 9 * CHR_BANK_1K);
+    }
+
+    #[test]
+    fn mirroring_register(){
+        let mut m = mmc3(Variant::Mmc3B);
+        m.cpu_map_write(0xA000, 1);
+        assert_eq!(m.mirroring(), Mirroring::Horizontal);
+
+        let mut four = Mmc3::new(32 * PRG_BANK_8K, 0x2000, Mirroring::FourScreen, Variant::Mmc3B);
+        four.cpu_map_write(0xA000, 1);
+        assert_eq!(four.mirroring(), Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn mmc6_ram_halves(){
+        let mut m = mmc3(Variant::Mmc6);
+        assert_eq!(m.cpu_map_read(0x7000), None);
+        m.cpu_map_write(0x8000, 0x20);
+        // Low half readable and writable, high half read only
+        m.cpu_map_write(0xA001, 0xB0);
+        assert_eq!(m.cpu_map_write(0x7010, 0), Some(CpuMapped::PrgRam(0x010)));
+        assert_eq!(m.cpu_map_write(0x7210, 0), None);
+        assert_eq!(m.cpu_map_read(0x7E10), Some(CpuMapped::PrgRam(0x210)));
+        assert_eq!(m.cpu_map_read(0x6000), None);
+    }
+
+    #[test]
+    fn state_round_trip(){
+        let mut m = mmc3(Variant::Mmc3B);
+        m.cpu_map_write(0x8000, 0x46);
+        m.cpu_map_write(0x8001, 0x05);
+        m.cpu_map_write(0xA000, 0x01);
+        irq_setup(&mut m, 2);
+        scanline(&mut m);
+
+        let mut state = StateWriter::new(0);
+        state.section(*b"MAPR", 1, |w| m.save_state(w));
+        let data = state.finish();
+        let mut loaded = mmc3(Variant::Mmc3B);
+        let file = crate::savestate::StateFile::parse(&data).unwrap();
+        loaded.load_state(&mut file.section(*b"MAPR").unwrap()).unwrap();
+
+        assert_eq!(loaded.cpu_map_read(0xC000), m.cpu_map_read(0xC000));
+        assert_eq!(loaded.mirroring(), Mirroring::Horizontal);
+        for _ in 0..2{
+            scanline(&mut m);
+            scanline(&mut loaded);
+            assert_eq!(loaded.irq(), m.irq());
+        }
+        assert!(loaded.irq());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::cartridge::{CartridgeError, Header, Mirroring};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub mod nrom;
+pub mod mmc1;
+pub mod uxrom;
+pub mod cnrom;
+pub mod axrom;
+pub mod mmc3;
+
+pub const PRG_BANK_8K: usize = 8 * 1024;
+pub const PRG_BANK_16K: usize = 16 * 1024;
+pub const PRG_BANK_32K: usize = 32 * 1024;
+pub const CHR_BANK_1K: usize = 1024;
+pub const CHR_BANK_4K: usize = 4 * 1024;
+pub const CHR_BANK_8K: usize = 8 * 1024;
+
+// Where a CPU access lands on the cartridge
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CpuMapped{
+    PrgRom(usize),
+    PrgRam(usize)
+}
+
+// The board logic between the console and the cartridge memory. Mappers only
+// translate addresses and keep their registers; the Cartridge owns the ROM and
+// RAM itself and does the actual reads and writes.
+pub trait Mapper{
+    // $4020-$FFFF reads. None leaves the data bus floating.
+    fn cpu_map_read(&self, addr: u16) -> Option<CpuMapped>;
+    // $4020-$FFFF writes. Register writes are consumed here and return None.
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> Option<CpuMapped>;
+    // $0000-$1FFF pattern table reads, as an offset into CHR
+    fn ppu_map_read(&self, addr: u16) -> usize;
+    // $0000-$1FFF pattern table writes, only honoured for CHR RAM
+    fn ppu_map_write(&self, addr: u16) -> usize{
+        return self.ppu_map_read(addr);
+    }
+    // Every address the PPU drives onto its bus, for boards that watch
+    // address lines (MMC3 counts rising edges of A12)
+    fn ppu_address(&mut self, _addr: u16){}
+    fn mirroring(&self) -> Mirroring;
+    // Level of the cartridge IRQ line, true when asserted
+    fn irq(&self) -> bool{
+        return false;
+    }
+    // Called once per CPU cycle, for boards that watch M2
+    fn cpu_clock(&mut self){}
+    fn reset(&mut self);
+    // Registers for save states. Bank counts and the like come from the
+    // header, only what the game can change needs saving.
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+// Shared by every board that puts PRG RAM at $6000-$7FFF
+fn prg_ram_window(addr: u16) -> Option<CpuMapped>{
+    if (0x6000..=0x7FFF).contains(&addr){
+        return Some(CpuMapped::PrgRam((addr & 0x1FFF) as usize));
+    }
+    return None;
+}
+
+pub fn new_mapper(header: &Header) -> Result<Box<dyn Mapper>, CartridgeError>{
+    let prg_size = header.prg_rom_size;
+    let chr_size = if header.chr_rom_size > 0{
+        header.chr_rom_size
+    }else{
+        header.chr_ram_size + header.chr_nvram_size
+    };
+
+    let mapper: Box<dyn Mapper> = match header.mapper{
+        0 => Box::new(nrom::Nrom::new(prg_size, header.mirroring)),
+        1 => Box::new(mmc1::Mmc1::new(prg_size, chr_size)),
+        2 => Box::new(uxrom::Uxrom::new(prg_size, header.mirroring)),
+        3 => Box::new(cnrom::Cnrom::new(chr_size, header.mirroring)),
+        4 => {
+            let variant = match header.submapper{
+                1 => mmc3::Variant::Mmc6,
+                4 => mmc3::Variant::Mmc3A,
+                _ => mmc3::Variant::Mmc3B
+            };
+            Box::new(mmc3::Mmc3::new(prg_size, chr_size, header.mirroring, variant))
+        }
+        7 => Box::new(axrom::Axrom::new(prg_size)),
+        n => return Err(CartridgeError::UnsupportedMapper(n))
+    };
+    return Ok(mapper);
+}
@@ -0,0 +1,71 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{prg_ram_window, CpuMapped, Mapper};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Mapper 0. 16KB or 32KB of PRG, 8KB of CHR, no registers at all.
+pub struct Nrom{
+    prg_size: usize,
+    mirroring: Mirroring
+}
+
+impl Nrom{
+    pub fn new(prg_size: usize, mirroring: Mirroring) -> Self{
+        return Nrom{
+            prg_size,
+            mirroring
+        };
+    }
+}
+
+impl Mapper for Nrom{
+    fn cpu_map_read(&self, addr: u16) -> Option<CpuMapped>{
+        if addr >= 0x8000{
+            // A 16KB cartridge shows up twice
+            let mask = if self.prg_size > 0x4000{ 0x7FFF }else{ 0x3FFF };
+            return Some(CpuMapped::PrgRom((addr & mask) as usize));
+        }
+        return prg_ram_window(addr);
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, _data: u8) -> Option<CpuMapped>{
+        return prg_ram_window(addr);
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize{
+        return addr as usize;
+    }
+
+    fn mirroring(&self) -> Mirroring{
+        return self.mirroring;
+    }
+
+    // Nothing a game can change
+    fn save_state(&self, _state: &mut StateWriter){}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError>{
+        return Ok(());
+    }
+
+    fn reset(&mut self){}
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn nrom_128_mirrors_prg(){
+        let m = Nrom::new(0x4000, Mirroring::Vertical);
+        assert_eq!(m.cpu_map_read(0x8123), Some(CpuMapped::PrgRom(0x0123)));
+        assert_eq!(m.cpu_map_read(0xC123), Some(CpuMapped::PrgRom(0x0123)));
+    }
+
+    #[test]
+    fn nrom_256_maps_flat(){
+        let m = Nrom::new(0x8000, Mirroring::Horizontal);
+        assert_eq!(m.cpu_map_read(0xFFFC), Some(CpuMapped::PrgRom(0x7FFC)));
+        assert_eq!(m.cpu_map_read(0x6010), Some(CpuMapped::PrgRam(0x0010)));
+        assert_eq!(m.cpu_map_read(0x5000), None);
+        assert_eq!(m.mirroring(), Mirroring::Horizontal);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{prg_ram_window, CpuMapped, Mapper, PRG_BANK_16K};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// Mapper 2. Switchable 16KB at $8000, last 16KB fixed at $C000.
+pub struct Uxrom{
+    prg_banks: usize,
+    prg_bank: u8,
+    mirroring: Mirroring
+}
+
+impl Uxrom{
+    pub fn new(prg_size: usize, mirroring: Mirroring) -> Self{
+        return Uxrom{
+            prg_banks: (prg_size / PRG_BANK_16K).max(1),
+            prg_bank: 0,
+            mirroring
+        };
+    }
+}
+
+impl Mapper for Uxrom{
+    fn cpu_map_read(&self, addr: u16) -> Option<CpuMapped>{
+        if addr >= 0xC000{
+            return Some(CpuMapped::PrgRom((self.prg_banks - 1) * PRG_BANK_16K + (addr & 0x3FFF) as usize));
+        }
+        if addr >= 0x8000{
+            let bank = self.prg_bank as usize % self.prg_banks;
+            return Some(CpuMapped::PrgRom(bank * PRG_BANK_16K + (addr & 0x3FFF) as usize));
+        }
+        return prg_ram_window(addr);
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> Option<CpuMapped>{
+        if addr >= 0x8000{
+            self.prg_bank = data;
+            return None;
+        }
+        return prg_ram_window(addr);
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> usize{
+        return addr as usize;
+    }
+
+    fn mirroring(&self) -> Mirroring{
+        return self.mirroring;
+    }
+
+    fn save_state(&self, state: &mut StateWriter){
+        state.u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>{
+        self.prg_bank = state.u8()?;
+        return Ok(());
+    }
+
+    fn reset(&mut self){
+        self.prg_bank = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn switches_low_bank_only(){
+        let mut m = Uxrom::new(8 * PRG_BANK_16K, Mirroring::Vertical);
+        m.cpu_map_write(0x8000, 0x05);
+        assert_eq!(m.cpu_map_read(0x8001), Some(CpuMapped::PrgRom(5 * PRG_BANK_16K + 1)));
+        assert_eq!(m.cpu_map_read(0xC001), Some(CpuMapped::PrgRom(7 * PRG_BANK_16K + 1)));
+    }
+
+    #[test]
+    fn bank_number_wraps_to_rom_size(){
+        let mut m = Uxrom::new(4 * PRG_BANK_16K, Mirroring::Vertical);
+        m.cpu_map_write(0xFFFF, 0x06);
+        assert_eq!(m.cpu_map_read(0x8000), Some(CpuMapped::PrgRom(2 * PRG_BANK_16K)));
+    }
+}
@@ -0,0 +1,97 @@
+#![allow(dead_code)]
+
+// What the CPU sees of the outside world. read and write are real bus
+// cycles and may have side effects (clearing flags, clocking shift
+// registers); peek is for tooling and must leave everything as it was.
+pub trait Memory{
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+    fn peek(&self, addr: u16) -> u8;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access{
+    Read,
+    Write
+}
+
+// One bus transaction, stamped with the cycle it happened on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusAccess{
+    pub cycle: u64,
+    pub addr: u16,
+    pub data: u8,
+    pub access: Access
+}
+
+// Plain 64K of RAM with nothing mapped anywhere, for driving the CPU on its
+// own. Every read and write is logged so a harness can check the bus
+// activity as well as the end state.
+#[derive(Clone)]
+pub struct FlatMemory{
+    ram: Vec<u8>,
+    log: Vec<BusAccess>,
+    cycle: u64
+}
+
+impl FlatMemory{
+    pub fn new() -> Self{
+        return FlatMemory{ ram: vec![0; 0x10000], log: Vec::new(), cycle: 0 };
+    }
+
+    // Sets memory without it showing up in the log
+    pub fn load(&mut self, addr: u16, data: u8){
+        self.ram[addr as usize] = data;
+    }
+
+    // Moves on to the next cycle, for the log's timestamps
+    pub fn clock(&mut self){
+        self.cycle += 1;
+    }
+
+    pub fn cycles(&self) -> u64{
+        return self.cycle;
+    }
+
+    // The accesses made since the log was last taken
+    pub fn take_log(&mut self) -> Vec<BusAccess>{
+        return std::mem::take(&mut self.log);
+    }
+}
+
+impl Memory for FlatMemory{
+    fn read(&mut self, addr: u16) -> u8{
+        let data = self.ram[addr as usize];
+        self.log.push(BusAccess{ cycle: self.cycle, addr, data, access: Access::Read });
+        return data;
+    }
+
+    fn write(&mut self, addr: u16, data: u8){
+        self.ram[addr as usize] = data;
+        self.log.push(BusAccess{ cycle: self.cycle, addr, data, access: Access::Write });
+    }
+
+    fn peek(&self, addr: u16) -> u8{
+        return self.ram[addr as usize];
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn flat_memory_logs_accesses(){
+        let mut memory = FlatMemory::new();
+        memory.load(0x8000, 0x34);
+        memory.write(0x4014, 0x12);
+        memory.clock();
+        assert_eq!(memory.read(0x4014), 0x12);
+        assert_eq!(memory.peek(0x8000), 0x34);
+        assert_eq!(memory.take_log(), vec![
+            BusAccess{ cycle: 0, addr: 0x4014, data: 0x12, access: Access::Write },
+            BusAccess{ cycle: 1, addr: 0x4014, data: 0x12, access: Access::Read }
+        ]);
+        assert!(memory.take_log().is_empty());
+    }
+}
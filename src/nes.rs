@@ -0,0 +1,564 @@
+#![allow(dead_code)]
+
+use crate::audio::{Audio, DEFAULT_SAMPLE_RATE};
+use std::io::Write;
+
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::controller::Controller;
+use crate::cpu::CPU_6502;
+use crate::region::Region;
+use crate::savestate::{StateError, StateFile, StateWriter};
+use crate::trace;
+
+// The whole console. Components hang off the CPU's bus, and the master clock
+// decides which of them gets to run next.
+pub struct Nes{
+    cpu: CPU_6502<Bus>,
+    region: Region,
+    audio: Audio,
+    master_clock: u64,
+    ppu_clock: u64, // master clock of the next PPU dot
+    // nestest.log style trace of every instruction, when set
+    trace: Option<Box<dyn Write>>
+}
+
+impl Nes{
+    // The region comes from the cartridge header, set_region overrides it
+    pub fn new(cart: Cartridge) -> Self{
+        let region = Region::from_timing(cart.header().timing);
+        let mut bus = Bus::new();
+        bus.insert_cartridge(cart);
+        bus.set_region(region);
+        let mut nes = Nes{
+            cpu: CPU_6502::new(bus),
+            region,
+            audio: Audio::new(region.cpu_clock_hz(), DEFAULT_SAMPLE_RATE),
+            master_clock: 0,
+            ppu_clock: 0,
+            trace: None
+        };
+        // Games and test ROMs count on the dummy reads and writes
+        nes.cpu.set_cycle_stepped(true);
+        nes.cpu.reset();
+        return nes;
+    }
+
+    pub fn reset(&mut self){
+        self.cpu.bus_mut().reset();
+        self.cpu.reset();
+    }
+
+    pub fn region(&self) -> Region{
+        return self.region;
+    }
+
+    pub fn set_region(&mut self, region: Region){
+        self.region = region;
+        self.cpu.bus_mut().set_region(region);
+        self.audio = Audio::new(region.cpu_clock_hz(), self.audio.sample_rate());
+    }
+
+    // Throws away any samples not yet read
+    pub fn set_sample_rate(&mut self, sample_rate: u32){
+        self.audio = Audio::new(self.region.cpu_clock_hz(), sample_rate);
+    }
+
+    // Host rate samples produced so far, ready to be drained
+    pub fn audio_mut(&mut self) -> &mut Audio{
+        return &mut self.audio;
+    }
+
+    // Tracing stops by itself if the writer fails
+    pub fn set_trace(&mut self, out: Option<Box<dyn Write>>){
+        self.trace = out;
+    }
+
+    pub fn cpu(&self) -> &CPU_6502<Bus>{
+        return &self.cpu;
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU_6502<Bus>{
+        return &mut self.cpu;
+    }
+
+    pub fn bus(&self) -> &Bus{
+        return self.cpu.bus();
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus{
+        return self.cpu.bus_mut();
+    }
+
+    // Buttons are the controller::BUTTON_* bits. Ports start out with a
+    // standard controller plugged into each.
+    pub fn set_buttons(&mut self, port: usize, buttons: u8){
+        if let Some(controller) = self.bus_mut().controller_mut(port){
+            controller.set_buttons(buttons);
+        }
+    }
+
+    pub fn plug_controller(&mut self, port: usize, controller: Option<Controller>){
+        self.bus_mut().plug_controller(port, controller);
+    }
+
+    pub fn master_clock(&self) -> u64{
+        return self.master_clock;
+    }
+
+    // Including the ones the CPU spent stalled
+    pub fn cpu_cycles(&self) -> u64{
+        return self.bus().cycles();
+    }
+
+    pub fn frame_count(&self) -> u64{
+        return self.bus().ppu().frame_count();
+    }
+
+    // A snapshot of the whole machine, see savestate for the layout. Host
+    // side things like audio buffers and the trace aren't included.
+    pub fn save_state(&self) -> Vec<u8>{
+        let mut state = StateWriter::new(self.bus().cartridge().rom_hash());
+        state.section(*b"NES ", 1, |w| {
+            w.u8(match self.region{
+                Region::Ntsc => 0,
+                Region::Pal => 1,
+                Region::Dendy => 2
+            });
+            w.u64(self.master_clock);
+            w.u64(self.ppu_clock);
+        });
+        self.cpu.save_state(&mut state);
+        self.bus().save_state(&mut state);
+        return state.finish();
+    }
+
+    // Has to be the same cartridge. On error the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError>{
+        let state = StateFile::parse(data)?;
+        if state.cartridge_hash != self.bus().cartridge().rom_hash(){
+            return Err(StateError::WrongCartridge);
+        }
+        let backup = self.save_state();
+        let result = self.load_sections(&state);
+        if result.is_err(){
+            self.load_sections(&StateFile::parse(&backup)?)?;
+        }
+        return result;
+    }
+
+    fn load_sections(&mut self, state: &StateFile) -> Result<(), StateError>{
+        let mut r = state.section(*b"NES ")?;
+        let region = match r.u8()?{
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Dendy,
+            _ => return Err(StateError::Invalid("region"))
+        };
+        let master_clock = r.u64()?;
+        let ppu_clock = r.u64()?;
+        if region != self.region{
+            self.set_region(region);
+        }
+        self.master_clock = master_clock;
+        self.ppu_clock = ppu_clock;
+        self.cpu.load_state(state)?;
+        self.cpu.bus_mut().load_state(state)?;
+        return Ok(());
+    }
+
+    // One CPU cycle, and the PPU dots that fall inside it
+    pub fn step_cycle(&mut self){
+        self.master_clock += self.region.cpu_divider();
+        // A running DMA holds the CPU halted and takes the cycle for itself
+        if self.cpu.bus().dma_takes_cycle(self.cpu.next_access()){
+            self.cpu.bus_mut().dma_cycle();
+        }else{
+            // An instruction boundary that an interrupt is about to take
+            // over doesn't get a trace line of its own
+            if self.cpu.complete() && !self.cpu.halted() && !self.cpu.interrupt_pending(){
+                if let Some(out) = &mut self.trace{
+                    if writeln!(out, "{}", trace::trace_line(&self.cpu)).is_err(){
+                        self.trace = None;
+                    }
+                }
+            }
+            self.cpu.clock();
+        }
+        self.cpu.bus_mut().clock();
+        self.audio.clock(self.cpu.bus().apu().channel_outputs());
+
+        // NTSC and Dendy fit three dots in every cycle, PAL alternates
+        // between three and four to average 3.2
+        let ppu_divider = self.region.ppu_divider();
+        while self.ppu_clock + ppu_divider <= self.master_clock{
+            self.ppu_clock += ppu_divider;
+            self.cpu.bus_mut().ppu_clock();
+        }
+
+        // The CPU samples its interrupt lines once the cycle is over
+        let (nmi, irq) = (self.cpu.bus().nmi(), self.cpu.bus().irq());
+        self.cpu.set_nmi_line(nmi);
+        self.cpu.set_irq_line(irq);
+        self.cpu.poll_interrupts();
+    }
+
+    // Runs until the CPU is ready to fetch its next opcode, taking any
+    // interrupt sequence as an instruction of its own
+    pub fn step_instruction(&mut self){
+        // A DMA can hold the CPU up before it gets going
+        let start = self.cpu.clock_count();
+        loop{
+            self.step_cycle();
+            // A jammed CPU never completes another instruction
+            if self.cpu.halted() || (self.cpu.complete() && self.cpu.clock_count() != start){
+                return;
+            }
+        }
+    }
+
+    // Runs until the PPU starts its next VBlank
+    pub fn run_frame(&mut self){
+        let frame = self.frame_count();
+        while self.frame_count() == frame{
+            self.step_cycle();
+        }
+        self.audio.flush();
+    }
+
+    // Runs until the CPU cycle counter reaches cycles
+    pub fn run_until(&mut self, cycles: u64){
+        while self.cpu_cycles() < cycles{
+            self.step_cycle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::assembler;
+    use crate::cartridge::nrom_cart;
+    use crate::cpu::AccessKind;
+
+    // The program at $C000, with the IRQ vector on reset's
+    fn test_cart(program: &[u8], nmi: u16, reset: u16) -> Cartridge{
+        return nrom_cart(program, [nmi, reset, reset], 0);
+    }
+
+    #[test]
+    fn three_dots_per_cpu_cycle(){
+        // JMP $C000
+        let mut nes = Nes::new(test_cart(&[0x4C, 0x00, 0xC0], 0xC000, 0xC000));
+        nes.run_until(1000);
+        let ppu = nes.bus().ppu();
+        assert_eq!(ppu.scanline() as u64 * 341 + ppu.dot() as u64, 3000);
+        assert_eq!(nes.master_clock(), 1000 * 12);
+    }
+
+    #[test]
+    fn pal_runs_16_dots_every_5_cpu_cycles(){
+        let mut nes = Nes::new(test_cart(&[0x4C, 0x00, 0xC0], 0xC000, 0xC000));
+        nes.set_region(Region::Pal);
+        nes.run_until(1000);
+        let ppu = nes.bus().ppu();
+        assert_eq!(ppu.scanline() as u64 * 341 + ppu.dot() as u64, 3200);
+    }
+
+    #[test]
+    fn region_follows_the_header(){
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0x08, 0, 0, 0, 0, 0x01, 0, 0, 0];
+        rom.extend(vec![0; 0x4000 + 0x2000]);
+        let nes = Nes::new(Cartridge::from_bytes(&rom).unwrap());
+        assert_eq!(nes.region(), Region::Pal);
+    }
+
+    #[test]
+    fn run_frame_stops_at_vblank(){
+        let mut nes = Nes::new(test_cart(&[0x4C, 0x00, 0xC0], 0xC000, 0xC000));
+        nes.run_frame();
+        assert_eq!(nes.frame_count(), 1);
+        assert_eq!(nes.bus().ppu().scanline(), 241);
+    }
+
+    #[test]
+    fn step_instruction_runs_whole_instructions(){
+        // LDA #$01, STA $0200, JMP $C005
+        let program = [0xA9, 0x01, 0x8D, 0x00, 0x02, 0x4C, 0x05, 0xC0];
+        let mut nes = Nes::new(test_cart(&program, 0xC005, 0xC000));
+        // The reset sequence counts as the first step
+        nes.step_instruction();
+        let start = nes.cpu_cycles();
+        nes.step_instruction();
+        assert_eq!(nes.cpu_cycles() - start, 2);
+        nes.step_instruction();
+        assert_eq!(nes.cpu_cycles() - start, 6);
+        assert_eq!(nes.bus_mut().cpu_read(0x0200), 0x01);
+    }
+
+    #[test]
+    fn oam_dma_halts_the_cpu(){
+        // LDA #$02, STA $4014, NOP, then the same with LDA $00 to shift
+        // the alignment by a cycle. The DMA starts after the write, holding
+        // up the NOP's opcode fetch.
+        for (program, dma) in [([0xA9, 0x02, 0x8D, 0x14, 0x40], 513), ([0xA5, 0x00, 0x8D, 0x14, 0x40], 514)]{
+            let mut nes = Nes::new(test_cart(&program, 0xC000, 0xC000));
+            nes.step_instruction();
+            nes.step_instruction();
+            let start = nes.cpu_cycles();
+            nes.step_instruction();
+            nes.step_instruction();
+            assert_eq!(nes.cpu_cycles() - start, 4 + dma + 2);
+        }
+    }
+
+    #[test]
+    fn dmc_fetch_waits_out_the_writes_of_a_read_modify_write(){
+        // INC $0200, JMP $C000
+        let mut nes = Nes::new(test_cart(&[0xEE, 0x00, 0x02, 0x4C, 0x00, 0xC0], 0xC000, 0xC000));
+        nes.step_instruction();
+        // Opcode and address fetches, leaving INC about to read $0200
+        for _ in 0..3{
+            nes.step_cycle();
+        }
+        nes.bus_mut().cpu_write(0x4013, 0x01);
+        nes.bus_mut().cpu_write(0x4015, 0x10);
+        nes.step_cycle();
+
+        // The fetch is pending through the dummy write and the real one,
+        // and the CPU keeps running
+        for _ in 0..2{
+            assert!(nes.bus().dma_active());
+            assert_eq!(nes.cpu().next_access(), AccessKind::Write);
+            let count = nes.cpu().clock_count();
+            nes.step_cycle();
+            assert_eq!(nes.cpu().clock_count(), count + 1);
+        }
+        assert!(nes.cpu().complete());
+        assert_eq!(nes.bus_mut().cpu_read(0x0200), 1);
+
+        // Then halts it on the next opcode fetch
+        let count = nes.cpu().clock_count();
+        nes.step_cycle();
+        assert_eq!(nes.cpu().clock_count(), count);
+    }
+
+    #[test]
+    fn controller_reads_through_4016(){
+        // LDA #$01, STA $4016, LDA #$00, STA $4016, then read two bits from
+        // port 1 and one from the empty port 2 into $00-$02, wait: JMP wait
+        let program = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40,
+            0xAD, 0x16, 0x40, 0x85, 0x00,
+            0xAD, 0x16, 0x40, 0x85, 0x01,
+            0xAD, 0x17, 0x40, 0x85, 0x02,
+            0x4C, 0x19, 0xC0
+        ];
+        let mut nes = Nes::new(test_cart(&program, 0xC019, 0xC000));
+        nes.set_buttons(0, crate::controller::BUTTON_A);
+        nes.plug_controller(1, None);
+        nes.run_until(100);
+        assert_eq!(nes.bus_mut().cpu_read(0x0000), 0x41);
+        assert_eq!(nes.bus_mut().cpu_read(0x0001), 0x40);
+        assert_eq!(nes.bus_mut().cpu_read(0x0002), 0x40);
+    }
+
+    #[test]
+    fn vblank_nmi_reaches_the_cpu_once_per_frame(){
+        // LDA #$80, STA $2000, wait: JMP wait
+        // NMI handler: INC $10, JMP wait
+        let program = [
+            0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0xC0,
+            0xE6, 0x10, 0x4C, 0x05, 0xC0
+        ];
+        let mut nes = Nes::new(test_cart(&program, 0xC008, 0xC000));
+        for _ in 0..3{
+            nes.run_frame();
+        }
+        // The last NMI has only just been raised
+        nes.run_until(nes.cpu_cycles() + 20);
+        assert_eq!(nes.bus_mut().cpu_read(0x0010), 3);
+    }
+
+    #[test]
+    fn dummy_read_acknowledges_vblank(){
+        // LDX #$10, wait: JMP wait, then LDA $20F2,X which crosses into
+        // $2102 and dummy reads $2002 on the way
+        let program = [0xA2, 0x10, 0x4C, 0x02, 0xC0, 0xBD, 0xF2, 0x20];
+        for cycle_stepped in [true, false]{
+            let mut nes = Nes::new(test_cart(&program, 0xC002, 0xC000));
+            nes.cpu_mut().set_cycle_stepped(cycle_stepped);
+            nes.run_frame();
+            nes.step_instruction();
+            nes.cpu_mut().set_pc(0xC005);
+            nes.step_instruction();
+            // The real read only sees VBlank if the dummy one didn't clear it
+            assert_eq!(nes.cpu().a() & 0x80 == 0, cycle_stepped);
+        }
+    }
+
+    // A CHR RAM cart that draws a scrolling screen full of tiles and sprites
+    // with a pulse tone going, so every component has state worth saving
+    fn busy_cart() -> Cartridge{
+        let program = assembler::assemble("
+            reset:  SEI
+                    LDX #$FF
+                    TXS
+                    LDA #0
+                    STA $2000
+                    STA $2001
+            vwait:  BIT $2002
+                    BPL vwait
+                    ; Pattern tables, every byte different
+                    STA $2006
+                    STA $2006
+                    LDY #$20
+            chr:    TXA
+                    EOR $00
+                    STA $2007
+                    INX
+                    BNE chr
+                    INC $00
+                    DEY
+                    BNE chr
+                    ; Nametable, tile n everywhere
+                    LDA #$20
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+                    LDY #$04
+            nt:     STX $2007
+                    INX
+                    BNE nt
+                    DEY
+                    BNE nt
+                    LDA #$3F
+                    STA $2006
+                    LDA #$00
+                    STA $2006
+            pal:    STX $2007
+                    INX
+                    CPX #$20
+                    BNE pal
+                    LDX #$00
+            spr:    TXA
+                    STA $0200,X
+                    INX
+                    BNE spr
+                    LDA #$01
+                    STA $4015
+                    LDA #$BF
+                    STA $4000
+                    LDA #$08
+                    STA $4003
+                    LDA #$80
+                    STA $2000
+                    LDA #$1E
+                    STA $2001
+            main:   INC $10
+                    LDA $10
+                    STA $4002
+                    JMP main
+            nmi:    PHA
+                    LDA #$02
+                    STA $4014
+                    INC $11
+                    LDA $11
+                    STA $2005
+                    STA $2005
+                    PLA
+                    RTI
+        ", 0xC000).unwrap();
+        let nmi = program.label("nmi").unwrap();
+
+        return nrom_cart(&program.bytes(), [nmi, 0xC000, 0xC000], 0);
+    }
+
+    fn next_frames(nes: &mut Nes, count: usize) -> Vec<Vec<u8>>{
+        return (0..count).map(|_| {
+            nes.run_frame();
+            return nes.bus().ppu().frame_buffer().to_vec();
+        }).collect();
+    }
+
+    // Where each section's header starts
+    fn section_offsets(state: &[u8]) -> Vec<([u8; 4], usize)>{
+        let mut offsets = Vec::new();
+        let mut pos = 14;
+        while pos < state.len(){
+            let mut tag = [0; 4];
+            tag.copy_from_slice(&state[pos..pos + 4]);
+            let length = u32::from_le_bytes([state[pos + 6], state[pos + 7], state[pos + 8], state[pos + 9]]) as usize;
+            offsets.push((tag, pos));
+            pos += 10 + length;
+        }
+        return offsets;
+    }
+
+    #[test]
+    fn save_state_round_trip_gives_identical_frames(){
+        let mut nes = Nes::new(busy_cart());
+        next_frames(&mut nes, 10);
+        // Partway down the visible frame, and likely an instruction
+        nes.run_until(nes.cpu_cycles() + 5001);
+        let state = nes.save_state();
+        let expected = next_frames(&mut nes, 5);
+        let end = nes.save_state();
+        assert_ne!(expected[0], expected[1], "the test program should scroll");
+
+        nes.load_state(&state).unwrap();
+        assert_eq!(next_frames(&mut nes, 5), expected);
+        assert_eq!(nes.save_state(), end);
+
+        let mut fresh = Nes::new(busy_cart());
+        fresh.load_state(&state).unwrap();
+        assert_eq!(next_frames(&mut fresh, 5), expected);
+        assert_eq!(fresh.save_state(), end);
+    }
+
+    #[test]
+    fn load_skips_what_it_does_not_know(){
+        let mut nes = Nes::new(busy_cart());
+        next_frames(&mut nes, 3);
+        let mut state = nes.save_state();
+        let expected = next_frames(&mut nes, 2);
+
+        // A field appended to the NES section by some later version, and a
+        // section this version has never heard of
+        let (_, nes_at) = section_offsets(&state)[0];
+        let length = u32::from_le_bytes([state[nes_at + 6], state[nes_at + 7], state[nes_at + 8], state[nes_at + 9]]);
+        state[nes_at + 6..nes_at + 10].copy_from_slice(&(length + 2).to_le_bytes());
+        let end = nes_at + 10 + length as usize;
+        state.splice(end..end, vec![0xAB, 0xCD]);
+        state.extend_from_slice(b"ZZZZ\x01\x00\x03\x00\x00\x00xyz");
+
+        nes.load_state(&state).unwrap();
+        assert_eq!(next_frames(&mut nes, 2), expected);
+    }
+
+    #[test]
+    fn failed_load_leaves_the_machine_alone(){
+        let mut nes = Nes::new(busy_cart());
+        next_frames(&mut nes, 3);
+        let state = nes.save_state();
+        next_frames(&mut nes, 1);
+        let before = nes.save_state();
+
+        // Drop the APU section, so the load fails after the CPU and bus
+        // have already taken theirs
+        let offsets = section_offsets(&state);
+        let apu = offsets.iter().position(|(tag, _)| tag == b"APU ").unwrap();
+        let mut broken = state[..offsets[apu].1].to_vec();
+        if let Some((_, next)) = offsets.get(apu + 1){
+            broken.extend_from_slice(&state[*next..]);
+        }
+        assert_eq!(nes.load_state(&broken), Err(StateError::MissingSection(*b"APU ")));
+        assert_eq!(nes.save_state(), before);
+
+        assert_eq!(nes.load_state(&state[..state.len() - 1]).err(), Some(StateError::Truncated(offsets.last().unwrap().0)));
+        assert_eq!(nes.load_state(b"garbage").err(), Some(StateError::BadMagic));
+        let mut other = Nes::new(test_cart(&[0x4C, 0x00, 0xC0], 0xC000, 0xC000));
+        assert_eq!(other.load_state(&state), Err(StateError::WrongCartridge));
+        assert_eq!(nes.save_state(), before);
+    }
+}
@@ -0,0 +1,1132 @@
+#![allow(dead_code)]
+
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::region::Region;
+use crate::savestate::{StateError, StateFile, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// RGB for each palette index, from a 2C02 capture. Emphasis bits aren't
+// applied.
+pub const PALETTE: [[u8; 3]; 64] = [
+    [84, 84, 84], [0, 30, 116], [8, 16, 144], [48, 0, 136], [68, 0, 100], [92, 0, 48], [84, 4, 0], [60, 24, 0],
+    [32, 42, 0], [8, 58, 0], [0, 64, 0], [0, 60, 0], [0, 50, 60], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [152, 150, 152], [8, 76, 196], [48, 50, 236], [92, 30, 228], [136, 20, 176], [160, 20, 100], [152, 34, 32], [120, 60, 0],
+    [84, 90, 0], [40, 114, 0], [8, 124, 0], [0, 118, 40], [0, 102, 120], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [76, 154, 236], [120, 124, 236], [176, 98, 236], [228, 84, 236], [236, 88, 180], [236, 106, 100], [212, 136, 32],
+    [160, 170, 0], [116, 196, 0], [76, 208, 32], [56, 204, 108], [56, 180, 204], [60, 60, 60], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [168, 204, 236], [188, 188, 236], [212, 178, 236], [236, 174, 236], [236, 174, 212], [236, 180, 176], [228, 196, 144],
+    [204, 210, 120], [180, 222, 120], [168, 226, 144], [152, 226, 180], [160, 214, 228], [160, 162, 160], [0, 0, 0], [0, 0, 0]
+];
+
+const DOTS_PER_SCANLINE: u16 = 341;
+
+// Frames an open bus bit holds its charge before reading back as 0 (~600ms)
+const LATCH_DECAY_FRAMES: u64 = 36;
+
+// PPUCTRL ($2000)
+const CTRL_INCREMENT_32: u8 = 1 << 2;
+const CTRL_SPRITE_TABLE: u8 = 1 << 3;
+const CTRL_BG_TABLE: u8 = 1 << 4;
+const CTRL_SPRITE_8X16: u8 = 1 << 5;
+const CTRL_NMI: u8 = 1 << 7;
+
+// PPUMASK ($2001)
+const MASK_GRAYSCALE: u8 = 1 << 0;
+const MASK_BG_LEFT: u8 = 1 << 1;
+const MASK_SPRITES_LEFT: u8 = 1 << 2;
+const MASK_BG: u8 = 1 << 3;
+const MASK_SPRITES: u8 = 1 << 4;
+
+// PPUSTATUS ($2002)
+const STATUS_OVERFLOW: u8 = 1 << 5;
+const STATUS_SPRITE_ZERO: u8 = 1 << 6;
+const STATUS_VBLANK: u8 = 1 << 7;
+
+// Where secondary OAM filling has got to during dots 65-256
+#[derive(Copy, Clone, PartialEq)]
+enum SpriteEval{
+    Copying,
+    // Eight sprites found, now hunting for a ninth with the buggy m increment
+    Overflow,
+    Done
+}
+
+pub struct Ppu{
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+    oam: [u8; 256],
+    secondary_oam: [u8; 32],
+    // 2KB of console VRAM plus the extra 2KB a four-screen board brings along
+    vram: [u8; 4096],
+    palette: [u8; 32],
+
+    // Loopy registers: current and temporary VRAM address, fine X and the
+    // shared write toggle for $2005/$2006
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+
+    // $2007 reads return the previous fetch, except for palette RAM
+    read_buffer: u8,
+    // Every register access charges this latch; reads of write-only
+    // registers and unused bits return it, decaying bit by bit
+    io_latch: u8,
+    io_latch_refresh: [u64; 8],
+    // $2002 was read on the dot before VBlank would have been set
+    suppress_vblank: bool,
+
+    region: Region,
+    scanline: u16,
+    dot: u16,
+    frame: u64,
+    odd_frame: bool,
+
+    // Background pipeline
+    bg_next_tile: u8,
+    bg_next_attr: u8,
+    bg_next_lo: u8,
+    bg_next_hi: u8,
+    bg_shift_lo: u16,
+    bg_shift_hi: u16,
+    bg_shift_attr_lo: u16,
+    bg_shift_attr_hi: u16,
+
+    // Sprite evaluation for the next scanline
+    eval_state: SpriteEval,
+    eval_n: u8,
+    eval_m: u8,
+    eval_index: usize,
+    oam_latch: u8,
+    sprite_zero_next: bool,
+
+    // Sprites being drawn on this scanline
+    sprite_count: usize,
+    sprite_zero_line: bool,
+    sprite_lo: [u8; 8],
+    sprite_hi: [u8; 8],
+    sprite_attr: [u8; 8],
+    sprite_x: [u8; 8],
+    fetch_tile: u8,
+    fetch_row: u8,
+
+    frame_buffer: Vec<u8>
+}
+
+impl Ppu{
+    pub fn new() -> Self{
+        return Ppu{
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            oam: [0; 256],
+            secondary_oam: [0xFF; 32],
+            vram: [0; 4096],
+            palette: [0; 32],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            io_latch_refresh: [0; 8],
+            suppress_vblank: false,
+            region: Region::Ntsc,
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            odd_frame: false,
+            bg_next_tile: 0,
+            bg_next_attr: 0,
+            bg_next_lo: 0,
+            bg_next_hi: 0,
+            bg_shift_lo: 0,
+            bg_shift_hi: 0,
+            bg_shift_attr_lo: 0,
+            bg_shift_attr_hi: 0,
+            eval_state: SpriteEval::Done,
+            eval_n: 0,
+            eval_m: 0,
+            eval_index: 0,
+            oam_latch: 0,
+            sprite_zero_next: false,
+            sprite_count: 0,
+            sprite_zero_line: false,
+            sprite_lo: [0; 8],
+            sprite_hi: [0; 8],
+            sprite_attr: [0; 8],
+            sprite_x: [0; 8],
+            fetch_tile: 0,
+            fetch_row: 0,
+            frame_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT]
+        };
+    }
+
+    pub fn reset(&mut self){
+        self.ctrl = 0;
+        self.mask = 0;
+        self.w = false;
+        self.x = 0;
+        self.t = 0;
+        self.read_buffer = 0;
+        self.scanline = 0;
+        self.dot = 0;
+        self.odd_frame = false;
+    }
+
+    pub fn scanline(&self) -> u16{
+        return self.scanline;
+    }
+
+    pub fn dot(&self) -> u16{
+        return self.dot;
+    }
+
+    // Primary OAM, as $2004 would read it outside of rendering
+    pub fn oam(&self) -> &[u8; 256]{
+        return &self.oam;
+    }
+
+    pub fn set_region(&mut self, region: Region){
+        self.region = region;
+    }
+
+    // Number of frames that have reached VBlank since power on
+    pub fn frame_count(&self) -> u64{
+        return self.frame;
+    }
+
+    // 256x240 palette indices ($00-$3F), row major
+    pub fn frame_buffer(&self) -> &[u8]{
+        return &self.frame_buffer;
+    }
+
+    // Level of the /NMI output, true when asserted
+    pub fn nmi_line(&self) -> bool{
+        return self.status & STATUS_VBLANK != 0 && self.ctrl & CTRL_NMI != 0;
+    }
+
+    // Everything but the region, which belongs to the console
+    pub fn save_state(&self, state: &mut StateWriter){
+        state.section(*b"PPU ", 1, |w| {
+            w.u8(self.ctrl);
+            w.u8(self.mask);
+            w.u8(self.status);
+            w.u8(self.oam_addr);
+            w.bytes(&self.oam);
+            w.bytes(&self.secondary_oam);
+            w.bytes(&self.vram);
+            w.bytes(&self.palette);
+            w.u16(self.v);
+            w.u16(self.t);
+            w.u8(self.x);
+            w.bool(self.w);
+            w.u8(self.read_buffer);
+            w.u8(self.io_latch);
+            for refresh in self.io_latch_refresh.iter(){
+                w.u64(*refresh);
+            }
+            w.bool(self.suppress_vblank);
+            w.u16(self.scanline);
+            w.u16(self.dot);
+            w.u64(self.frame);
+            w.bool(self.odd_frame);
+            w.u8(self.bg_next_tile);
+            w.u8(self.bg_next_attr);
+            w.u8(self.bg_next_lo);
+            w.u8(self.bg_next_hi);
+            w.u16(self.bg_shift_lo);
+            w.u16(self.bg_shift_hi);
+            w.u16(self.bg_shift_attr_lo);
+            w.u16(self.bg_shift_attr_hi);
+            w.u8(match self.eval_state{
+                SpriteEval::Copying => 0,
+                SpriteEval::Overflow => 1,
+                SpriteEval::Done => 2
+            });
+            w.u8(self.eval_n);
+            w.u8(self.eval_m);
+            w.u8(self.eval_index as u8);
+            w.u8(self.oam_latch);
+            w.bool(self.sprite_zero_next);
+            w.u8(self.sprite_count as u8);
+            w.bool(self.sprite_zero_line);
+            w.bytes(&self.sprite_lo);
+            w.bytes(&self.sprite_hi);
+            w.bytes(&self.sprite_attr);
+            w.bytes(&self.sprite_x);
+            w.u8(self.fetch_tile);
+            w.u8(self.fetch_row);
+            // A state saved mid-frame needs the lines already drawn
+            w.bytes(&self.frame_buffer);
+        });
+    }
+
+    pub fn load_state(&mut self, state: &StateFile) -> Result<(), StateError>{
+        let mut r = state.section(*b"PPU ")?;
+        self.ctrl = r.u8()?;
+        self.mask = r.u8()?;
+        self.status = r.u8()?;
+        self.oam_addr = r.u8()?;
+        r.bytes(&mut self.oam)?;
+        r.bytes(&mut self.secondary_oam)?;
+        r.bytes(&mut self.vram)?;
+        r.bytes(&mut self.palette)?;
+        self.v = r.u16()?;
+        self.t = r.u16()?;
+        self.x = r.u8()?;
+        self.w = r.bool()?;
+        self.read_buffer = r.u8()?;
+        self.io_latch = r.u8()?;
+        for refresh in self.io_latch_refresh.iter_mut(){
+            *refresh = r.u64()?;
+        }
+        self.suppress_vblank = r.bool()?;
+        self.scanline = r.u16()?;
+        self.dot = r.u16()?;
+        self.frame = r.u64()?;
+        self.odd_frame = r.bool()?;
+        self.bg_next_tile = r.u8()?;
+        self.bg_next_attr = r.u8()?;
+        self.bg_next_lo = r.u8()?;
+        self.bg_next_hi = r.u8()?;
+        self.bg_shift_lo = r.u16()?;
+        self.bg_shift_hi = r.u16()?;
+        self.bg_shift_attr_lo = r.u16()?;
+        self.bg_shift_attr_hi = r.u16()?;
+        self.eval_state = match r.u8()?{
+            0 => SpriteEval::Copying,
+            1 => SpriteEval::Overflow,
+            2 => SpriteEval::Done,
+            _ => return Err(StateError::Invalid("sprite evaluation state"))
+        };
+        self.eval_n = r.u8()?;
+        self.eval_m = r.u8()?;
+        self.eval_index = r.u8()? as usize;
+        self.oam_latch = r.u8()?;
+        self.sprite_zero_next = r.bool()?;
+        self.sprite_count = r.u8()? as usize;
+        if self.eval_index > self.secondary_oam.len() || self.sprite_count > 8{
+            return Err(StateError::Invalid("sprite counts"));
+        }
+        self.sprite_zero_line = r.bool()?;
+        r.bytes(&mut self.sprite_lo)?;
+        r.bytes(&mut self.sprite_hi)?;
+        r.bytes(&mut self.sprite_attr)?;
+        r.bytes(&mut self.sprite_x)?;
+        self.fetch_tile = r.u8()?;
+        self.fetch_row = r.u8()?;
+        r.bytes(&mut self.frame_buffer)?;
+        return Ok(());
+    }
+
+    fn rendering_enabled(&self) -> bool{
+        return self.mask & (MASK_BG | MASK_SPRITES) != 0;
+    }
+
+    fn sprite_height(&self) -> i16{
+        if self.ctrl & CTRL_SPRITE_8X16 != 0{
+            return 16;
+        }
+        return 8;
+    }
+
+    /**********************************
+     *
+     * PPU address space
+     *
+     **********************************/
+    fn nametable_offset(&self, addr: u16, mirroring: Mirroring) -> usize{
+        let table = ((addr >> 10) & 0x03) as usize;
+        let physical = match mirroring{
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 0x01,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table
+        };
+        return physical * 0x400 + (addr & 0x03FF) as usize;
+    }
+
+    fn palette_offset(addr: u16) -> usize{
+        let mut i = (addr & 0x1F) as usize;
+        // $3F10/$3F14/$3F18/$3F1C are mirrors of the backdrop entries
+        if i & 0x13 == 0x10{
+            i &= 0x0F;
+        }
+        return i;
+    }
+
+    fn ppu_read(&mut self, cart: &mut Cartridge, addr: u16) -> u8{
+        let addr = addr & 0x3FFF;
+        cart.ppu_address(addr);
+        if addr <= 0x1FFF{
+            return cart.ppu_read(addr).unwrap_or(0);
+        }else if addr <= 0x3EFF{
+            return self.vram[self.nametable_offset(addr, cart.mirroring())];
+        }else{
+            return self.palette[Ppu::palette_offset(addr)];
+        }
+    }
+
+    fn ppu_write(&mut self, cart: &mut Cartridge, addr: u16, data: u8){
+        let addr = addr & 0x3FFF;
+        cart.ppu_address(addr);
+        if addr <= 0x1FFF{
+            cart.ppu_write(addr, data);
+        }else if addr <= 0x3EFF{
+            let i = self.nametable_offset(addr, cart.mirroring());
+            self.vram[i] = data;
+        }else{
+            self.palette[Ppu::palette_offset(addr)] = data & 0x3F;
+        }
+    }
+
+    /**********************************
+     *
+     * CPU facing registers
+     *
+     **********************************/
+    // Refresh the latch bits selected by mask with the bits of data
+    fn charge_latch(&mut self, data: u8, mask: u8){
+        self.io_latch = (self.io_latch & !mask) | (data & mask);
+        for bit in 0..8{
+            if mask & (1 << bit) != 0{
+                self.io_latch_refresh[bit] = self.frame;
+            }
+        }
+    }
+
+    fn decayed_latch(&mut self) -> u8{
+        for bit in 0..8{
+            if self.frame - self.io_latch_refresh[bit] > LATCH_DECAY_FRAMES{
+                self.io_latch &= !(1 << bit);
+            }
+        }
+        return self.io_latch;
+    }
+
+    pub fn cpu_read(&mut self, cart: &mut Cartridge, addr: u16) -> u8{
+        let open_bus = self.decayed_latch();
+        match addr & 0x0007{
+            0x0002 => {
+                let data = (self.status & 0xE0) | (open_bus & 0x1F);
+                self.charge_latch(data, 0xE0);
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+                // Racing the flag: a read just before it goes up means it never does
+                if self.scanline == self.region.vblank_scanline() && self.dot == 1{
+                    self.suppress_vblank = true;
+                }
+                return data;
+            }
+            0x0004 => {
+                let data = if self.rendering_enabled() && self.on_render_line(){
+                    // During rendering this is whatever evaluation last latched
+                    self.oam_latch
+                }else if self.oam_addr & 0x03 == 0x02{
+                    // Attribute bits 2-4 don't exist
+                    self.oam[self.oam_addr as usize] & 0xE3
+                }else{
+                    self.oam[self.oam_addr as usize]
+                };
+                self.charge_latch(data, 0xFF);
+                return data;
+            }
+            0x0007 => {
+                let addr = self.v & 0x3FFF;
+                let data = if addr >= 0x3F00{
+                    // Palette reads skip the buffer, which picks up the
+                    // nametable byte sitting underneath instead
+                    let mut color = self.ppu_read(cart, addr);
+                    if self.mask & MASK_GRAYSCALE != 0{
+                        color &= 0x30;
+                    }
+                    self.read_buffer = self.ppu_read(cart, addr - 0x1000);
+                    self.charge_latch(color, 0x3F);
+                    (open_bus & 0xC0) | color
+                }else{
+                    let data = self.read_buffer;
+                    self.read_buffer = self.ppu_read(cart, addr);
+                    self.charge_latch(data, 0xFF);
+                    data
+                };
+                self.increment_vram_address();
+                return data;
+            }
+            // Write only registers
+            _ => return open_bus
+        }
+    }
+
+    // What a read would return, without any of the side effects
+    pub fn cpu_peek(&self, addr: u16) -> u8{
+        match addr & 0x0007{
+            0x0002 => return (self.status & 0xE0) | (self.io_latch & 0x1F),
+            0x0004 => return self.oam[self.oam_addr as usize],
+            0x0007 => {
+                let addr = self.v & 0x3FFF;
+                if addr >= 0x3F00{
+                    return (self.io_latch & 0xC0) | self.palette[Ppu::palette_offset(addr)];
+                }
+                return self.read_buffer;
+            }
+            _ => return self.io_latch
+        }
+    }
+
+    pub fn cpu_write(&mut self, cart: &mut Cartridge, addr: u16, data: u8){
+        self.charge_latch(data, 0xFF);
+        match addr & 0x0007{
+            0x0000 => {
+                self.ctrl = data;
+                self.t = (self.t & 0xF3FF) | (((data & 0x03) as u16) << 10);
+            }
+            0x0001 => self.mask = data,
+            0x0003 => self.oam_addr = data,
+            0x0004 => {
+                if self.rendering_enabled() && self.on_render_line(){
+                    // Writes during rendering don't land, but bump the high six bits
+                    self.oam_addr = self.oam_addr.wrapping_add(4);
+                }else{
+                    self.oam[self.oam_addr as usize] = data;
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                }
+            }
+            0x0005 => {
+                if !self.w{
+                    self.t = (self.t & 0xFFE0) | (data >> 3) as u16;
+                    self.x = data & 0x07;
+                }else{
+                    self.t = (self.t & 0x8C1F) | (((data & 0x07) as u16) << 12) | (((data & 0xF8) as u16) << 2);
+                }
+                self.w = !self.w;
+            }
+            0x0006 => {
+                if !self.w{
+                    self.t = (self.t & 0x00FF) | (((data & 0x3F) as u16) << 8);
+                }else{
+                    self.t = (self.t & 0xFF00) | data as u16;
+                    self.v = self.t;
+                    cart.ppu_address(self.v);
+                }
+                self.w = !self.w;
+            }
+            0x0007 => {
+                self.ppu_write(cart, self.v, data);
+                self.increment_vram_address();
+            }
+            _ => {}
+        }
+    }
+
+    fn on_render_line(&self) -> bool{
+        return self.scanline < 240 || self.scanline == self.region.pre_render_scanline();
+    }
+
+    // $2007 accesses step v by 1 or 32, except while rendering where the
+    // PPU's own coarse X and Y increments fire instead.
+    fn increment_vram_address(&mut self){
+        if self.rendering_enabled() && self.on_render_line(){
+            self.increment_scroll_x();
+            self.increment_scroll_y();
+        }else if self.ctrl & CTRL_INCREMENT_32 != 0{
+            self.v = self.v.wrapping_add(32) & 0x7FFF;
+        }else{
+            self.v = self.v.wrapping_add(1) & 0x7FFF;
+        }
+    }
+
+    /**********************************
+     *
+     * Scrolling
+     *
+     **********************************/
+    fn increment_scroll_x(&mut self){
+        if self.v & 0x001F == 31{
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        }else{
+            self.v += 1;
+        }
+    }
+
+    fn increment_scroll_y(&mut self){
+        if self.v & 0x7000 != 0x7000{
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29{
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        }else if coarse_y == 31{
+            // Rows 30 and 31 are attribute data, wrap without switching tables
+            coarse_y = 0;
+        }else{
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn transfer_address_x(&mut self){
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn transfer_address_y(&mut self){
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    /**********************************
+     *
+     * Background
+     *
+     **********************************/
+    fn load_background_shifters(&mut self){
+        self.bg_shift_lo = (self.bg_shift_lo & 0xFF00) | self.bg_next_lo as u16;
+        self.bg_shift_hi = (self.bg_shift_hi & 0xFF00) | self.bg_next_hi as u16;
+        let attr_lo = if self.bg_next_attr & 0x01 != 0{ 0xFF }else{ 0x00 };
+        let attr_hi = if self.bg_next_attr & 0x02 != 0{ 0xFF }else{ 0x00 };
+        self.bg_shift_attr_lo = (self.bg_shift_attr_lo & 0xFF00) | attr_lo;
+        self.bg_shift_attr_hi = (self.bg_shift_attr_hi & 0xFF00) | attr_hi;
+    }
+
+    fn update_background_shifters(&mut self){
+        if self.mask & MASK_BG != 0{
+            self.bg_shift_lo <<= 1;
+            self.bg_shift_hi <<= 1;
+            self.bg_shift_attr_lo <<= 1;
+            self.bg_shift_attr_hi <<= 1;
+        }
+    }
+
+    fn fetch_background(&mut self, cart: &mut Cartridge){
+        match (self.dot - 1) % 8{
+            0 => {
+                self.load_background_shifters();
+                self.bg_next_tile = self.ppu_read(cart, 0x2000 | (self.v & 0x0FFF));
+            }
+            2 => {
+                let addr = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
+                let mut attr = self.ppu_read(cart, addr);
+                if self.v & 0x0040 != 0{
+                    attr >>= 4;
+                }
+                if self.v & 0x0002 != 0{
+                    attr >>= 2;
+                }
+                self.bg_next_attr = attr & 0x03;
+            }
+            4 => {
+                let addr = self.background_pattern_address();
+                self.bg_next_lo = self.ppu_read(cart, addr);
+            }
+            6 => {
+                let addr = self.background_pattern_address() + 8;
+                self.bg_next_hi = self.ppu_read(cart, addr);
+            }
+            7 => self.increment_scroll_x(),
+            _ => {}
+        }
+    }
+
+    fn background_pattern_address(&self) -> u16{
+        let table: u16 = if self.ctrl & CTRL_BG_TABLE != 0{ 0x1000 }else{ 0x0000 };
+        return table | ((self.bg_next_tile as u16) << 4) | ((self.v >> 12) & 0x07);
+    }
+
+    /**********************************
+     *
+     * Sprites
+     *
+     **********************************/
+    fn sprite_in_range(&self, y: u8) -> bool{
+        let row = self.scanline as i16 - y as i16;
+        return row >= 0 && row < self.sprite_height();
+    }
+
+    fn next_sprite(&mut self){
+        self.eval_n = (self.eval_n + 1) & 0x3F;
+        if self.eval_n == 0{
+            self.eval_state = SpriteEval::Done;
+        }else if self.eval_index >= 32{
+            self.eval_state = SpriteEval::Overflow;
+        }
+    }
+
+    // One dot of secondary OAM clear and sprite evaluation. Odd dots read
+    // primary OAM, even dots write secondary OAM.
+    fn evaluate_sprites(&mut self){
+        if self.dot >= 1 && self.dot <= 64{
+            if self.dot.is_multiple_of(2){
+                self.secondary_oam[(self.dot as usize / 2) - 1] = 0xFF;
+            }
+            self.oam_latch = 0xFF;
+            return;
+        }
+
+        if self.dot == 65{
+            self.eval_state = SpriteEval::Copying;
+            self.eval_n = 0;
+            self.eval_m = 0;
+            self.eval_index = 0;
+            self.sprite_zero_next = false;
+        }
+
+        if !self.dot.is_multiple_of(2){
+            self.oam_latch = self.oam[(self.eval_n as usize) * 4 + self.eval_m as usize];
+            return;
+        }
+
+        let value = self.oam_latch;
+        match self.eval_state{
+            SpriteEval::Copying => {
+                self.secondary_oam[self.eval_index] = value;
+                if self.eval_m == 0{
+                    if self.sprite_in_range(value){
+                        if self.eval_n == 0{
+                            self.sprite_zero_next = true;
+                        }
+                        self.eval_m = 1;
+                        self.eval_index += 1;
+                    }else{
+                        self.next_sprite();
+                    }
+                }else{
+                    self.eval_index += 1;
+                    self.eval_m += 1;
+                    if self.eval_m == 4{
+                        self.eval_m = 0;
+                        self.next_sprite();
+                    }
+                }
+            }
+            SpriteEval::Overflow => {
+                if self.sprite_in_range(value){
+                    self.status |= STATUS_OVERFLOW;
+                    self.eval_state = SpriteEval::Done;
+                }else{
+                    // The hardware bug: m is bumped along with n, so the check
+                    // slides diagonally through tile, attribute and X bytes
+                    self.eval_m = (self.eval_m + 1) & 0x03;
+                    self.eval_n = (self.eval_n + 1) & 0x3F;
+                    if self.eval_n == 0{
+                        self.eval_state = SpriteEval::Done;
+                    }
+                }
+            }
+            SpriteEval::Done => {
+                self.eval_m = 0;
+                self.eval_n = (self.eval_n + 1) & 0x3F;
+            }
+        }
+    }
+
+    // Dots 257-320: eight slots of two garbage nametable reads and two
+    // pattern reads each. Unused slots fetch tile $FF and draw nothing.
+    fn fetch_sprites(&mut self, cart: &mut Cartridge){
+        let slot = ((self.dot - 257) / 8) as usize;
+        let base = slot * 4;
+        match (self.dot - 257) % 8{
+            0 => {
+                self.sprite_attr[slot] = self.secondary_oam[base + 2];
+                self.sprite_x[slot] = self.secondary_oam[base + 3];
+                self.fetch_tile = self.secondary_oam[base + 1];
+                let row = (self.scanline as i16 - self.secondary_oam[base] as i16) as u8;
+                self.fetch_row = row & (self.sprite_height() as u8 - 1);
+                if self.sprite_attr[slot] & 0x80 != 0{
+                    self.fetch_row = self.sprite_height() as u8 - 1 - self.fetch_row;
+                }
+                self.ppu_read(cart, 0x2000 | (self.v & 0x0FFF));
+            }
+            2 => {
+                self.ppu_read(cart, 0x2000 | (self.v & 0x0FFF));
+            }
+            4 => {
+                let addr = self.sprite_pattern_address();
+                let data = self.ppu_read(cart, addr);
+                self.sprite_lo[slot] = self.sprite_pattern(slot, data);
+            }
+            6 => {
+                let addr = self.sprite_pattern_address() + 8;
+                let data = self.ppu_read(cart, addr);
+                self.sprite_hi[slot] = self.sprite_pattern(slot, data);
+            }
+            _ => {}
+        }
+    }
+
+    fn sprite_pattern_address(&self) -> u16{
+        if self.ctrl & CTRL_SPRITE_8X16 != 0{
+            let table: u16 = (self.fetch_tile as u16 & 0x01) << 12;
+            let tile = (self.fetch_tile & 0xFE) as u16 + (self.fetch_row >> 3) as u16;
+            return table | (tile << 4) | (self.fetch_row & 0x07) as u16;
+        }
+        let table: u16 = if self.ctrl & CTRL_SPRITE_TABLE != 0{ 0x1000 }else{ 0x0000 };
+        return table | ((self.fetch_tile as u16) << 4) | self.fetch_row as u16;
+    }
+
+    fn sprite_pattern(&self, slot: usize, data: u8) -> u8{
+        if slot >= self.next_sprite_count(){
+            return 0;
+        }
+        if self.sprite_attr[slot] & 0x40 != 0{
+            return data.reverse_bits();
+        }
+        return data;
+    }
+
+    fn next_sprite_count(&self) -> usize{
+        if self.scanline == self.region.pre_render_scanline(){
+            return 0;
+        }
+        return (self.eval_index / 4).min(8);
+    }
+
+    /**********************************
+     *
+     * Pixel output
+     *
+     **********************************/
+    fn render_pixel(&mut self){
+        let x = (self.dot - 1) as usize;
+        let y = self.scanline as usize;
+
+        let mut bg_pixel: u8 = 0;
+        let mut bg_palette: u8 = 0;
+        if self.mask & MASK_BG != 0 && (x >= 8 || self.mask & MASK_BG_LEFT != 0){
+            let mux: u16 = 0x8000 >> self.x;
+            let p0 = (self.bg_shift_lo & mux != 0) as u8;
+            let p1 = (self.bg_shift_hi & mux != 0) as u8;
+            bg_pixel = (p1 << 1) | p0;
+            let a0 = (self.bg_shift_attr_lo & mux != 0) as u8;
+            let a1 = (self.bg_shift_attr_hi & mux != 0) as u8;
+            bg_palette = (a1 << 1) | a0;
+        }
+
+        let mut fg_pixel: u8 = 0;
+        let mut fg_palette: u8 = 0;
+        let mut fg_behind = false;
+        let mut fg_is_zero = false;
+        if self.mask & MASK_SPRITES != 0 && (x >= 8 || self.mask & MASK_SPRITES_LEFT != 0){
+            for i in 0..self.sprite_count{
+                if self.sprite_x[i] != 0{
+                    continue;
+                }
+                let p0 = (self.sprite_lo[i] & 0x80 != 0) as u8;
+                let p1 = (self.sprite_hi[i] & 0x80 != 0) as u8;
+                let pixel = (p1 << 1) | p0;
+                if pixel != 0{
+                    fg_pixel = pixel;
+                    fg_palette = (self.sprite_attr[i] & 0x03) + 4;
+                    fg_behind = self.sprite_attr[i] & 0x20 != 0;
+                    fg_is_zero = i == 0 && self.sprite_zero_line;
+                    break;
+                }
+            }
+        }
+
+        if bg_pixel != 0 && fg_is_zero && x != 255 && self.rendering_both(){
+            self.status |= STATUS_SPRITE_ZERO;
+        }
+
+        let (pixel, palette) = if bg_pixel == 0 && fg_pixel == 0{
+            (0, 0)
+        }else if bg_pixel == 0{
+            (fg_pixel, fg_palette)
+        }else if fg_pixel == 0 || fg_behind{
+            (bg_pixel, bg_palette)
+        }else{
+            (fg_pixel, fg_palette)
+        };
+
+        let mut color = if !self.rendering_enabled() && (self.v & 0x3F00) == 0x3F00{
+            // With rendering off the backdrop comes from wherever v points
+            self.palette[Ppu::palette_offset(self.v)]
+        }else{
+            self.palette[Ppu::palette_offset(0x3F00 | ((palette as u16) << 2) | pixel as u16)]
+        };
+        if self.mask & MASK_GRAYSCALE != 0{
+            color &= 0x30;
+        }
+        self.frame_buffer[y * SCREEN_WIDTH + x] = color & 0x3F;
+
+        // Count sprites down to their X position, then shift their pattern out
+        for i in 0..self.sprite_count{
+            if self.sprite_x[i] > 0{
+                self.sprite_x[i] -= 1;
+            }else{
+                self.sprite_lo[i] <<= 1;
+                self.sprite_hi[i] <<= 1;
+            }
+        }
+    }
+
+    fn rendering_both(&self) -> bool{
+        return self.mask & MASK_BG != 0 && self.mask & MASK_SPRITES != 0;
+    }
+
+    /**********************************
+     *
+     * One PPU dot
+     *
+     **********************************/
+    pub fn clock(&mut self, cart: &mut Cartridge){
+        let render_line = self.on_render_line();
+        let pre_render = self.scanline == self.region.pre_render_scanline();
+
+        if pre_render && self.dot == 1{
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO | STATUS_OVERFLOW);
+        }
+
+        if render_line && self.rendering_enabled(){
+            if (self.dot >= 2 && self.dot <= 257) || (self.dot >= 322 && self.dot <= 337){
+                self.update_background_shifters();
+                self.fetch_background(cart);
+            }
+            if self.dot == 256{
+                self.increment_scroll_y();
+            }
+            if self.dot == 257{
+                self.load_background_shifters();
+                self.transfer_address_x();
+            }
+            if self.dot == 338 || self.dot == 340{
+                self.bg_next_tile = self.ppu_read(cart, 0x2000 | (self.v & 0x0FFF));
+            }
+            if pre_render && self.dot >= 280 && self.dot <= 304{
+                self.transfer_address_y();
+            }
+
+            if !pre_render && self.dot >= 1 && self.dot <= 256{
+                self.evaluate_sprites();
+            }
+            if self.dot >= 257 && self.dot <= 320{
+                self.oam_addr = 0;
+                self.fetch_sprites(cart);
+            }
+            if self.dot == 320{
+                self.sprite_count = self.next_sprite_count();
+                self.sprite_zero_line = self.sprite_zero_next && !pre_render;
+            }
+        }
+
+        if self.scanline == self.region.vblank_scanline() && self.dot == 1{
+            if !self.suppress_vblank{
+                self.status |= STATUS_VBLANK;
+            }
+            self.suppress_vblank = false;
+            self.frame += 1;
+        }
+
+        if self.scanline < 240 && self.dot >= 1 && self.dot <= 256{
+            self.render_pixel();
+        }
+
+        self.advance();
+    }
+
+    fn advance(&mut self){
+        // Odd frames skip the last dot of the pre-render line when rendering
+        if self.scanline == self.region.pre_render_scanline() && self.dot == 339 && self.odd_frame
+            && self.rendering_enabled() && self.region.skips_odd_frame_dot(){
+            self.dot = 340;
+        }
+
+        self.dot += 1;
+        if self.dot >= DOTS_PER_SCANLINE{
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > self.region.pre_render_scanline(){
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn run_until(ppu: &mut Ppu, cart: &mut Cartridge, scanline: u16, dot: u16) -> u32{
+        let mut dots = 0;
+        while ppu.scanline() != scanline || ppu.dot() != dot{
+            ppu.clock(cart);
+            dots += 1;
+        }
+        return dots;
+    }
+
+    #[test]
+    fn vblank_starts_at_scanline_241_dot_1(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.ctrl = CTRL_NMI;
+        run_until(&mut ppu, &mut cart, 241, 1);
+        assert!(!ppu.nmi_line());
+        ppu.clock(&mut cart);
+        assert!(ppu.nmi_line());
+        run_until(&mut ppu, &mut cart, 261, 2);
+        assert!(!ppu.nmi_line());
+    }
+
+    // Dots from the current (0, 0) to the next one
+    fn frame_length(ppu: &mut Ppu, cart: &mut Cartridge) -> u32{
+        ppu.clock(cart);
+        return run_until(ppu, cart, 0, 0) + 1;
+    }
+
+    #[test]
+    fn odd_frames_are_one_dot_shorter_when_rendering(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.mask = MASK_BG;
+        assert_eq!(frame_length(&mut ppu, &mut cart), 341 * 262);
+        assert_eq!(frame_length(&mut ppu, &mut cart), 341 * 262 - 1);
+        assert_eq!(frame_length(&mut ppu, &mut cart), 341 * 262);
+    }
+
+    #[test]
+    fn pal_frames_never_skip_a_dot(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.set_region(Region::Pal);
+        ppu.mask = MASK_BG;
+        assert_eq!(frame_length(&mut ppu, &mut cart), 341 * 312);
+        assert_eq!(frame_length(&mut ppu, &mut cart), 341 * 312);
+    }
+
+    #[test]
+    fn dendy_vblank_starts_late(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.set_region(Region::Dendy);
+        run_until(&mut ppu, &mut cart, 291, 1);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        ppu.clock(&mut cart);
+        assert_ne!(ppu.status & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn frames_are_full_length_with_rendering_off(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        assert_eq!(frame_length(&mut ppu, &mut cart), 341 * 262);
+        assert_eq!(frame_length(&mut ppu, &mut cart), 341 * 262);
+    }
+
+    #[test]
+    fn loopy_scroll_writes(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.cpu_write(&mut cart, 0x2000, 0x03);
+        ppu.cpu_write(&mut cart, 0x2005, 0x7D);
+        assert_eq!((ppu.t, ppu.x, ppu.w), (0x0C0F, 0x05, true));
+        ppu.cpu_write(&mut cart, 0x2005, 0x5E);
+        assert_eq!((ppu.t, ppu.w), (0x6D6F, false));
+        ppu.cpu_write(&mut cart, 0x2006, 0x3D);
+        ppu.cpu_write(&mut cart, 0x2006, 0xF0);
+        assert_eq!((ppu.t, ppu.v), (0x3DF0, 0x3DF0));
+    }
+
+    #[test]
+    fn status_read_clears_vblank_and_toggle(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        run_until(&mut ppu, &mut cart, 241, 2);
+        ppu.cpu_write(&mut cart, 0x2005, 0x00);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2002) & 0x80, 0x80);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2002) & 0x80, 0x00);
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn status_read_just_before_vblank_suppresses_it(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.ctrl = CTRL_NMI;
+        run_until(&mut ppu, &mut cart, 241, 1);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2002) & 0x80, 0x00);
+        ppu.clock(&mut cart);
+        assert!(!ppu.nmi_line());
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2002) & 0x80, 0x00);
+    }
+
+    #[test]
+    fn data_reads_are_buffered_except_palette(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.vram[0x0305] = 0x11;
+        ppu.vram[0x0306] = 0x22;
+        ppu.vram[0x0700] = 0x33;
+        ppu.palette[0x01] = 0x2A;
+
+        ppu.cpu_write(&mut cart, 0x2006, 0x23);
+        ppu.cpu_write(&mut cart, 0x2006, 0x05);
+        ppu.cpu_read(&mut cart, 0x2007);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2007), 0x11);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2007), 0x22);
+
+        // Palette comes back straight away; the buffer gets $2F01's nametable byte
+        ppu.cpu_write(&mut cart, 0x2006, 0x3F);
+        ppu.cpu_write(&mut cart, 0x2006, 0x01);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2007) & 0x3F, 0x2A);
+        assert_eq!(ppu.read_buffer, ppu.vram[0x0701]);
+    }
+
+    #[test]
+    fn open_bus_latch_decays(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.cpu_write(&mut cart, 0x2000, 0x00);
+        ppu.cpu_write(&mut cart, 0x2003, 0xFF);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2001), 0xFF);
+        // $2002 only refreshes the top three bits
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2002) & 0x1F, 0x1F);
+        for _ in 0..LATCH_DECAY_FRAMES + 2{
+            let frame = ppu.frame_count();
+            while ppu.frame_count() == frame{
+                ppu.clock(&mut cart);
+            }
+        }
+        assert_eq!(ppu.cpu_read(&mut cart, 0x2005), 0x00);
+    }
+
+    #[test]
+    fn overflow_bug_reads_diagonally(){
+        let mut ppu = Ppu::new();
+        let mut cart = Cartridge::empty();
+        ppu.mask = MASK_SPRITES;
+        // Eight sprites on line 10, then the ninth's Y is out of range but the
+        // tenth's tile byte happens to be - the buggy check looks at it.
+        for i in 0..64{
+            ppu.oam[i * 4] = 0xF0;
+            ppu.oam[i * 4 + 1] = 0xF0;
+        }
+        for i in 0..8{
+            ppu.oam[i * 4] = 10;
+        }
+        ppu.oam[9 * 4 + 1] = 10;
+        run_until(&mut ppu, &mut cart, 10, 257);
+        assert_eq!(ppu.status & STATUS_OVERFLOW, STATUS_OVERFLOW);
+
+        // Without the bug the check would have seen sprite 9's Y and stopped
+        let mut ppu = Ppu::new();
+        ppu.mask = MASK_SPRITES;
+        for i in 0..64{
+            ppu.oam[i * 4] = 0xF0;
+        }
+        for i in 0..8{
+            ppu.oam[i * 4] = 10;
+        }
+        run_until(&mut ppu, &mut cart, 10, 257);
+        assert_eq!(ppu.status & STATUS_OVERFLOW, 0);
+    }
+}
@@ -0,0 +1,147 @@
+#![allow(dead_code)]
+
+use crate::cartridge::Timing;
+
+// The console the emulator pretends to be. Everything clock related hangs off
+// this: dividers, frame shape and the APU's period tables.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Region{
+    Ntsc,
+    Pal,
+    Dendy
+}
+
+static NOISE_PERIODS_NTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068
+];
+static NOISE_PERIODS_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778
+];
+static DMC_RATES_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54
+];
+static DMC_RATES_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50
+];
+
+// CPU cycles from the $4017 write to each frame counter step. The first four
+// end the 4-step sequence, the fifth only happens in 5-step mode.
+static FRAME_STEPS_NTSC: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+static FRAME_STEPS_PAL: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+impl Region{
+    // Multi-region carts run as NTSC, it's what they were mostly sold as
+    pub fn from_timing(timing: Timing) -> Self{
+        match timing{
+            Timing::Ntsc | Timing::MultiRegion => return Region::Ntsc,
+            Timing::Pal => return Region::Pal,
+            Timing::Dendy => return Region::Dendy
+        }
+    }
+
+    pub fn master_clock_hz(&self) -> u64{
+        match self{
+            Region::Ntsc => return 21_477_272,
+            Region::Pal | Region::Dendy => return 26_601_712
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> f64{
+        return self.master_clock_hz() as f64 / self.cpu_divider() as f64;
+    }
+
+    // Master clocks per CPU cycle
+    pub fn cpu_divider(&self) -> u64{
+        match self{
+            Region::Ntsc => return 12,
+            Region::Pal => return 16,
+            Region::Dendy => return 15
+        }
+    }
+
+    // Master clocks per PPU dot. PAL ends up with 3.2 dots per CPU cycle.
+    pub fn ppu_divider(&self) -> u64{
+        match self{
+            Region::Ntsc => return 4,
+            Region::Pal | Region::Dendy => return 5
+        }
+    }
+
+    // Including the pre-render line
+    pub fn scanlines(&self) -> u16{
+        match self{
+            Region::Ntsc => return 262,
+            Region::Pal | Region::Dendy => return 312
+        }
+    }
+
+    pub fn pre_render_scanline(&self) -> u16{
+        return self.scanlines() - 1;
+    }
+
+    // Dendy keeps NTSC's 20 lines of VBlank and pads the post-render
+    // section instead, PAL spends all 50 extra lines in VBlank
+    pub fn vblank_scanline(&self) -> u16{
+        match self{
+            Region::Ntsc | Region::Pal => return 241,
+            Region::Dendy => return 291
+        }
+    }
+
+    pub fn vblank_scanlines(&self) -> u16{
+        return self.pre_render_scanline() - self.vblank_scanline();
+    }
+
+    // Only the NTSC PPU drops a dot on odd frames
+    pub fn skips_odd_frame_dot(&self) -> bool{
+        return *self == Region::Ntsc;
+    }
+
+    pub fn noise_periods(&self) -> &'static [u16; 16]{
+        match self{
+            Region::Ntsc | Region::Dendy => return &NOISE_PERIODS_NTSC,
+            Region::Pal => return &NOISE_PERIODS_PAL
+        }
+    }
+
+    pub fn dmc_rates(&self) -> &'static [u16; 16]{
+        match self{
+            Region::Ntsc | Region::Dendy => return &DMC_RATES_NTSC,
+            Region::Pal => return &DMC_RATES_PAL
+        }
+    }
+
+    pub fn frame_counter_steps(&self) -> &'static [u32; 5]{
+        match self{
+            Region::Ntsc | Region::Dendy => return &FRAME_STEPS_NTSC,
+            Region::Pal => return &FRAME_STEPS_PAL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn frame_rates(){
+        for (region, fps) in [(Region::Ntsc, 60), (Region::Pal, 50), (Region::Dendy, 50)]{
+            let frame = region.scanlines() as u64 * 341 * region.ppu_divider();
+            assert_eq!(region.master_clock_hz() / frame, fps);
+        }
+    }
+
+    #[test]
+    fn pal_runs_3_2_dots_per_cpu_cycle(){
+        assert_eq!(Region::Pal.cpu_divider() * 10 / Region::Pal.ppu_divider(), 32);
+        assert_eq!(Region::Ntsc.cpu_divider(), Region::Ntsc.ppu_divider() * 3);
+        assert_eq!(Region::Dendy.cpu_divider(), Region::Dendy.ppu_divider() * 3);
+    }
+
+    #[test]
+    fn vblank_lengths(){
+        assert_eq!(Region::Ntsc.vblank_scanlines(), 20);
+        assert_eq!(Region::Pal.vblank_scanlines(), 70);
+        assert_eq!(Region::Dendy.vblank_scanlines(), 20);
+    }
+}
@@ -0,0 +1,360 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+use crate::nes::Nes;
+
+// Rewinding, built on save states. Every `interval` frames the whole
+// machine is saved. Only the newest snapshot is kept as it is; each older
+// one is stored as its XOR against the one after it, run length encoded.
+// Most of a state doesn't change between snapshots, so those deltas are
+// mostly zeros and pack down small. Going back a snapshot is a single XOR.
+//
+// Inputs are logged every frame, so stepping back to a frame between
+// snapshots restores the one before it and plays the frames back exactly.
+//
+// The buffer drops its oldest snapshots to stay inside the memory budget.
+//
+//     let mut rewind = Rewind::new(4, 16 << 20);
+//     loop{
+//         nes.set_buttons(0, buttons);
+//         nes.run_frame();
+//         rewind.frame_done(&nes);
+//     }
+//     rewind.step_back(&mut nes);
+
+// An older snapshot, as the difference from the next newer one
+struct Delta{
+    frame: u64,
+    len: usize,
+    data: Vec<u8>
+}
+
+pub struct Rewind{
+    interval: u32,
+    budget: usize,
+    // The newest snapshot, whole
+    latest: Option<(u64, Vec<u8>)>,
+    // Oldest first
+    history: VecDeque<Delta>,
+    // Buttons on both ports during each frame since the oldest snapshot
+    inputs: VecDeque<(u64, [u8; 2])>,
+    frames_since: u32
+}
+
+/******
+ * Delta compression
+ ******/
+// A run of zeros, then some literal bytes, over and over:
+//   varint zeros, varint literal count, literal bytes
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize){
+    while value >= 0x80{
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize{
+    let mut value = 0;
+    let mut shift = 0;
+    while *pos < data.len(){
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0{
+            break;
+        }
+        shift += 7;
+    }
+    return value;
+}
+
+// XOR of two states, as long as the longer one
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8>{
+    let mut out = vec![0; a.len().max(b.len())];
+    for (i, byte) in out.iter_mut().enumerate(){
+        *byte = a.get(i).copied().unwrap_or(0) ^ b.get(i).copied().unwrap_or(0);
+    }
+    return out;
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8>{
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len(){
+        let zeros = data[pos..].iter().take_while(|&&b| b == 0).count();
+        pos += zeros;
+        // Literals run until the next stretch of zeros worth skipping
+        let mut end = pos;
+        while end < data.len() && !(data[end..].len() >= 3 && data[end..end + 3] == [0, 0, 0]){
+            end += 1;
+        }
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, end - pos);
+        out.extend_from_slice(&data[pos..end]);
+        pos = end;
+    }
+    return out;
+}
+
+pub fn decompress(data: &[u8], len: usize) -> Vec<u8>{
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < data.len(){
+        let zeros = read_varint(data, &mut pos);
+        let literals = read_varint(data, &mut pos);
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[pos..pos + literals]);
+        pos += literals;
+    }
+    out.resize(len, 0);
+    return out;
+}
+
+impl Rewind{
+    // A snapshot every interval frames, in at most budget bytes
+    pub fn new(interval: u32, budget: usize) -> Self{
+        return Rewind{
+            interval: interval.max(1),
+            budget,
+            latest: None,
+            history: VecDeque::new(),
+            inputs: VecDeque::new(),
+            frames_since: 0
+        };
+    }
+
+    pub fn set_interval(&mut self, interval: u32){
+        self.interval = interval.max(1);
+    }
+
+    pub fn set_budget(&mut self, budget: usize){
+        self.budget = budget;
+        self.trim();
+    }
+
+    pub fn clear(&mut self){
+        self.latest = None;
+        self.history.clear();
+        self.inputs.clear();
+        self.frames_since = 0;
+    }
+
+    // Snapshots held, including the newest
+    pub fn snapshots(&self) -> usize{
+        return self.history.len() + self.latest.is_some() as usize;
+    }
+
+    pub fn memory_used(&self) -> usize{
+        let latest = self.latest.as_ref().map_or(0, |(_, state)| state.len());
+        return latest + self.history.iter().map(|delta| delta.data.len()).sum::<usize>();
+    }
+
+    // The furthest back step_back can go
+    pub fn oldest_frame(&self) -> Option<u64>{
+        if let Some(delta) = self.history.front(){
+            return Some(delta.frame);
+        }
+        return self.latest.as_ref().map(|(frame, _)| *frame);
+    }
+
+    // Call after every frame. Logs the input and takes a snapshot when one
+    // is due.
+    pub fn frame_done(&mut self, nes: &Nes){
+        let frame = nes.frame_count();
+        let buttons = [nes.bus().buttons(0), nes.bus().buttons(1)];
+        self.inputs.push_back((frame.saturating_sub(1), buttons));
+
+        self.frames_since += 1;
+        if self.latest.is_none() || self.frames_since >= self.interval{
+            self.capture(nes);
+        }
+    }
+
+    // Takes a snapshot now, whatever the interval says
+    pub fn capture(&mut self, nes: &Nes){
+        let frame = nes.frame_count();
+        let state = nes.save_state();
+        if let Some((previous_frame, previous)) = self.latest.take(){
+            self.history.push_back(Delta{
+                frame: previous_frame,
+                len: previous.len(),
+                data: compress(&xor(&previous, &state))
+            });
+        }
+        self.latest = Some((frame, state));
+        self.frames_since = 0;
+        self.trim();
+    }
+
+    fn trim(&mut self){
+        while self.memory_used() > self.budget && !self.history.is_empty(){
+            self.history.pop_front();
+        }
+        if let Some(oldest) = self.oldest_frame(){
+            while self.inputs.front().is_some_and(|(frame, _)| *frame < oldest){
+                self.inputs.pop_front();
+            }
+        }
+    }
+
+    // Turns the newest snapshot back into the one before it
+    fn drop_latest(&mut self){
+        let (_, newer) = self.latest.take().unwrap();
+        if let Some(delta) = self.history.pop_back(){
+            let mut older = xor(&newer, &decompress(&delta.data, delta.len.max(newer.len())));
+            older.truncate(delta.len);
+            self.latest = Some((delta.frame, older));
+        }
+    }
+
+    // Puts the machine back to the start of the frame before the one it is
+    // in. False, with nothing changed, when that is older than the buffer
+    // goes.
+    pub fn step_back(&mut self, nes: &mut Nes) -> bool{
+        let target = match nes.frame_count().checked_sub(1){
+            Some(target) => target,
+            None => return false
+        };
+        return self.seek(nes, target);
+    }
+
+    // Puts the machine back to the start of an earlier frame
+    pub fn seek(&mut self, nes: &mut Nes, target: u64) -> bool{
+        match self.oldest_frame(){
+            Some(oldest) if oldest <= target && target <= nes.frame_count() => {}
+            _ => return false
+        }
+        while self.latest.as_ref().is_some_and(|(frame, _)| *frame > target){
+            self.drop_latest();
+        }
+        let (frame, state) = match &self.latest{
+            Some((frame, state)) => (*frame, state),
+            None => return false
+        };
+        if nes.load_state(state).is_err(){
+            return false;
+        }
+
+        // Play the frames since the snapshot back with the same input
+        for (input_frame, buttons) in self.inputs.iter(){
+            if *input_frame >= frame && *input_frame < target{
+                nes.set_buttons(0, buttons[0]);
+                nes.set_buttons(1, buttons[1]);
+                nes.run_frame();
+            }
+        }
+        while self.inputs.back().is_some_and(|(input_frame, _)| *input_frame >= target){
+            self.inputs.pop_back();
+        }
+        self.frames_since = (target - frame) as u32;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::assembler;
+    use crate::cartridge::{nrom_cart, Cartridge};
+
+    #[test]
+    fn compression_round_trips(){
+        let cases: [&[u8]; 5] = [
+            &[],
+            &[0; 1000],
+            &[1, 2, 3],
+            &[0, 0, 0, 0, 5, 0, 0, 6, 7, 0, 0, 0, 0, 0, 8],
+            &[0xFF; 300]
+        ];
+        for data in cases.iter(){
+            assert_eq!(decompress(&compress(data), data.len()), data.to_vec());
+        }
+        assert!(compress(&[0; 10000]).len() < 8);
+    }
+
+    // Scrolls by whatever the pad on port 0 says, and keeps a frame count
+    fn pad_cart() -> Cartridge{
+        let program = assembler::assemble("
+            reset:  LDA #$80
+                    STA $2000
+                    LDA #$0A
+                    STA $2001
+            loop:   JMP loop
+            nmi:    LDA #1
+                    STA $4016
+                    LDA #0
+                    STA $4016
+                    LDX #8
+            read:   LDA $4016
+                    LSR A
+                    ROL $00
+                    DEX
+                    BNE read
+                    LDA $01
+                    CLC
+                    ADC $00
+                    STA $01
+                    STA $2005
+                    STA $2005
+                    INC $02
+                    RTI
+        ", 0xC000).unwrap();
+        let nmi = program.label("nmi").unwrap();
+        return nrom_cart(&program.bytes(), [nmi, 0xC000, 0xC000], 0);
+    }
+
+    // Runs frames with changing input, returning the state at each frame
+    fn play(nes: &mut Nes, rewind: &mut Rewind, frames: u64) -> Vec<(u64, Vec<u8>)>{
+        let mut states = Vec::new();
+        for i in 0..frames{
+            nes.set_buttons(0, (i * 7) as u8);
+            nes.run_frame();
+            rewind.frame_done(nes);
+            states.push((nes.frame_count(), nes.save_state()));
+        }
+        return states;
+    }
+
+    #[test]
+    fn steps_back_one_frame_at_a_time(){
+        let mut nes = Nes::new(pad_cart());
+        let mut rewind = Rewind::new(4, 1 << 20);
+        let states = play(&mut nes, &mut rewind, 30);
+
+        // Back through every frame, matching the machine exactly
+        for (frame, state) in states.iter().rev().skip(1){
+            assert!(rewind.step_back(&mut nes));
+            assert_eq!(nes.frame_count(), *frame);
+            assert!(nes.save_state() == *state, "state differs at frame {}", frame);
+        }
+        // The very first snapshot is as far as it goes
+        assert!(!rewind.step_back(&mut nes));
+        assert_eq!(nes.frame_count(), states[0].0);
+
+        // and playing on from there takes snapshots again
+        play(&mut nes, &mut rewind, 6);
+        assert!(rewind.step_back(&mut nes));
+        assert_eq!(nes.frame_count(), states[0].0 + 5);
+    }
+
+    #[test]
+    fn stays_inside_the_budget(){
+        let mut nes = Nes::new(pad_cart());
+        let mut rewind = Rewind::new(1, usize::MAX);
+        play(&mut nes, &mut rewind, 10);
+        let full = nes.save_state().len();
+        // Deltas are much smaller than whole states
+        assert!(rewind.memory_used() < full * 2);
+        assert_eq!(rewind.snapshots(), 10);
+
+        rewind.set_budget(full + 1);
+        assert!(rewind.memory_used() <= full + 1);
+        assert_eq!(rewind.snapshots(), 1);
+        let frame = nes.frame_count();
+        assert!(!rewind.step_back(&mut nes));
+        assert_eq!(nes.frame_count(), frame);
+    }
+}
@@ -0,0 +1,334 @@
+#![allow(dead_code)]
+
+use std::error::Error;
+use std::fmt;
+
+// Save state file layout, all numbers little endian:
+//
+//   "MELS"              magic
+//   u16                 format version
+//   u64                 hash of the cartridge's PRG and CHR ROM
+//   sections...         until the end of the file
+//
+// and each section is
+//
+//   [u8; 4]             tag, like b"CPU "
+//   u16                 section version
+//   u32                 payload length
+//   payload
+//
+// Components write their fields in a fixed order and only ever append new
+// ones, bumping the section version. A loader skips sections it doesn't
+// know and ignores anything left over at the end of one, so older builds
+// can still load states from newer ones as long as the format version
+// matches.
+
+pub const MAGIC: [u8; 4] = *b"MELS";
+pub const FORMAT_VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = 4 + 2 + 8;
+const SECTION_HEADER_SIZE: usize = 4 + 2 + 4;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError{
+    // Doesn't start with "MELS"
+    BadMagic,
+    // Written by a build with an incompatible layout
+    UnsupportedVersion(u16),
+    // Saved with a different game
+    WrongCartridge,
+    // A component the loader needs isn't in the file
+    MissingSection([u8; 4]),
+    // Ran out of data in the middle of a section, or of the file
+    Truncated([u8; 4]),
+    // A field with a value the component can't take
+    Invalid(&'static str)
+}
+
+fn tag_name(tag: &[u8; 4]) -> String{
+    return String::from_utf8_lossy(tag).trim_end().to_string();
+}
+
+impl fmt::Display for StateError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            StateError::BadMagic => write!(f, "not a save state (missing MELS signature)"),
+            StateError::UnsupportedVersion(v) => write!(f, "save state format {} is not supported (expected {})", v, FORMAT_VERSION),
+            StateError::WrongCartridge => write!(f, "save state belongs to a different cartridge"),
+            StateError::MissingSection(tag) => write!(f, "save state has no {} section", tag_name(tag)),
+            StateError::Truncated(tag) => write!(f, "save state is truncated in the {} section", tag_name(tag)),
+            StateError::Invalid(what) => write!(f, "save state is corrupt: {}", what)
+        }
+    }
+}
+
+impl Error for StateError{}
+
+// FNV-1a, to recognise the cartridge a state goes with
+pub fn hash(parts: &[&[u8]]) -> u64{
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for part in parts.iter(){
+        for &byte in part.iter(){
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01B3);
+        }
+    }
+    return hash;
+}
+
+/******
+ * Writing
+ ******/
+
+pub struct StateWriter{
+    data: Vec<u8>
+}
+
+impl StateWriter{
+    pub fn new(cartridge_hash: u64) -> Self{
+        let mut writer = StateWriter{ data: Vec::new() };
+        writer.bytes(&MAGIC);
+        writer.u16(FORMAT_VERSION);
+        writer.u64(cartridge_hash);
+        return writer;
+    }
+
+    // A section holding whatever fill writes
+    pub fn section<F: FnOnce(&mut StateWriter)>(&mut self, tag: [u8; 4], version: u16, fill: F){
+        self.bytes(&tag);
+        self.u16(version);
+        let length_at = self.data.len();
+        self.u32(0);
+        fill(self);
+        let length = (self.data.len() - length_at - 4) as u32;
+        self.data[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn u8(&mut self, value: u8){
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool){
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16){
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32){
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64){
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // A fixed size array, the reader has to know how long it is
+    pub fn bytes(&mut self, data: &[u8]){
+        self.data.extend_from_slice(data);
+    }
+
+    // Length prefixed, for memories whose size depends on the cartridge
+    pub fn blob(&mut self, data: &[u8]){
+        self.u32(data.len() as u32);
+        self.data.extend_from_slice(data);
+    }
+
+    pub fn option_u8(&mut self, value: Option<u8>){
+        self.bool(value.is_some());
+        self.u8(value.unwrap_or(0));
+    }
+
+    pub fn finish(self) -> Vec<u8>{
+        return self.data;
+    }
+}
+
+/******
+ * Reading
+ ******/
+
+// One section's payload, read front to back
+pub struct StateReader<'a>{
+    tag: [u8; 4],
+    version: u16,
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> StateReader<'a>{
+    pub fn new(tag: [u8; 4], version: u16, data: &'a [u8]) -> Self{
+        return StateReader{ tag, version, data, pos: 0 };
+    }
+
+    // Version the section was written with, for loaders that need to tell
+    // an older layout apart
+    pub fn version(&self) -> u16{
+        return self.version;
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError>{
+        if self.data.len() - self.pos < len{
+            return Err(StateError::Truncated(self.tag));
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        return Ok(slice);
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError>{
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError>{
+        match self.u8()?{
+            0 => return Ok(false),
+            1 => return Ok(true),
+            _ => return Err(StateError::Invalid("boolean out of range"))
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError>{
+        let bytes = self.take(2)?;
+        return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError>{
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        return Ok(u32::from_le_bytes(bytes));
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError>{
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    pub fn bytes(&mut self, into: &mut [u8]) -> Result<(), StateError>{
+        into.copy_from_slice(self.take(into.len())?);
+        return Ok(());
+    }
+
+    // A blob that has to come back the same size it is now, since the
+    // cartridge decides how big its memories are
+    pub fn blob_into(&mut self, into: &mut [u8]) -> Result<(), StateError>{
+        let len = self.u32()? as usize;
+        if len != into.len(){
+            return Err(StateError::Invalid("memory size doesn't match the cartridge"));
+        }
+        return self.bytes(into);
+    }
+
+    pub fn option_u8(&mut self) -> Result<Option<u8>, StateError>{
+        let present = self.bool()?;
+        let value = self.u8()?;
+        return Ok(if present{ Some(value) }else{ None });
+    }
+}
+
+// A whole state file, split into its sections
+pub struct StateFile<'a>{
+    pub cartridge_hash: u64,
+    sections: Vec<([u8; 4], u16, &'a [u8])>
+}
+
+impl<'a> StateFile<'a>{
+    pub fn parse(data: &'a [u8]) -> Result<Self, StateError>{
+        if data.len() < 4 || data[0..4] != MAGIC{
+            return Err(StateError::BadMagic);
+        }
+        if data.len() < HEADER_SIZE{
+            return Err(StateError::Truncated(*b"HEAD"));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != FORMAT_VERSION{
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let mut hash = [0; 8];
+        hash.copy_from_slice(&data[6..14]);
+
+        let mut sections = Vec::new();
+        let mut pos = HEADER_SIZE;
+        while pos < data.len(){
+            if data.len() - pos < SECTION_HEADER_SIZE{
+                return Err(StateError::Truncated(*b"HEAD"));
+            }
+            let mut tag = [0; 4];
+            tag.copy_from_slice(&data[pos..pos + 4]);
+            let version = u16::from_le_bytes([data[pos + 4], data[pos + 5]]);
+            let mut length = [0; 4];
+            length.copy_from_slice(&data[pos + 6..pos + 10]);
+            let length = u32::from_le_bytes(length) as usize;
+            pos += SECTION_HEADER_SIZE;
+            if data.len() - pos < length{
+                return Err(StateError::Truncated(tag));
+            }
+            sections.push((tag, version, &data[pos..pos + length]));
+            pos += length;
+        }
+        return Ok(StateFile{ cartridge_hash: u64::from_le_bytes(hash), sections });
+    }
+
+    // The first section with the given tag
+    pub fn section(&self, tag: [u8; 4]) -> Result<StateReader<'a>, StateError>{
+        return self.sections.iter()
+            .find(|(t, _, _)| *t == tag)
+            .map(|&(tag, version, data)| StateReader::new(tag, version, data))
+            .ok_or(StateError::MissingSection(tag));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn sections_round_trip(){
+        let mut writer = StateWriter::new(0x1234);
+        writer.section(*b"TEST", 3, |w| {
+            w.u8(1);
+            w.bool(true);
+            w.u16(0x0203);
+            w.u32(0x04050607);
+            w.u64(u64::MAX);
+            w.blob(&[8, 9]);
+            w.option_u8(None);
+            w.option_u8(Some(10));
+        });
+        writer.section(*b"NEXT", 1, |w| w.u8(11));
+        let data = writer.finish();
+
+        let file = StateFile::parse(&data).unwrap();
+        assert_eq!(file.cartridge_hash, 0x1234);
+        let mut test = file.section(*b"TEST").unwrap();
+        assert_eq!(test.version(), 3);
+        assert_eq!(test.u8(), Ok(1));
+        assert_eq!(test.bool(), Ok(true));
+        assert_eq!(test.u16(), Ok(0x0203));
+        assert_eq!(test.u32(), Ok(0x04050607));
+        assert_eq!(test.u64(), Ok(u64::MAX));
+        let mut blob = [0; 2];
+        assert_eq!(test.blob_into(&mut blob), Ok(()));
+        assert_eq!(blob, [8, 9]);
+        assert_eq!(test.option_u8(), Ok(None));
+        assert_eq!(test.option_u8(), Ok(Some(10)));
+        assert_eq!(test.u8(), Err(StateError::Truncated(*b"TEST")));
+        assert_eq!(file.section(*b"NEXT").unwrap().u8(), Ok(11));
+        assert_eq!(file.section(*b"GONE").err(), Some(StateError::MissingSection(*b"GONE")));
+    }
+
+    #[test]
+    fn rejects_what_it_cant_load(){
+        assert_eq!(StateFile::parse(b"NES\x1A").err(), Some(StateError::BadMagic));
+        let mut data = StateWriter::new(0).finish();
+        data[4] = 2;
+        assert_eq!(StateFile::parse(&data).err(), Some(StateError::UnsupportedVersion(2)));
+
+        let mut writer = StateWriter::new(0);
+        writer.section(*b"CPU ", 1, |w| w.u32(0));
+        let data = writer.finish();
+        assert_eq!(StateFile::parse(&data[..data.len() - 1]).err(), Some(StateError::Truncated(*b"CPU ")));
+    }
+}
@@ -0,0 +1,176 @@
+#![allow(dead_code)]
+
+use crate::bus::Bus;
+use crate::cpu::{AddrMode, Operation, CPU_6502};
+use crate::disasm;
+
+// One line per instruction in the format of Nintendulator's nestest.log, so
+// a run can be diffed straight against the reference log:
+//
+// C72F  B0 04     BCS $C735                       A:00 X:00 Y:00 P:27 SP:FB PPU:  0, 30 CYC:10
+//
+// Memory is only ever peeked, tracing never changes what the program sees.
+
+fn mnemonic(operation: Operation) -> String{
+    match operation{
+        // Nintendulator's name for it
+        Operation::ISC => return String::from("ISB"),
+        _ => return format!("{:?}", operation)
+    }
+}
+
+// The operand, with the effective address and the value it holds before
+// the instruction runs
+fn operand(cpu: &CPU_6502<Bus>, bus: &Bus, mode: AddrMode, operation: Operation) -> String{
+    let pc = cpu.pc();
+    let lo = bus.cpu_peek(pc.wrapping_add(1));
+    let hi = bus.cpu_peek(pc.wrapping_add(2));
+    let absolute = ((hi as u16) << 8) | lo as u16;
+    let zero_page_word = |addr: u8| -> u16{
+        let lo = bus.cpu_peek(addr as u16) as u16;
+        let hi = bus.cpu_peek(addr.wrapping_add(1) as u16) as u16;
+        return (hi << 8) | lo;
+    };
+
+    match mode{
+        AddrMode::IMP => {
+            match operation{
+                Operation::ASL | Operation::LSR | Operation::ROL | Operation::ROR => return String::from("A"),
+                _ => return String::new()
+            }
+        }
+        AddrMode::IMM => return format!("#${:02X}", lo),
+        AddrMode::ZP0 => return format!("${:02X} = {:02X}", lo, bus.cpu_peek(lo as u16)),
+        AddrMode::ZPX | AddrMode::ZPY => {
+            let (index, name) = if mode == AddrMode::ZPX{ (cpu.x(), 'X') }else{ (cpu.y(), 'Y') };
+            let addr = lo.wrapping_add(index);
+            return format!("${:02X},{} @ {:02X} = {:02X}", lo, name, addr, bus.cpu_peek(addr as u16));
+        }
+        AddrMode::ABS => {
+            match operation{
+                Operation::JMP | Operation::JSR => return format!("${:04X}", absolute),
+                _ => return format!("${:04X} = {:02X}", absolute, bus.cpu_peek(absolute))
+            }
+        }
+        AddrMode::ABX | AddrMode::ABY => {
+            let (index, name) = if mode == AddrMode::ABX{ (cpu.x(), 'X') }else{ (cpu.y(), 'Y') };
+            let addr = absolute.wrapping_add(index as u16);
+            return format!("${:04X},{} @ {:04X} = {:02X}", absolute, name, addr, bus.cpu_peek(addr));
+        }
+        AddrMode::IND => {
+            // The pointer's high byte never crosses a page
+            let hi_addr = (absolute & 0xFF00) | (absolute.wrapping_add(1) & 0x00FF);
+            let target = ((bus.cpu_peek(hi_addr) as u16) << 8) | bus.cpu_peek(absolute) as u16;
+            return format!("(${:04X}) = {:04X}", absolute, target);
+        }
+        AddrMode::IZX => {
+            let pointer = lo.wrapping_add(cpu.x());
+            let addr = zero_page_word(pointer);
+            return format!("(${:02X},X) @ {:02X} = {:04X} = {:02X}", lo, pointer, addr, bus.cpu_peek(addr));
+        }
+        AddrMode::IZY => {
+            let base = zero_page_word(lo);
+            let addr = base.wrapping_add(cpu.y() as u16);
+            return format!("(${:02X}),Y = {:04X} @ {:04X} = {:02X}", lo, base, addr, bus.cpu_peek(addr));
+        }
+        AddrMode::REL => return format!("${:04X}", pc.wrapping_add(2).wrapping_add(lo as i8 as u16)),
+    }
+}
+
+// The trace line for the instruction the CPU is about to run
+pub fn trace_line(cpu: &CPU_6502<Bus>) -> String{
+    let bus = cpu.bus();
+    let pc = cpu.pc();
+    let decoded = disasm::decode(pc, |addr| bus.cpu_peek(addr));
+    let bytes: Vec<String> = decoded.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+
+    let operand = operand(cpu, bus, decoded.mode, decoded.operation);
+    let disassembly = if operand.is_empty(){
+        mnemonic(decoded.operation)
+    }else{
+        format!("{} {}", mnemonic(decoded.operation), operand)
+    };
+    let marker = if decoded.unofficial{ '*' }else{ ' ' };
+
+    return format!(
+        "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        pc, bytes.join(" "), marker, disassembly,
+        cpu.a(), cpu.x(), cpu.y(), cpu.status(), cpu.stkp(),
+        bus.ppu().scanline(), bus.ppu().dot(), bus.cycles()
+    );
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::nes::Nes;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    fn test_nes(program: &[u8]) -> Nes{
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x3FFC] = 0x00;
+        prg[0x3FFD] = 0xC0;
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        return Nes::new(Cartridge::from_bytes(&rom).unwrap());
+    }
+
+    // Somewhere for Nes to write to that the test can still read
+    #[derive(Clone, Default)]
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog{
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>{
+            self.0.borrow_mut().extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()>{
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn matches_nestest_layout(){
+        // LDA #$7F, STA $00, JMP $C000
+        let mut nes = test_nes(&[0xA9, 0x7F, 0x85, 0x00, 0x4C, 0x00, 0xC0]);
+        let log = SharedLog::default();
+        nes.set_trace(Some(Box::new(log.clone())));
+        for _ in 0..4{
+            nes.step_instruction();
+        }
+        let text = String::from_utf8(log.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, vec![
+            "C000  A9 7F     LDA #$7F                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+            "C002  85 00     STA $00 = 00                    A:7F X:00 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9",
+            "C004  4C 00 C0  JMP $C000                       A:7F X:00 Y:00 P:24 SP:FD PPU:  0, 36 CYC:12"
+        ]);
+    }
+
+    #[test]
+    fn indirect_modes_show_their_addresses(){
+        // LDX #$02, LDY #$03, LDA ($10,X), LDA ($12),Y, *LAX $0200,Y
+        let mut nes = test_nes(&[0xA2, 0x02, 0xA0, 0x03, 0xA1, 0x10, 0xB1, 0x12, 0xBF, 0x00, 0x02]);
+        nes.bus_mut().cpu_write(0x0012, 0x00);
+        nes.bus_mut().cpu_write(0x0013, 0x03);
+        nes.bus_mut().cpu_write(0x0303, 0x5A);
+        nes.step_instruction();
+        nes.step_instruction();
+        nes.step_instruction();
+
+        let line = trace_line(nes.cpu());
+        assert!(line.starts_with("C004  A1 10     LDA ($10,X) @ 12 = 0300 = 00  "));
+        nes.step_instruction();
+        let line = trace_line(nes.cpu());
+        assert!(line.starts_with("C006  B1 12     LDA ($12),Y = 0300 @ 0303 = 5A"));
+        nes.step_instruction();
+        let line = trace_line(nes.cpu());
+        assert!(line.starts_with("C008  BF 00 02 *LAX $0200,Y @ 0203 = 00"));
+    }
+}
@@ -0,0 +1,105 @@
+// Explicit returns are the house style
+#![allow(clippy::needless_return)]
+
+// Runs blargg's CPU, PPU, APU and mapper test ROMs through the $6000 status
+// protocol, see melones::blargg.
+//
+// The ROMs aren't checked in; tests/roms/fetch.sh downloads them, or unpack
+// the test suites into tests/roms keeping their own directory names
+// (instr_test-v5/..., ppu_vbl_nmi/...). Then run `cargo test -- --ignored`.
+// A missing ROM counts as a failure.
+
+use std::path::Path;
+
+use melones::blargg::{self, Outcome};
+
+// ROM, and seconds of emulated time it gets to finish
+type Table = [(&'static str, u64)];
+
+const CPU: &Table = &[
+    ("instr_test-v5/all_instrs.nes", 120),
+    ("instr_misc/instr_misc.nes", 20),
+    ("instr_timing/instr_timing.nes", 40),
+    ("cpu_interrupts_v2/cpu_interrupts.nes", 30),
+    ("cpu_dummy_writes/cpu_dummy_writes_oam.nes", 20),
+    ("cpu_dummy_writes/cpu_dummy_writes_ppumem.nes", 20),
+    ("cpu_exec_space/test_cpu_exec_space_ppuio.nes", 10),
+    ("cpu_reset/registers.nes", 10),
+    ("cpu_reset/ram_after_reset.nes", 10)
+];
+
+const PPU: &Table = &[
+    ("ppu_vbl_nmi/ppu_vbl_nmi.nes", 60),
+    ("ppu_open_bus/ppu_open_bus.nes", 10),
+    ("ppu_read_buffer/test_ppu_read_buffer.nes", 30),
+    ("oam_read/oam_read.nes", 10),
+    ("oam_stress/oam_stress.nes", 60)
+];
+
+const APU: &Table = &[
+    ("apu_test/apu_test.nes", 30),
+    ("apu_reset/4015_cleared.nes", 10),
+    ("apu_reset/4017_timing.nes", 10),
+    ("apu_reset/4017_written.nes", 10),
+    ("apu_reset/irq_flag_cleared.nes", 10),
+    ("apu_reset/len_ctrs_enabled.nes", 10),
+    ("apu_reset/works_immediately.nes", 10)
+];
+
+const MAPPER: &Table = &[
+    ("mmc3_test_2/rom_singles/1-clocking.nes", 10),
+    ("mmc3_test_2/rom_singles/2-details.nes", 10),
+    ("mmc3_test_2/rom_singles/3-A12_clocking.nes", 10),
+    ("mmc3_test_2/rom_singles/4-scanline_timing.nes", 10),
+    ("mmc3_test_2/rom_singles/5-MMC3.nes", 10)
+];
+
+// Runs every ROM in the table and reports all the failures together
+fn run_table(table: &Table){
+    let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms");
+    let mut failures = Vec::new();
+    for (name, seconds) in table.iter(){
+        let path = roms.join(name);
+        if !path.exists(){
+            failures.push(format!("{}: not found in {}", name, roms.display()));
+            continue;
+        }
+        let outcome = match blargg::run_rom(&path, seconds * 60){
+            Ok(outcome) => outcome,
+            Err(e) => {
+                failures.push(format!("{}: {}", name, e));
+                continue;
+            }
+        };
+        match outcome{
+            Outcome::Passed(_) => {}
+            Outcome::Failed{ code, text } => failures.push(format!("{}: failed with {}\n{}", name, code, text)),
+            Outcome::TimedOut(text) => failures.push(format!("{}: no result after {}s\n{}", name, seconds, text))
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+#[ignore = "needs blargg's test ROMs in tests/roms; run with --ignored"]
+fn blargg_cpu(){
+    run_table(CPU);
+}
+
+#[test]
+#[ignore = "needs blargg's test ROMs in tests/roms; run with --ignored"]
+fn blargg_ppu(){
+    run_table(PPU);
+}
+
+#[test]
+#[ignore = "needs blargg's test ROMs in tests/roms; run with --ignored"]
+fn blargg_apu(){
+    run_table(APU);
+}
+
+#[test]
+#[ignore = "needs blargg's test ROMs in tests/roms; run with --ignored"]
+fn blargg_mapper(){
+    run_table(MAPPER);
+}
@@ -0,0 +1,85 @@
+// Explicit returns are the house style
+#![allow(clippy::needless_return)]
+
+// Runs kevtris' nestest in automation mode and compares every instruction
+// against Nintendulator's reference log.
+//
+// The ROM and log aren't checked in; tests/roms/fetch.sh downloads them, or
+// drop nestest.nes and nestest.log into tests/roms by hand. Then run
+// `cargo test -- --ignored`. The test fails rather than passing when they
+// are missing.
+
+use std::fs;
+use std::path::Path;
+
+use melones::cartridge::Cartridge;
+use melones::nes::Nes;
+use melones::trace::trace_line;
+
+// Column ranges of the trace fields, for pointing at what went wrong
+const FIELDS: [(usize, &str); 11] = [
+    (0, "PC"), (6, "opcode bytes"), (15, "disassembly"), (48, "A"), (53, "X"), (58, "Y"),
+    (63, "P"), (68, "SP"), (74, "PPU scanline"), (78, "PPU dot"), (82, "CYC")
+];
+
+fn field_at(column: usize) -> &'static str{
+    let mut name = FIELDS[0].1;
+    for (start, field) in FIELDS.iter(){
+        if column >= *start{
+            name = field;
+        }
+    }
+    return name;
+}
+
+fn mismatch_report(line: usize, previous: Option<&str>, expected: &str, actual: &str) -> String{
+    let column = expected.chars().zip(actual.chars())
+        .position(|(e, a)| e != a)
+        .unwrap_or(expected.len().min(actual.len()));
+    let mut report = format!("nestest diverged at log line {} ({} differs)\n", line, field_at(column));
+    if let Some(previous) = previous{
+        report += &format!("  previous: {}\n", previous);
+    }
+    report += &format!("  expected: {}\n", expected);
+    report += &format!("  actual:   {}\n", actual);
+    report += &format!("            {}^", " ".repeat(column));
+    return report;
+}
+
+#[test]
+#[ignore = "needs tests/roms/nestest.nes and nestest.log; run with --ignored"]
+fn nestest(){
+    let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms");
+    let rom = fs::read(roms.join("nestest.nes")).expect("tests/roms/nestest.nes should be there");
+    let log = fs::read_to_string(roms.join("nestest.log")).expect("tests/roms/nestest.log should be there");
+
+    let mut nes = Nes::new(Cartridge::from_bytes(&rom).expect("nestest.nes should load"));
+    // Let the reset sequence run, then jump to the automated entry point
+    nes.step_instruction();
+    nes.cpu_mut().set_pc(0xC000);
+
+    let mut previous: Option<&str> = None;
+    for (number, expected) in log.lines().enumerate(){
+        let actual = trace_line(nes.cpu());
+        if actual != expected{
+            panic!("{}", mismatch_report(number + 1, previous, expected, &actual));
+        }
+        previous = Some(expected);
+        nes.step_instruction();
+    }
+
+    // Official opcode results, then unofficial ones; zero means every test passed
+    let official = nes.bus().cpu_peek(0x0002);
+    let unofficial = nes.bus().cpu_peek(0x0003);
+    assert_eq!((official, unofficial), (0x00, 0x00),
+        "nestest reported failures: $02 = {:02X}, $03 = {:02X}", official, unofficial);
+}
+
+#[test]
+fn mismatch_report_points_at_the_field(){
+    let expected = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7";
+    let actual   = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 21 CYC:7";
+    let report = mismatch_report(1, None, expected, actual);
+    assert!(report.contains("(P differs)"));
+    assert!(report.ends_with(&format!("{}^", " ".repeat(12 + 65))));
+}
@@ -0,0 +1,209 @@
+// Explicit returns are the house style
+#![allow(clippy::needless_return)]
+
+// Runs the CPU through Tom Harte's single-step ProcessorTests, nes6502
+// flavour: for every opcode, thousands of cases giving the registers and
+// RAM before and after one instruction, and each cycle's bus access.
+//
+// The data is far too big to keep in the repo. Copy nes6502/v1/*.json from
+// https://github.com/SingleStepTests/ProcessorTests into tests/processor_tests
+// and run `cargo test -- --ignored`. A missing file counts as a failure.
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use melones::cpu::CPU_6502;
+use melones::memory::{Access, BusAccess, FlatMemory, Memory};
+
+// Gives up on an instruction that never finishes, like a jammed CPU
+const MAX_CYCLES: usize = 16;
+
+struct State{
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>
+}
+
+fn number(value: &Value, key: &str) -> u64{
+    return value[key].as_u64().unwrap_or_else(|| panic!("test case is missing {}", key));
+}
+
+fn parse_state(value: &Value) -> State{
+    let ram = value["ram"].as_array().expect("test case is missing ram").iter()
+        .map(|pair| (pair[0].as_u64().unwrap() as u16, pair[1].as_u64().unwrap() as u8))
+        .collect();
+    return State{
+        pc: number(value, "pc") as u16,
+        s: number(value, "s") as u8,
+        a: number(value, "a") as u8,
+        x: number(value, "x") as u8,
+        y: number(value, "y") as u8,
+        p: number(value, "p") as u8,
+        ram
+    };
+}
+
+// The expected cycles, numbered from the instruction's first
+fn parse_cycles(value: &Value) -> Vec<BusAccess>{
+    return value.as_array().expect("test case is missing cycles").iter().enumerate()
+        .map(|(cycle, entry)| BusAccess{
+            cycle: cycle as u64,
+            addr: entry[0].as_u64().unwrap() as u16,
+            data: entry[1].as_u64().unwrap() as u8,
+            access: if entry[2] == "write"{ Access::Write }else{ Access::Read }
+        })
+        .collect();
+}
+
+fn load(cpu: &mut CPU_6502<FlatMemory>, state: &State){
+    cpu.set_pc(state.pc);
+    cpu.set_stkp(state.s);
+    cpu.set_a(state.a);
+    cpu.set_x(state.x);
+    cpu.set_y(state.y);
+    cpu.set_status(state.p);
+    for &(addr, data) in state.ram.iter(){
+        cpu.bus_mut().load(addr, data);
+    }
+}
+
+fn compare(cpu: &CPU_6502<FlatMemory>, expected: &State) -> Result<(), String>{
+    let registers = [
+        ("A", cpu.a(), expected.a), ("X", cpu.x(), expected.x), ("Y", cpu.y(), expected.y),
+        ("P", cpu.status(), expected.p), ("S", cpu.stkp(), expected.s)
+    ];
+    if cpu.pc() != expected.pc{
+        return Err(format!("PC is {:04X}, expected {:04X}", cpu.pc(), expected.pc));
+    }
+    for (name, actual, wanted) in registers.iter(){
+        if actual != wanted{
+            return Err(format!("{} is {:02X}, expected {:02X}", name, actual, wanted));
+        }
+    }
+    for &(addr, data) in expected.ram.iter(){
+        let actual = cpu.bus().peek(addr);
+        if actual != data{
+            return Err(format!("${:04X} is {:02X}, expected {:02X}", addr, actual, data));
+        }
+    }
+    return Ok(());
+}
+
+// Runs one case on a fresh cycle-stepped CPU sitting on a flat bus, so
+// nothing a case leaves behind (a jam, a pending interrupt, half an
+// instruction) leaks into the next
+fn run_case(case: &Value) -> Result<(), String>{
+    let initial = parse_state(&case["initial"]);
+    let expected = parse_state(&case["final"]);
+    let cycles = parse_cycles(&case["cycles"]);
+    let mut cpu = CPU_6502::new(FlatMemory::new());
+    cpu.set_cycle_stepped(true);
+    load(&mut cpu, &initial);
+
+    let start = cpu.bus().cycles();
+    let mut taken = 0;
+    loop{
+        cpu.clock();
+        cpu.bus_mut().clock();
+        taken += 1;
+        if cpu.complete() || taken == MAX_CYCLES || (cpu.halted() && taken >= cycles.len()){
+            break;
+        }
+    }
+    let activity: Vec<BusAccess> = cpu.bus_mut().take_log().into_iter()
+        .map(|access| BusAccess{ cycle: access.cycle - start, ..access })
+        .collect();
+
+    compare(&cpu, &expected)?;
+    if taken != cycles.len(){
+        return Err(format!("took {} cycles, expected {}", taken, cycles.len()));
+    }
+    if activity != cycles{
+        return Err(format!("bus activity was {:?}, expected {:?}", activity, cycles));
+    }
+    return Ok(());
+}
+
+#[test]
+#[ignore = "needs the ProcessorTests JSON in tests/processor_tests; run with --ignored"]
+fn processor_tests(){
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/processor_tests");
+    assert!(dir.is_dir(), "{} not found", dir.display());
+
+    let mut failures = Vec::new();
+    for opcode in 0..=0xFFu8{
+        let path = dir.join(format!("{:02x}.json", opcode));
+        let text = match fs::read_to_string(&path){
+            Ok(text) => text,
+            Err(_) => {
+                failures.push(format!("{:02X}: {} not found", opcode, path.display()));
+                continue;
+            }
+        };
+        let cases: Value = serde_json::from_str(&text).expect("test file should be valid JSON");
+        let cases = cases.as_array().expect("test file should hold a list of cases");
+
+        let mut failed = 0;
+        let mut first = None;
+        for case in cases.iter(){
+            if let Err(reason) = run_case(case){
+                failed += 1;
+                first.get_or_insert(format!("{}: {}", case["name"], reason));
+            }
+        }
+        if let Some(first) = first{
+            failures.push(format!("{:02X}: {}/{} cases failed, first was {}", opcode, failed, cases.len(), first));
+        }
+    }
+    assert!(failures.is_empty(), "processor tests failed for {} opcodes\n{}", failures.len(), failures.join("\n"));
+}
+
+#[test]
+fn case_runner_checks_state_and_cycles(){
+    // LDA #$42 at $1234
+    let case: Value = serde_json::from_str(r#"{
+        "name": "a9 42 00",
+        "initial": { "pc": 4660, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[4660, 169], [4661, 66]] },
+        "final": { "pc": 4662, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36, "ram": [[4660, 169], [4661, 66]] },
+        "cycles": [[4660, 169, "read"], [4661, 66, "read"]]
+    }"#).unwrap();
+    assert_eq!(run_case(&case), Ok(()));
+
+    let mut wrong = case.clone();
+    wrong["final"]["a"] = Value::from(0x43);
+    assert_eq!(run_case(&wrong), Err(String::from("A is 42, expected 43")));
+
+    let mut dummy = case.clone();
+    dummy["cycles"][1] = serde_json::json!([4661, 66, "write"]);
+    assert!(run_case(&dummy).unwrap_err().starts_with("bus activity was"));
+
+    let mut slow = case.clone();
+    slow["cycles"].as_array_mut().unwrap().push(serde_json::json!([4662, 0, "read"]));
+    assert_eq!(run_case(&slow), Err(String::from("took 2 cycles, expected 3")));
+}
+
+#[test]
+fn a_jammed_cpu_does_not_leak_into_the_next_case(){
+    // KIL at $1234 jams after its opcode fetch and one dummy read
+    let kil: Value = serde_json::from_str(r#"{
+        "name": "02 00 00",
+        "initial": { "pc": 4660, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[4660, 2], [4661, 0]] },
+        "final": { "pc": 4661, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[4660, 2], [4661, 0]] },
+        "cycles": [[4660, 2, "read"], [4661, 0, "read"]]
+    }"#).unwrap();
+    // LDX #$42 at $0200
+    let ldx: Value = serde_json::from_str(r#"{
+        "name": "a2 42 00",
+        "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[512, 162], [513, 66]] },
+        "final": { "pc": 514, "s": 253, "a": 0, "x": 66, "y": 0, "p": 36, "ram": [[512, 162], [513, 66]] },
+        "cycles": [[512, 162, "read"], [513, 66, "read"]]
+    }"#).unwrap();
+    assert_eq!(run_case(&kil), Ok(()));
+    assert_eq!(run_case(&ldx), Ok(()));
+}
@@ -0,0 +1,23 @@
+#!/bin/sh
+# Fetches the test ROMs the ignored integration tests run against into
+# tests/roms, from the collection at github.com/christopherpow/nes-test-roms.
+# Run from anywhere, then `cargo test -- --ignored`.
+set -e
+
+roms=$(cd "$(dirname "$0")" && pwd)
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+git clone --quiet --depth 1 https://github.com/christopherpow/nes-test-roms "$work/nes-test-roms"
+cd "$work/nes-test-roms"
+
+# nestest and Nintendulator's log of it
+cp other/nestest.nes other/nestest.log "$roms"
+
+# blargg's suites, keeping their own directory names
+for suite in instr_test-v5 instr_misc instr_timing cpu_interrupts_v2 cpu_dummy_writes \
+        cpu_exec_space cpu_reset ppu_vbl_nmi ppu_open_bus ppu_read_buffer oam_read \
+        oam_stress apu_test apu_reset mmc3_test_2; do
+    rm -rf "$roms/$suite"
+    cp -R "$suite" "$roms/$suite"
+done